@@ -0,0 +1,99 @@
+#!/usr/bin/env python3
+"""Guardrail against accidental super-linear behaviour in the Rust transform.
+
+Generates inputs of N and 4N rows, times the release binary on each, and
+fails when the 4N run takes more than --max-ratio times as long as the N run.
+
+A linear transform lands near 4x: fixed costs such as process start and
+dimension loading pull it below, cache misses and hash table growth push it a
+little above. A quadratic step (say, a nested lookup in the aggregation loop)
+lands near 16x. The default tolerance of 8x is the geometric midpoint of the
+two, so ordinary timing noise does not trip it, and an O(n^2) hot path does.
+This is not a benchmark; use run_bench.py for absolute numbers.
+"""
+from __future__ import annotations
+
+import argparse
+import subprocess
+import tempfile
+import time
+from pathlib import Path
+
+
+def parse_args() -> argparse.Namespace:
+    parser = argparse.ArgumentParser(description="Check that the Rust transform scales linearly")
+    parser.add_argument("--rows", type=int, default=100_000, help="Row count N of the smaller input")
+    parser.add_argument("--runs", type=int, default=3, help="Timed runs per size; the fastest counts")
+    parser.add_argument("--seed", type=int, default=42)
+    parser.add_argument("--max-ratio", type=float, default=8.0)
+    parser.add_argument("--python-bin", type=str, default="python3")
+    return parser.parse_args()
+
+
+def generate(root: Path, python_bin: str, rows: int, seed: int, out_dir: Path) -> None:
+    subprocess.run(
+        [
+            python_bin,
+            "pipeline/extract/generate_dummy_data.py",
+            "--rows",
+            str(rows),
+            "--seed",
+            str(seed),
+            "--out-dir",
+            str(out_dir),
+        ],
+        cwd=root,
+        check=True,
+        stdout=subprocess.DEVNULL,
+    )
+
+
+def fastest_wall_sec(rust_bin: Path, data_dir: Path, runs: int) -> float:
+    cmd = [
+        str(rust_bin),
+        str(data_dir / "events.csv"),
+        str(data_dir / "dim_products.csv"),
+        str(data_dir / "dim_countries.csv"),
+        str(data_dir / "output.csv"),
+    ]
+    best = float("inf")
+    for _ in range(runs):
+        started = time.perf_counter()
+        subprocess.run(cmd, check=True, stdout=subprocess.DEVNULL)
+        best = min(best, time.perf_counter() - started)
+    return best
+
+
+def main() -> None:
+    args = parse_args()
+    if args.rows <= 0 or args.runs <= 0 or args.max_ratio <= 0:
+        raise ValueError("--rows, --runs and --max-ratio must be > 0")
+
+    root = Path(__file__).resolve().parents[1]
+    rust_bin = root / "process_rust/target/release/process_rust"
+
+    subprocess.run(
+        ["cargo", "build", "--release", "--manifest-path", "process_rust/Cargo.toml"],
+        cwd=root,
+        check=True,
+    )
+
+    with tempfile.TemporaryDirectory(prefix="etl_scaling_") as tmp:
+        timings: dict[int, float] = {}
+        for rows in (args.rows, 4 * args.rows):
+            data_dir = Path(tmp) / f"rows_{rows}"
+            generate(root, args.python_bin, rows, args.seed, data_dir)
+            timings[rows] = fastest_wall_sec(rust_bin, data_dir, args.runs)
+            print(f"rows={rows} fastest_wall={timings[rows]:.4f}s")
+
+    small, large = timings[args.rows], timings[4 * args.rows]
+    ratio = large / small if small > 0 else float("inf")
+    print(f"ratio(4N/N)={ratio:.2f} max_ratio={args.max_ratio:.2f}")
+    if ratio > args.max_ratio:
+        raise SystemExit("Scaling check failed: 4x the rows took more than max-ratio times as long")
+
+    print("Scaling check passed")
+
+
+if __name__ == "__main__":
+    main()
@@ -45,7 +45,11 @@ def load_to_sqlite(input_csv: Path, db_path: Path, table: str) -> int:
                 total_profit_usd_cents INTEGER NOT NULL,
                 total_risk_adjusted_usd_cents INTEGER NOT NULL,
                 avg_item_price_usd_cents INTEGER NOT NULL,
-                heavy_item_orders INTEGER NOT NULL
+                heavy_item_orders INTEGER NOT NULL,
+                vip_order_rate_bps INTEGER NOT NULL,
+                profit_margin_bps INTEGER NOT NULL,
+                stddev_net_usd_cents INTEGER NOT NULL,
+                avg_quantity_centi INTEGER NOT NULL
             )
             """
         )
@@ -54,8 +58,9 @@ def load_to_sqlite(input_csv: Path, db_path: Path, table: str) -> int:
             f"INSERT INTO {table} ("
             "event_date, customer_tier, category, country, time_bucket, order_size_bucket, "
             "order_count, vip_customer_orders, total_quantity, total_net_usd_cents, "
-            "total_profit_usd_cents, total_risk_adjusted_usd_cents, avg_item_price_usd_cents, heavy_item_orders"
-            ") VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+            "total_profit_usd_cents, total_risk_adjusted_usd_cents, avg_item_price_usd_cents, heavy_item_orders, "
+            "vip_order_rate_bps, profit_margin_bps, stddev_net_usd_cents, avg_quantity_centi"
+            ") VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
         )
 
         inserted = 0
@@ -79,6 +84,10 @@ def load_to_sqlite(input_csv: Path, db_path: Path, table: str) -> int:
                         parse_int(row.get("total_risk_adjusted_usd_cents", "")),
                         parse_int(row.get("avg_item_price_usd_cents", "")),
                         parse_int(row.get("heavy_item_orders", "")),
+                        parse_int(row.get("vip_order_rate_bps", "")),
+                        parse_int(row.get("profit_margin_bps", "")),
+                        parse_int(row.get("stddev_net_usd_cents", "")),
+                        parse_int(row.get("avg_quantity_centi", "")),
                     )
                 )
                 if len(rows) >= 10_000:
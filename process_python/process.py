@@ -3,6 +3,7 @@ from __future__ import annotations
 
 import argparse
 import csv
+import math
 from pathlib import Path
 
 VALID_TIERS = {"bronze", "silver", "gold", "platinum"}
@@ -24,13 +25,24 @@ def clamp_int(value: int, low: int, high: int) -> int:
 
 
 def round_div(numerator: int, denominator: int) -> int:
+    """Divides rounding half away from zero; 0 for a non-positive denominator."""
     if denominator <= 0:
         return 0
-    if numerator <= 0:
-        return 0
+    if numerator < 0:
+        return -((-numerator + denominator // 2) // denominator)
     return (numerator + denominator // 2) // denominator
 
 
+def population_stddev(total: int, total_squares: int, count: int) -> int:
+    if count <= 1:
+        return 0
+    spread = max(count * total_squares - total * total, 0)
+    stddev = math.isqrt(spread) // count
+    if 4 * spread >= ((2 * stddev + 1) * count) ** 2:
+        stddev += 1
+    return stddev
+
+
 def parse_event_hour(event_ts: str) -> int:
     if len(event_ts) < 13 or event_ts[10] != "T":
         return -1
@@ -237,7 +249,7 @@ def transform(
         vip_customer_order = 1 if customer_day_spend.get((event_date, customer_id), 0) >= 50_000 else 0
 
         key = (event_date, customer_tier, category, country, time_bucket, size_bucket)
-        bucket = aggregated.setdefault(key, [0, 0, 0, 0, 0, 0, 0, 0])
+        bucket = aggregated.setdefault(key, [0, 0, 0, 0, 0, 0, 0, 0, 0])
 
         bucket[0] += 1
         bucket[1] += vip_customer_order
@@ -247,6 +259,7 @@ def transform(
         bucket[5] += risk_adjusted_usd_cents
         bucket[6] += quantity
         bucket[7] += heavy_item_order
+        bucket[8] += net_usd_cents * net_usd_cents
 
     output_path.parent.mkdir(parents=True, exist_ok=True)
     with output_path.open("w", newline="", encoding="utf-8") as f:
@@ -267,6 +280,10 @@ def transform(
                 "total_risk_adjusted_usd_cents",
                 "avg_item_price_usd_cents",
                 "heavy_item_orders",
+                "vip_order_rate_bps",
+                "profit_margin_bps",
+                "stddev_net_usd_cents",
+                "avg_quantity_centi",
             ]
         )
 
@@ -288,9 +305,14 @@ def transform(
                 total_risk_adjusted_usd_cents,
                 total_items,
                 heavy_item_orders,
+                total_net_squared,
             ) = metrics
 
             avg_item_price_usd_cents = round_div(total_net_usd_cents, total_items)
+            vip_order_rate_bps = round_div(vip_customer_orders * 10_000, order_count)
+            profit_margin_bps = round_div(total_profit_usd_cents * 10_000, total_net_usd_cents)
+            stddev_net_usd_cents = population_stddev(total_net_usd_cents, total_net_squared, order_count)
+            avg_quantity_centi = round_div(total_quantity * 100, order_count)
 
             writer.writerow(
                 [
@@ -308,6 +330,10 @@ def transform(
                     total_risk_adjusted_usd_cents,
                     avg_item_price_usd_cents,
                     heavy_item_orders,
+                    vip_order_rate_bps,
+                    profit_margin_bps,
+                    stddev_net_usd_cents,
+                    avg_quantity_centi,
                 ]
             )
 
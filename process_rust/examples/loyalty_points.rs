@@ -0,0 +1,126 @@
+//! Runs the transform with a custom `Deriver` that adds a `loyalty_points`
+//! column: one point per whole dollar of net revenue, doubled for gold and
+//! platinum customers.
+//!
+//! cargo run --example loyalty_points -- <events_csv> <product_dim_csv> <country_dim_csv> <output_csv>
+
+use std::env;
+use std::io;
+use std::path::Path;
+use std::process;
+
+use process_rust::{
+    transform, DerivedRecord, Deriver, EventRecord, TransformOptions, TransformSummary,
+};
+
+struct LoyaltyPoints;
+
+impl Deriver for LoyaltyPoints {
+    fn derive_extra(&self, _record: &EventRecord, derived: &DerivedRecord) -> Vec<(String, i64)> {
+        let multiplier = match derived.customer_tier.as_str() {
+            "gold" | "platinum" => 2,
+            _ => 1,
+        };
+        vec![(
+            "loyalty_points".to_string(),
+            derived.net_usd_cents / 100 * multiplier,
+        )]
+    }
+}
+
+fn run(
+    events_path: &Path,
+    product_dim_path: &Path,
+    country_dim_path: &Path,
+    output_path: &Path,
+) -> io::Result<TransformSummary> {
+    let options = TransformOptions {
+        derivers: vec![Box::new(LoyaltyPoints)],
+        ..TransformOptions::default()
+    };
+    transform(
+        events_path,
+        product_dim_path,
+        country_dim_path,
+        output_path,
+        &options,
+    )
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 5 {
+        eprintln!(
+            "Usage: {} <events_csv> <product_dim_csv> <country_dim_csv> <output_csv>",
+            args[0]
+        );
+        process::exit(1);
+    }
+
+    match run(
+        Path::new(&args[1]),
+        Path::new(&args[2]),
+        Path::new(&args[3]),
+        Path::new(&args[4]),
+    ) {
+        Ok(summary) => println!(
+            "loyalty transform completed | dedup_rows={} output={}",
+            summary.dedup_rows, args[4]
+        ),
+        Err(err) => {
+            eprintln!("loyalty transform failed | {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn loyalty_points_column_is_added() {
+        let dir = env::temp_dir().join(format!("loyalty-points-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, contents: &str| {
+            let path = dir.join(name);
+            fs::write(&path, contents).unwrap();
+            path
+        };
+        let events = write(
+            "events.csv",
+            "event_id,event_version,event_ts,event_date,customer_id,product_id,amount_cents,\
+             quantity,discount_bps,shipping_cents,status,country,customer_tier,payment_method\n\
+             E1,1,2025-01-24T07:21:55,2025-01-24,1,1,10000,1,0,0,COMPLETE,US,gold,card\n\
+             E2,1,2025-01-24T07:21:55,2025-01-24,2,1,10000,1,0,0,COMPLETE,US,bronze,card\n",
+        );
+        let products = write(
+            "dim_products.csv",
+            "product_id,category,margin_bps,weight_grams\n1,apparel,2000,500\n",
+        );
+        let countries = write(
+            "dim_countries.csv",
+            "country,fx_to_usd_ppm,risk_bps,tax_bps\nUS,1000000,10000,850\n",
+        );
+        let output = dir.join("output.csv");
+
+        let summary = run(&events, &products, &countries, &output).unwrap();
+        let contents = fs::read_to_string(&output).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(summary.dedup_rows, 2);
+        let mut lines = contents.lines();
+        let header: Vec<&str> = lines.next().unwrap().split(',').collect();
+        assert_eq!(header.last(), Some(&"loyalty_points"));
+        let points: Vec<(&str, &str)> = lines
+            .map(|line| {
+                let cols: Vec<&str> = line.split(',').collect();
+                (cols[1], cols[cols.len() - 1])
+            })
+            .collect();
+        // $108.50 net is 108 points, doubled for gold.
+        assert_eq!(points, [("bronze", "108"), ("gold", "216")]);
+    }
+}
@@ -0,0 +1,318 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, BinaryHeap};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{group_key_dims, AggregateRecord, GroupKey};
+
+/// Customer-day of an order; its VIP flag is resolved while merging.
+pub(crate) type SpendKey = (String, i64, String);
+
+/// Keeps the run files of concurrent transforms in one `--spill-dir` apart.
+static NEXT_SPILL_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Most runs merged at once; more are first merged in batches.
+const MERGE_FAN_IN: usize = 64;
+
+struct SpilledOrder {
+    key: GroupKey,
+    spend_key: SpendKey,
+    order: AggregateRecord,
+}
+
+/// Run files of one transform, removed on drop.
+struct SpillRuns {
+    paths: Vec<PathBuf>,
+}
+
+impl Drop for SpillRuns {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Writes grouped orders to run files sorted by group key, `chunk_rows` at a time.
+pub(crate) struct SpillWriter {
+    dir: PathBuf,
+    spill_id: usize,
+    chunk_rows: usize,
+    buffer: Vec<SpilledOrder>,
+    runs: SpillRuns,
+    runs_created: usize,
+    orders: i64,
+    extra_names: BTreeSet<String>,
+}
+
+impl SpillWriter {
+    pub(crate) fn new(dir: &Path, chunk_rows: usize) -> Self {
+        SpillWriter {
+            dir: dir.to_path_buf(),
+            spill_id: NEXT_SPILL_ID.fetch_add(1, Ordering::Relaxed),
+            chunk_rows: chunk_rows.max(1),
+            buffer: Vec::new(),
+            runs: SpillRuns { paths: Vec::new() },
+            runs_created: 0,
+            orders: 0,
+            extra_names: BTreeSet::new(),
+        }
+    }
+
+    pub(crate) fn push(
+        &mut self,
+        key: GroupKey,
+        spend_key: SpendKey,
+        order: AggregateRecord,
+    ) -> io::Result<()> {
+        self.orders += 1;
+        for name in order.extra.keys() {
+            if !self.extra_names.contains(name) {
+                self.extra_names.insert(name.clone());
+            }
+        }
+        self.buffer.push(SpilledOrder {
+            key,
+            spend_key,
+            order,
+        });
+        if self.buffer.len() >= self.chunk_rows {
+            self.write_run()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn orders(&self) -> i64 {
+        self.orders
+    }
+
+    /// Every deriver and bin measure seen on a pushed order.
+    pub(crate) fn extra_names(&self) -> &BTreeSet<String> {
+        &self.extra_names
+    }
+
+    /// Registered before it is created, so a failed write is cleaned up too.
+    fn create_run(&mut self) -> io::Result<BufWriter<File>> {
+        let path = self.dir.join(format!(
+            ".spill-{}-{}-{}.bin",
+            std::process::id(),
+            self.spill_id,
+            self.runs_created
+        ));
+        self.runs_created += 1;
+        self.runs.paths.push(path.clone());
+        Ok(BufWriter::new(File::create(&path)?))
+    }
+
+    fn write_run(&mut self) -> io::Result<()> {
+        self.buffer.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+        let mut writer = self.create_run()?;
+        for spilled in std::mem::take(&mut self.buffer) {
+            write_order(&mut writer, &spilled)?;
+        }
+        writer.flush()
+    }
+
+    /// Writes the last run and merges the runs down to `MERGE_FAN_IN`.
+    pub(crate) fn into_merge(mut self) -> io::Result<SpillMerge> {
+        if !self.buffer.is_empty() {
+            self.write_run()?;
+        }
+        while self.runs.paths.len() > MERGE_FAN_IN {
+            let batch = SpillRuns {
+                paths: self.runs.paths.drain(..MERGE_FAN_IN).collect(),
+            };
+            let mut merge = SpillMerge::open(batch)?;
+            let mut writer = self.create_run()?;
+            while let Some(spilled) = merge.next_order()? {
+                write_order(&mut writer, &spilled)?;
+            }
+            writer.flush()?;
+        }
+        SpillMerge::open(self.runs)
+    }
+}
+
+/// K-way merge of the sorted runs into summed groups, in key order.
+pub(crate) struct SpillMerge {
+    readers: Vec<BufReader<File>>,
+    /// The next order of each run; its key waits in `heap`.
+    heads: Vec<Option<(SpendKey, AggregateRecord)>>,
+    heap: BinaryHeap<Reverse<(GroupKey, usize)>>,
+    /// The first order of the next group, read while finishing the last one.
+    pending: Option<SpilledOrder>,
+    _runs: SpillRuns,
+}
+
+impl SpillMerge {
+    fn open(runs: SpillRuns) -> io::Result<Self> {
+        let mut merge = SpillMerge {
+            readers: Vec::with_capacity(runs.paths.len()),
+            heads: Vec::with_capacity(runs.paths.len()),
+            heap: BinaryHeap::new(),
+            pending: None,
+            _runs: runs,
+        };
+        for path in &merge._runs.paths {
+            merge.readers.push(BufReader::new(File::open(path)?));
+        }
+        for run in 0..merge.readers.len() {
+            merge.heads.push(None);
+            merge.advance(run)?;
+        }
+        Ok(merge)
+    }
+
+    fn advance(&mut self, run: usize) -> io::Result<()> {
+        if let Some(next) = read_order(&mut self.readers[run])? {
+            self.heap.push(Reverse((next.key, run)));
+            self.heads[run] = Some((next.spend_key, next.order));
+        }
+        Ok(())
+    }
+
+    fn next_order(&mut self) -> io::Result<Option<SpilledOrder>> {
+        let Some(Reverse((key, run))) = self.heap.pop() else {
+            return Ok(None);
+        };
+        let (spend_key, order) = self.heads[run]
+            .take()
+            .expect("every key in the heap has its run's head order");
+        self.advance(run)?;
+        Ok(Some(SpilledOrder {
+            key,
+            spend_key,
+            order,
+        }))
+    }
+
+    /// `vip` gives each order's vip_customer_orders from its customer-day.
+    pub(crate) fn next_group(
+        &mut self,
+        vip: &dyn Fn(&SpendKey) -> i64,
+    ) -> io::Result<Option<(GroupKey, AggregateRecord)>> {
+        let first = match self.pending.take() {
+            Some(first) => first,
+            None => match self.next_order()? {
+                Some(first) => first,
+                None => return Ok(None),
+            },
+        };
+        let key = first.key;
+        let mut group = AggregateRecord::default();
+        let mut absorb = |spend_key: &SpendKey, mut order: AggregateRecord| {
+            order.vip_customer_orders = vip(spend_key);
+            group.absorb(&order)
+        };
+        absorb(&first.spend_key, first.order)?;
+        while let Some(next) = self.next_order()? {
+            if next.key != key {
+                self.pending = Some(next);
+                break;
+            }
+            absorb(&next.spend_key, next.order)?;
+        }
+        Ok(Some((key, group)))
+    }
+}
+
+// Run files hold each order as length-prefixed strings and little-endian
+// integers; vip_customer_orders is left out, as `next_group` sets it.
+
+fn write_str(writer: &mut impl Write, value: &str) -> io::Result<()> {
+    writer.write_all(&(value.len() as u64).to_le_bytes())?;
+    writer.write_all(value.as_bytes())
+}
+
+fn write_order(writer: &mut impl Write, spilled: &SpilledOrder) -> io::Result<()> {
+    for dim in group_key_dims(&spilled.key) {
+        write_str(writer, dim)?;
+    }
+    let (event_date, customer_id, country) = &spilled.spend_key;
+    write_str(writer, event_date)?;
+    writer.write_all(&customer_id.to_le_bytes())?;
+    write_str(writer, country)?;
+
+    let order = &spilled.order;
+    for value in [
+        order.order_count,
+        order.total_quantity,
+        order.total_net_usd_cents,
+        order.total_profit_usd_cents,
+        order.total_risk_adjusted_usd_cents,
+        order.total_items,
+        order.heavy_item_orders,
+        order.total_taxable_local_cents,
+        order.total_tax_local_cents,
+    ] {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    writer.write_all(&order.total_net_squared.to_le_bytes())?;
+    writer.write_all(&(order.extra.len() as u64).to_le_bytes())?;
+    for (name, value) in &order.extra {
+        write_str(writer, name)?;
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_i64(reader: &mut impl Read) -> io::Result<i64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(i64::from_le_bytes(bytes))
+}
+
+fn read_str(reader: &mut impl Read) -> io::Result<String> {
+    let mut bytes = vec![0; read_u64(reader)? as usize];
+    reader.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn read_order(reader: &mut impl BufRead) -> io::Result<Option<SpilledOrder>> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    let key = (
+        read_str(reader)?,
+        read_str(reader)?,
+        read_str(reader)?,
+        read_str(reader)?,
+        read_str(reader)?,
+        read_str(reader)?,
+        read_str(reader)?,
+    );
+    let spend_key = (read_str(reader)?, read_i64(reader)?, read_str(reader)?);
+
+    let mut order = AggregateRecord {
+        order_count: read_i64(reader)?,
+        total_quantity: read_i64(reader)?,
+        total_net_usd_cents: read_i64(reader)?,
+        total_profit_usd_cents: read_i64(reader)?,
+        total_risk_adjusted_usd_cents: read_i64(reader)?,
+        total_items: read_i64(reader)?,
+        heavy_item_orders: read_i64(reader)?,
+        total_taxable_local_cents: read_i64(reader)?,
+        total_tax_local_cents: read_i64(reader)?,
+        ..AggregateRecord::default()
+    };
+    let mut squared = [0; 16];
+    reader.read_exact(&mut squared)?;
+    order.total_net_squared = i128::from_le_bytes(squared);
+    for _ in 0..read_u64(reader)? {
+        let name = read_str(reader)?;
+        order.extra.insert(name, read_i64(reader)?);
+    }
+    Ok(Some(SpilledOrder {
+        key,
+        spend_key,
+        order,
+    }))
+}
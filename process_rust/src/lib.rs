@@ -0,0 +1,5477 @@
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+mod external_sort;
+mod ndjson_input;
+#[cfg(feature = "parquet")]
+mod parquet_output;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_dims;
+
+#[derive(Clone)]
+struct ProductDim {
+    category: String,
+    subcategory: String,
+    margin_bps: i64,
+    weight_grams: i64,
+}
+
+impl ProductDim {
+    /// Values for a product the dim does not list.
+    fn missing(label: &str) -> Self {
+        ProductDim {
+            category: label.to_string(),
+            subcategory: label.to_string(),
+            margin_bps: 2500,
+            weight_grams: 500,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct CountryDim {
+    fx_to_usd_ppm: i64,
+    risk_bps: i64,
+    tax_bps: i64,
+}
+
+impl CountryDim {
+    /// Values for a country the dim does not list.
+    fn missing() -> Self {
+        CountryDim {
+            fx_to_usd_ppm: 1_000_000,
+            risk_bps: 10_000,
+            tax_bps: 0,
+        }
+    }
+}
+
+/// A filtered, deduplicated event as read from the events file.
+#[derive(Clone)]
+pub struct EventRecord {
+    pub event_version: i64,
+    pub event_ts: String,
+    pub event_date: String,
+    pub customer_id: i64,
+    pub product_id: i64,
+    pub amount_cents: i64,
+    pub quantity: i64,
+    pub discount_bps: i64,
+    pub shipping_cents: i64,
+    pub country: String,
+    pub customer_tier: String,
+    /// From `--fx-key-column`; empty when FX is keyed by the country.
+    pub fx_key: String,
+}
+
+/// One enriched order, ready to be folded into its output group.
+#[derive(Clone)]
+pub struct DerivedRecord {
+    pub event_date: String,
+    pub customer_id: i64,
+    pub product_id: i64,
+    pub customer_tier: String,
+    pub category: String,
+    pub country: String,
+    pub time_bucket: String,
+    pub order_size_bucket: String,
+    /// Empty unless `--risk-buckets` adds the dimension.
+    pub risk_bucket: String,
+    pub quantity: i64,
+    pub net_usd_cents: i64,
+    pub profit_usd_cents: i64,
+    pub risk_adjusted_usd_cents: i64,
+    pub heavy_item_order: i64,
+    /// Local-currency tax base and tax, behind `--effective-tax`.
+    pub taxable_local_cents: i64,
+    pub tax_local_cents: i64,
+    /// Measures added by `TransformOptions::derivers`, summed per group.
+    pub extra: Vec<(String, i64)>,
+}
+
+#[derive(Default)]
+struct AggregateRecord {
+    order_count: i64,
+    vip_customer_orders: i64,
+    total_quantity: i64,
+    total_net_usd_cents: i64,
+    total_profit_usd_cents: i64,
+    total_risk_adjusted_usd_cents: i64,
+    total_items: i64,
+    heavy_item_orders: i64,
+    /// Sum of squared per-order net, for stddev_net_usd_cents.
+    total_net_squared: i128,
+    total_taxable_local_cents: i64,
+    total_tax_local_cents: i64,
+    extra: BTreeMap<String, i64>,
+}
+
+impl AggregateRecord {
+    /// Fails rather than wrapping when a total no longer fits in i64.
+    fn absorb(&mut self, other: &AggregateRecord) -> io::Result<()> {
+        add_total(&mut self.order_count, other.order_count, "order_count")?;
+        add_total(
+            &mut self.vip_customer_orders,
+            other.vip_customer_orders,
+            "vip_customer_orders",
+        )?;
+        add_total(
+            &mut self.total_quantity,
+            other.total_quantity,
+            "total_quantity",
+        )?;
+        add_total(
+            &mut self.total_net_usd_cents,
+            other.total_net_usd_cents,
+            "total_net_usd_cents",
+        )?;
+        add_total(
+            &mut self.total_profit_usd_cents,
+            other.total_profit_usd_cents,
+            "total_profit_usd_cents",
+        )?;
+        add_total(
+            &mut self.total_risk_adjusted_usd_cents,
+            other.total_risk_adjusted_usd_cents,
+            "total_risk_adjusted_usd_cents",
+        )?;
+        add_total(&mut self.total_items, other.total_items, "total_items")?;
+        add_total(
+            &mut self.heavy_item_orders,
+            other.heavy_item_orders,
+            "heavy_item_orders",
+        )?;
+        add_total(
+            &mut self.total_taxable_local_cents,
+            other.total_taxable_local_cents,
+            "total_taxable_local_cents",
+        )?;
+        add_total(
+            &mut self.total_tax_local_cents,
+            other.total_tax_local_cents,
+            "total_tax_local_cents",
+        )?;
+        self.total_net_squared = self
+            .total_net_squared
+            .checked_add(other.total_net_squared)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "total_net_squared overflowed i128 while aggregating",
+                )
+            })?;
+        for (name, value) in &other.extra {
+            add_total(self.extra.entry(name.clone()).or_insert(0), *value, name)?;
+        }
+        Ok(())
+    }
+}
+
+fn add_total(total: &mut i64, value: i64, column: &str) -> io::Result<()> {
+    *total = total.checked_add(value).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} overflowed i64 while aggregating", column),
+        )
+    })?;
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+enum ColumnKind {
+    String,
+    Int64,
+}
+
+impl ColumnKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            ColumnKind::String => "string",
+            ColumnKind::Int64 => "int64",
+        }
+    }
+}
+
+struct OutputColumn {
+    name: &'static str,
+    kind: ColumnKind,
+    unit: Option<&'static str>,
+}
+
+const fn dimension_column(name: &'static str) -> OutputColumn {
+    OutputColumn {
+        name,
+        kind: ColumnKind::String,
+        unit: None,
+    }
+}
+
+const fn measure_column(name: &'static str, unit: &'static str) -> OutputColumn {
+    OutputColumn {
+        name,
+        kind: ColumnKind::Int64,
+        unit: Some(unit),
+    }
+}
+
+// Metadata for a usd_cents column written in whole dollars by `--output-unit`.
+static USD_COLUMN: OutputColumn = measure_column("", "usd");
+
+// Metadata shared by every column a `Deriver` adds; the name comes from the deriver.
+static DERIVED_EXTRA_COLUMN: OutputColumn = OutputColumn {
+    name: "",
+    kind: ColumnKind::Int64,
+    unit: None,
+};
+
+const GROSS_LOCAL_COLUMN_NAME: &str = "total_gross_local_cents";
+
+static GROSS_LOCAL_COLUMN: OutputColumn = measure_column(GROSS_LOCAL_COLUMN_NAME, "local_cents");
+
+const EFFECTIVE_TAX_COLUMN_NAME: &str = "effective_tax_bps";
+
+static EFFECTIVE_TAX_COLUMN: OutputColumn = measure_column(EFFECTIVE_TAX_COLUMN_NAME, "bps");
+
+const QUANTITY_BIN_PREFIX: &str = "qty_bin_";
+
+static QUANTITY_BIN_COLUMN: OutputColumn = measure_column("", "count");
+
+/// Contiguous quantity bins from 1 up, so the bins sum to order_count.
+pub struct QuantityBins {
+    /// Inclusive `(low, high)` per bin; `None` for the open last bin.
+    bins: Vec<(i64, Option<i64>)>,
+    names: Vec<String>,
+}
+
+impl QuantityBins {
+    /// Parses `--quantity-bins`, e.g. `1,2,3,4-5,6-10,11+`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || {
+            format!(
+                "--quantity-bins expects contiguous bins from 1 ending in an open bin, like 1,2,3,4-5,6-10,11+, got {:?}",
+                value
+            )
+        };
+        let parse_bound = |bound: &str| bound.trim().parse::<i64>().map_err(|_| invalid());
+        let mut bins = Vec::new();
+        let mut names = Vec::new();
+        let mut next_low = 1;
+        let mut open = false;
+        for bin in value.split(',').map(str::trim) {
+            if open {
+                return Err(invalid());
+            }
+            let (low, high, name) = if let Some(low) = bin.strip_suffix('+') {
+                open = true;
+                let low = parse_bound(low)?;
+                (low, None, format!("{}_plus", low))
+            } else if let Some((low, high)) = bin.split_once('-') {
+                let (low, high) = (parse_bound(low)?, parse_bound(high)?);
+                if high < low {
+                    return Err(invalid());
+                }
+                (low, Some(high), format!("{}_{}", low, high))
+            } else {
+                let low = parse_bound(bin)?;
+                (low, Some(low), low.to_string())
+            };
+            if low != next_low {
+                return Err(invalid());
+            }
+            next_low = high.map_or(next_low, |high| high + 1);
+            bins.push((low, high));
+            names.push(format!("{}{}", QUANTITY_BIN_PREFIX, name));
+        }
+        if !open {
+            return Err(invalid());
+        }
+        Ok(QuantityBins { bins, names })
+    }
+
+    fn column_names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Anything below 1 falls in the first bin.
+    fn column_for(&self, quantity: i64) -> &str {
+        let idx = self
+            .bins
+            .iter()
+            .position(|&(_, high)| high.is_none_or(|high| quantity <= high))
+            .unwrap_or(self.bins.len() - 1);
+        &self.names[idx]
+    }
+
+    fn as_spec(&self) -> String {
+        let bins: Vec<String> = self
+            .bins
+            .iter()
+            .map(|&(low, high)| match high {
+                None => format!("{}+", low),
+                Some(high) if high == low => low.to_string(),
+                Some(high) => format!("{}-{}", low, high),
+            })
+            .collect();
+        bins.join(",")
+    }
+}
+
+fn extra_output_column(name: &str) -> &'static OutputColumn {
+    match name {
+        GROSS_LOCAL_COLUMN_NAME => &GROSS_LOCAL_COLUMN,
+        EFFECTIVE_TAX_COLUMN_NAME => &EFFECTIVE_TAX_COLUMN,
+        _ if name.starts_with(QUANTITY_BIN_PREFIX) => &QUANTITY_BIN_COLUMN,
+        _ => &DERIVED_EXTRA_COLUMN,
+    }
+}
+
+/// effective_tax_bps is a ratio of the group's totals; every other extra is a sum.
+fn extra_value(agg: &AggregateRecord, name: &str) -> i64 {
+    if name == EFFECTIVE_TAX_COLUMN_NAME {
+        return round_div(
+            agg.total_tax_local_cents.saturating_mul(10_000),
+            agg.total_taxable_local_cents,
+        );
+    }
+    agg.extra.get(name).copied().unwrap_or(0)
+}
+
+// Single source of truth for the output layout: the CSV header and the
+// schema sidecar are both generated from this table.
+static OUTPUT_COLUMNS: [OutputColumn; 19] = [
+    dimension_column("event_date"),
+    dimension_column("customer_tier"),
+    dimension_column("category"),
+    dimension_column("country"),
+    dimension_column("time_bucket"),
+    dimension_column("order_size_bucket"),
+    dimension_column("risk_bucket"),
+    measure_column("order_count", "count"),
+    measure_column("vip_customer_orders", "count"),
+    measure_column("total_quantity", "items"),
+    measure_column("total_net_usd_cents", "usd_cents"),
+    measure_column("total_profit_usd_cents", "usd_cents"),
+    measure_column("total_risk_adjusted_usd_cents", "usd_cents"),
+    measure_column("avg_item_price_usd_cents", "usd_cents"),
+    measure_column("heavy_item_orders", "count"),
+    measure_column("vip_order_rate_bps", "bps"),
+    measure_column("profit_margin_bps", "bps"),
+    measure_column("stddev_net_usd_cents", "usd_cents"),
+    measure_column("avg_quantity_centi", "centi_items"),
+];
+
+#[derive(Clone, Copy)]
+enum CellValue<'a> {
+    Text(&'a str),
+    Int(i64),
+}
+
+impl fmt::Display for CellValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CellValue::Text(value) => f.write_str(value),
+            CellValue::Int(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+pub fn resolve_output_columns(names: &str) -> Result<Vec<usize>, String> {
+    let mut selected = Vec::new();
+    for name in names.split(',').map(str::trim) {
+        let idx = OUTPUT_COLUMNS
+            .iter()
+            .position(|column| column.name == name)
+            .ok_or_else(|| format!("unknown output column: {:?}", name))?;
+        if selected.contains(&idx) {
+            return Err(format!("output column listed twice: {:?}", name));
+        }
+        selected.push(idx);
+    }
+    Ok(selected)
+}
+
+const DIMENSION_COLUMN_COUNT: usize = 7;
+const COUNTRY_COLUMN: usize = 3;
+/// Only grouped on, and only emitted, under `--risk-buckets`.
+const RISK_BUCKET_COLUMN: usize = 6;
+
+/// Parses a `--dense <dimension>=<value,...>` domain.
+pub fn parse_dense_domain(value: &str) -> Result<(usize, Vec<String>), String> {
+    let (dimension_name, values) = value
+        .split_once('=')
+        .ok_or_else(|| format!("--dense expects <dimension>=<value,...>, got {:?}", value))?;
+    let dimension = OUTPUT_COLUMNS[..DIMENSION_COLUMN_COUNT]
+        .iter()
+        .position(|column| column.name == dimension_name.trim())
+        .ok_or_else(|| {
+            format!(
+                "--dense dimension must be a grouping column, got {:?}",
+                dimension_name
+            )
+        })?;
+    let domain: Vec<String> = values
+        .split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    if domain.is_empty() {
+        return Err(format!(
+            "--dense {} needs at least one value",
+            dimension_name
+        ));
+    }
+    Ok((dimension, domain))
+}
+
+/// Resolves a `--measures` list; dimension columns are always emitted, so
+/// only measure columns may be named.
+pub fn resolve_measures(names: &str) -> Result<Vec<usize>, String> {
+    let selected = resolve_output_columns(names)?;
+    if let Some(&idx) = selected.iter().find(|&&idx| idx < DIMENSION_COLUMN_COUNT) {
+        return Err(format!(
+            "--measures accepts only measure columns, got {:?}",
+            OUTPUT_COLUMNS[idx].name
+        ));
+    }
+    Ok(selected)
+}
+
+/// Which per-order values are accumulated; a derived rate keeps its inputs.
+struct MeasureMask {
+    vip: bool,
+    quantity: bool,
+    net: bool,
+    profit: bool,
+    risk: bool,
+    items: bool,
+    heavy: bool,
+    net_squared: bool,
+}
+
+impl MeasureMask {
+    fn for_options(options: &TransformOptions) -> Self {
+        let Some(measures) = &options.measures else {
+            return MeasureMask {
+                vip: true,
+                quantity: true,
+                net: true,
+                profit: true,
+                risk: true,
+                items: true,
+                heavy: true,
+                net_squared: true,
+            };
+        };
+        let selected = |name: &str| measures.iter().any(|&idx| OUTPUT_COLUMNS[idx].name == name);
+        MeasureMask {
+            vip: selected("vip_customer_orders") || selected("vip_order_rate_bps"),
+            quantity: selected("total_quantity") || selected("avg_quantity_centi"),
+            net: selected("total_net_usd_cents")
+                || selected("avg_item_price_usd_cents")
+                || selected("profit_margin_bps")
+                || selected("stddev_net_usd_cents")
+                || options.verify
+                || options.min_net_usd_cents.is_some(),
+            profit: selected("total_profit_usd_cents") || selected("profit_margin_bps"),
+            risk: selected("total_risk_adjusted_usd_cents"),
+            items: selected("avg_item_price_usd_cents"),
+            heavy: selected("heavy_item_orders"),
+            net_squared: selected("stddev_net_usd_cents"),
+        }
+    }
+}
+
+type GroupKey = (String, String, String, String, String, String, String);
+
+fn group_key_dims(key: &GroupKey) -> [&str; DIMENSION_COLUMN_COUNT] {
+    [&key.0, &key.1, &key.2, &key.3, &key.4, &key.5, &key.6]
+}
+
+// Values for one aggregate row, in OUTPUT_COLUMNS order.
+fn output_values<'a>(key: &'a GroupKey, agg: &AggregateRecord) -> [CellValue<'a>; 19] {
+    let avg_item_price_usd_cents = round_div(agg.total_net_usd_cents, agg.total_items);
+    // round_div yields 0 for an empty group rather than dividing by zero.
+    let vip_order_rate_bps = round_div_wide(
+        i128::from(agg.vip_customer_orders) * 10_000,
+        agg.order_count,
+    );
+    // Mean quantity per order in hundredths, so 2.5 items is 250.
+    let avg_quantity_centi = round_div(agg.total_quantity.saturating_mul(100), agg.order_count);
+    // Negative when the group lost money; 0 when net is zero or negative.
+    let profit_margin_bps = round_div_wide(
+        i128::from(agg.total_profit_usd_cents) * 10_000,
+        agg.total_net_usd_cents,
+    );
+    [
+        CellValue::Text(&key.0),
+        CellValue::Text(&key.1),
+        CellValue::Text(&key.2),
+        CellValue::Text(&key.3),
+        CellValue::Text(&key.4),
+        CellValue::Text(&key.5),
+        CellValue::Text(&key.6),
+        CellValue::Int(agg.order_count),
+        CellValue::Int(agg.vip_customer_orders),
+        CellValue::Int(agg.total_quantity),
+        CellValue::Int(agg.total_net_usd_cents),
+        CellValue::Int(agg.total_profit_usd_cents),
+        CellValue::Int(agg.total_risk_adjusted_usd_cents),
+        CellValue::Int(avg_item_price_usd_cents),
+        CellValue::Int(agg.heavy_item_orders),
+        CellValue::Int(vip_order_rate_bps),
+        CellValue::Int(profit_margin_bps),
+        CellValue::Int(population_stddev(
+            agg.total_net_usd_cents,
+            agg.total_net_squared,
+            agg.order_count,
+        )),
+        CellValue::Int(avg_quantity_centi),
+    ]
+}
+
+/// sqrt(n * sum_sq - sum^2) / n, rounded half-up.
+fn population_stddev(sum: i64, sum_squares: i128, count: i64) -> i64 {
+    if count <= 1 {
+        return 0;
+    }
+    let count = count as u128;
+    let sum = i128::from(sum).unsigned_abs();
+    let spread = (count.saturating_mul(sum_squares.max(0) as u128)).saturating_sub(sum * sum);
+    let mut stddev = spread.isqrt() / count;
+    // Round up when sqrt(spread) / count >= stddev + 0.5.
+    if spread.saturating_mul(4) >= ((2 * stddev + 1) * count).pow(2) {
+        stddev += 1;
+    }
+    stddev as i64
+}
+
+/// Pivots one dimension into `<measure>_<value>` columns for one measure.
+pub struct PivotSpec {
+    dimension: usize,
+    measure: usize,
+}
+
+pub fn parse_pivot_spec(value: &str) -> Result<PivotSpec, String> {
+    let (dimension_name, measure_name) = value
+        .split_once(':')
+        .ok_or_else(|| format!("--pivot expects <dimension>:<measure>, got {:?}", value))?;
+    let position = |name: &str| {
+        OUTPUT_COLUMNS
+            .iter()
+            .position(|column| column.name == name.trim())
+    };
+
+    let dimension = match position(dimension_name) {
+        Some(idx) if idx < DIMENSION_COLUMN_COUNT => idx,
+        _ => {
+            return Err(format!(
+                "--pivot dimension must be a grouping column, got {:?}",
+                dimension_name
+            ))
+        }
+    };
+    let measure = match position(measure_name) {
+        Some(idx) if idx >= DIMENSION_COLUMN_COUNT => idx,
+        _ => {
+            return Err(format!(
+                "--pivot measure must be a measure column, got {:?}",
+                measure_name
+            ))
+        }
+    };
+
+    Ok(PivotSpec { dimension, measure })
+}
+
+/// Whether negative per-order profit is kept or clamped to zero before aggregation.
+#[derive(Clone, Copy, Default)]
+pub enum ProfitFloor {
+    #[default]
+    Signed,
+    Zero,
+}
+
+impl ProfitFloor {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "signed" => Ok(ProfitFloor::Signed),
+            "zero" => Ok(ProfitFloor::Zero),
+            _ => Err(format!(
+                "--profit-floor expects signed or zero, got {:?}",
+                value
+            )),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ProfitFloor::Signed => "signed",
+            ProfitFloor::Zero => "zero",
+        }
+    }
+
+    fn apply(self, profit_usd_cents: i64) -> i64 {
+        match self {
+            ProfitFloor::Signed => profit_usd_cents,
+            ProfitFloor::Zero => std::cmp::max(profit_usd_cents, 0),
+        }
+    }
+}
+
+/// `Scale` is `net * risk_bps / 10000`; `Haircut` is `net * (10000 - risk_bps) / 10000`.
+#[derive(Clone, Copy, Default)]
+pub enum RiskMode {
+    #[default]
+    Scale,
+    Haircut,
+}
+
+impl RiskMode {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "scale" => Ok(RiskMode::Scale),
+            "haircut" => Ok(RiskMode::Haircut),
+            _ => Err(format!(
+                "--risk-mode expects scale or haircut, got {:?}",
+                value
+            )),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RiskMode::Scale => "scale",
+            RiskMode::Haircut => "haircut",
+        }
+    }
+
+    fn apply(self, net_usd_cents: i64, risk_bps: i64, rounding: RoundingMode) -> i64 {
+        match self {
+            RiskMode::Scale => rounding.divide(net_usd_cents * risk_bps, 10_000),
+            // Clamped: rounding keeps signs, so a risk above 10000 bps would
+            // otherwise turn the haircut negative.
+            RiskMode::Haircut => {
+                rounding.divide(net_usd_cents * (10_000 - risk_bps).max(0), 10_000)
+            }
+        }
+    }
+}
+
+/// Which of several rows sharing an event_id survives deduplication.
+#[derive(Clone, Copy, Default)]
+pub enum DedupKeep {
+    #[default]
+    LatestVersion,
+    First,
+    Last,
+}
+
+impl DedupKeep {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "latest-version" => Ok(DedupKeep::LatestVersion),
+            "first" => Ok(DedupKeep::First),
+            "last" => Ok(DedupKeep::Last),
+            _ => Err(format!(
+                "--dedup-keep expects latest-version, first or last, got {:?}",
+                value
+            )),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DedupKeep::LatestVersion => "latest-version",
+            DedupKeep::First => "first",
+            DedupKeep::Last => "last",
+        }
+    }
+
+    fn replaces(self, current: &EventRecord, candidate: &EventRecord) -> bool {
+        match self {
+            DedupKeep::LatestVersion => {
+                candidate.event_version > current.event_version
+                    || (candidate.event_version == current.event_version
+                        && candidate.event_ts > current.event_ts)
+            }
+            DedupKeep::First => false,
+            DedupKeep::Last => true,
+        }
+    }
+}
+
+/// What to do with rows sharing event_id, version and event_ts but not amount.
+#[derive(Clone, Copy, Default)]
+pub enum DedupConflict {
+    #[default]
+    KeepFirst,
+    RejectConflict,
+    PreferHigherAmount,
+}
+
+impl DedupConflict {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "keep-first" => Ok(DedupConflict::KeepFirst),
+            "reject-conflict" => Ok(DedupConflict::RejectConflict),
+            "prefer-higher-amount" => Ok(DedupConflict::PreferHigherAmount),
+            _ => Err(format!(
+                "--dedup-conflict expects keep-first, reject-conflict or prefer-higher-amount, got {:?}",
+                value
+            )),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DedupConflict::KeepFirst => "keep-first",
+            DedupConflict::RejectConflict => "reject-conflict",
+            DedupConflict::PreferHigherAmount => "prefer-higher-amount",
+        }
+    }
+
+    fn conflicts(current: &EventRecord, candidate: &EventRecord) -> bool {
+        candidate.event_version == current.event_version
+            && candidate.event_ts == current.event_ts
+            && candidate.amount_cents != current.amount_cents
+    }
+
+    fn replaces(self, current: &EventRecord, candidate: &EventRecord) -> bool {
+        match self {
+            DedupConflict::KeepFirst | DedupConflict::RejectConflict => false,
+            DedupConflict::PreferHigherAmount => candidate.amount_cents > current.amount_cents,
+        }
+    }
+}
+
+/// How customer spend is pooled for the VIP threshold.
+#[derive(Clone, Copy, Default)]
+pub enum VipKey {
+    #[default]
+    DateCustomer,
+    DateCustomerCountry,
+}
+
+impl VipKey {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "date-customer" => Ok(VipKey::DateCustomer),
+            "date-customer-country" => Ok(VipKey::DateCustomerCountry),
+            _ => Err(format!(
+                "--vip-key expects date-customer or date-customer-country, got {:?}",
+                value
+            )),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            VipKey::DateCustomer => "date-customer",
+            VipKey::DateCustomerCountry => "date-customer-country",
+        }
+    }
+
+    fn spend_key(self, row: &DerivedRecord) -> (String, i64, String) {
+        let country = match self {
+            VipKey::DateCustomer => String::new(),
+            VipKey::DateCustomerCountry => row.country.clone(),
+        };
+        (row.event_date.clone(), row.customer_id, country)
+    }
+}
+
+/// What happens to an event_version that is not a non-negative integer.
+#[derive(Clone, Copy, Default)]
+pub enum InvalidVersionPolicy {
+    #[default]
+    Zero,
+    Lowest,
+    Reject,
+}
+
+impl InvalidVersionPolicy {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "zero" => Ok(InvalidVersionPolicy::Zero),
+            "lowest" => Ok(InvalidVersionPolicy::Lowest),
+            "reject" => Ok(InvalidVersionPolicy::Reject),
+            _ => Err(format!(
+                "--invalid-version expects zero, lowest or reject, got {:?}",
+                value
+            )),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            InvalidVersionPolicy::Zero => "zero",
+            InvalidVersionPolicy::Lowest => "lowest",
+            InvalidVersionPolicy::Reject => "reject",
+        }
+    }
+}
+
+/// What a blank or unparseable discount_bps or shipping_cents means.
+#[derive(Clone, Copy, Default)]
+pub enum NumericMissing {
+    #[default]
+    Zero,
+    Reject,
+}
+
+impl NumericMissing {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "zero" => Ok(NumericMissing::Zero),
+            "reject" => Ok(NumericMissing::Reject),
+            _ => Err(format!(
+                "--numeric-missing expects zero or reject, got {:?}",
+                value
+            )),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NumericMissing::Zero => "zero",
+            NumericMissing::Reject => "reject",
+        }
+    }
+}
+
+/// How a division rounds; a negative numerator rounds like its magnitude.
+#[derive(Clone, Copy, Default)]
+pub enum RoundingMode {
+    #[default]
+    HalfUp,
+    HalfEven,
+    Truncate,
+}
+
+impl RoundingMode {
+    pub fn parse(flag: &str, value: &str) -> Result<Self, String> {
+        match value {
+            "half-up" => Ok(RoundingMode::HalfUp),
+            "half-even" => Ok(RoundingMode::HalfEven),
+            "truncate" => Ok(RoundingMode::Truncate),
+            _ => Err(format!(
+                "{} expects half-up, half-even or truncate, got {:?}",
+                flag, value
+            )),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RoundingMode::HalfUp => "half-up",
+            RoundingMode::HalfEven => "half-even",
+            RoundingMode::Truncate => "truncate",
+        }
+    }
+
+    /// Like `round_div`, 0 for a non-positive denominator.
+    fn divide(self, numerator: i64, denominator: i64) -> i64 {
+        if denominator <= 0 {
+            return 0;
+        }
+        if numerator < 0 {
+            return -self.divide(numerator.saturating_neg(), denominator);
+        }
+        let quotient = numerator / denominator;
+        let remainder = numerator % denominator;
+        let round_up = match self {
+            RoundingMode::HalfUp => remainder >= denominator - remainder,
+            RoundingMode::HalfEven => {
+                let twice = remainder * 2;
+                twice > denominator || (twice == denominator && quotient % 2 == 1)
+            }
+            RoundingMode::Truncate => false,
+        };
+        quotient + i64::from(round_up)
+    }
+}
+
+/// Unit of the USD measures as written; computation is always in cents.
+#[derive(Clone, Copy, Default)]
+pub enum OutputUnit {
+    #[default]
+    Cents,
+    Dollars,
+}
+
+impl OutputUnit {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "cents" => Ok(OutputUnit::Cents),
+            "dollars" => Ok(OutputUnit::Dollars),
+            _ => Err(format!(
+                "--output-unit expects cents or dollars, got {:?}",
+                value
+            )),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OutputUnit::Cents => "cents",
+            OutputUnit::Dollars => "dollars",
+        }
+    }
+
+    /// Shared by the schema sidecar and `WholeDollarSink`.
+    fn columns(
+        self,
+        columns: &[(String, &'static OutputColumn)],
+    ) -> Vec<(String, &'static OutputColumn)> {
+        columns
+            .iter()
+            .map(|(name, column)| match self {
+                OutputUnit::Dollars if column.unit == Some("usd_cents") => {
+                    let name = match name.strip_suffix("_usd_cents") {
+                        Some(stem) => format!("{}_usd", stem),
+                        None => name.clone(),
+                    };
+                    (name, &USD_COLUMN)
+                }
+                _ => (name.clone(), *column),
+            })
+            .collect()
+    }
+}
+
+/// `Ndjson` lines are objects keyed by the CSV header names, with no header line.
+#[derive(Clone, Copy, Default)]
+pub enum EventsFormat {
+    #[default]
+    Csv,
+    Ndjson,
+}
+
+impl EventsFormat {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "csv" => Ok(EventsFormat::Csv),
+            "ndjson" => Ok(EventsFormat::Ndjson),
+            _ => Err(format!(
+                "--events-format expects csv or ndjson, got {:?}",
+                value
+            )),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventsFormat::Csv => "csv",
+            EventsFormat::Ndjson => "ndjson",
+        }
+    }
+}
+
+/// `DateTime` feeds carry a `hh:mm:ss` event_ts, combined with event_date.
+#[derive(Clone, Copy, Default)]
+pub enum TimestampLayout {
+    #[default]
+    Ts,
+    DateTime,
+}
+
+impl TimestampLayout {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "ts" => Ok(TimestampLayout::Ts),
+            "date-time" => Ok(TimestampLayout::DateTime),
+            _ => Err(format!(
+                "--timestamp-layout expects ts or date-time, got {:?}",
+                value
+            )),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TimestampLayout::Ts => "ts",
+            TimestampLayout::DateTime => "date-time",
+        }
+    }
+
+    fn event_ts<'a>(self, ts_field: &'a str, event_date: &str) -> Cow<'a, str> {
+        match self {
+            TimestampLayout::Ts => Cow::Borrowed(ts_field),
+            TimestampLayout::DateTime if ts_field.is_empty() || event_date.is_empty() => {
+                Cow::Borrowed("")
+            }
+            TimestampLayout::DateTime => Cow::Owned(format!("{}T{}", event_date, ts_field)),
+        }
+    }
+}
+
+/// `Subcategory` groups by `<category>/<subcategory>`.
+#[derive(Clone, Copy, Default)]
+pub enum CategoryLevel {
+    #[default]
+    Category,
+    Subcategory,
+}
+
+impl CategoryLevel {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "category" => Ok(CategoryLevel::Category),
+            "subcategory" => Ok(CategoryLevel::Subcategory),
+            _ => Err(format!(
+                "--category-level expects category or subcategory, got {:?}",
+                value
+            )),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CategoryLevel::Category => "category",
+            CategoryLevel::Subcategory => "subcategory",
+        }
+    }
+}
+
+/// Which date an event is grouped under; `UtcTs` converts event_ts to UTC.
+#[derive(Clone, Copy, Default)]
+pub enum DateSource {
+    #[default]
+    Column,
+    UtcTs,
+}
+
+impl DateSource {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "column" => Ok(DateSource::Column),
+            "utc-ts" => Ok(DateSource::UtcTs),
+            _ => Err(format!(
+                "--date-from expects column or utc-ts, got {:?}",
+                value
+            )),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DateSource::Column => "column",
+            DateSource::UtcTs => "utc-ts",
+        }
+    }
+
+    fn event_date<'a>(self, source_date: &'a str, event_ts: &str) -> Cow<'a, str> {
+        match self {
+            DateSource::Column => Cow::Borrowed(source_date),
+            DateSource::UtcTs => utc_date(event_ts).map_or(Cow::Borrowed(source_date), Cow::Owned),
+        }
+    }
+}
+
+/// What happens when event_ts's date prefix disagrees with event_date.
+#[derive(Clone, Copy, Default)]
+pub enum DateTsMismatch {
+    #[default]
+    Keep,
+    Reject,
+    UseTs,
+}
+
+impl DateTsMismatch {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "keep" => Ok(DateTsMismatch::Keep),
+            "reject" => Ok(DateTsMismatch::Reject),
+            "use-ts" => Ok(DateTsMismatch::UseTs),
+            _ => Err(format!(
+                "--date-ts-mismatch expects keep, reject or use-ts, got {:?}",
+                value
+            )),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DateTsMismatch::Keep => "keep",
+            DateTsMismatch::Reject => "reject",
+            DateTsMismatch::UseTs => "use-ts",
+        }
+    }
+
+    /// The event_date to group under, or `None` to reject the row.
+    fn resolve<'a>(self, event_date: Cow<'a, str>, event_ts: &str) -> Option<Cow<'a, str>> {
+        let ts_date = match event_ts.get(..10) {
+            Some(ts_date) if ts_date != event_date => ts_date,
+            _ => return Some(event_date),
+        };
+        match self {
+            DateTsMismatch::Keep => Some(event_date),
+            DateTsMismatch::Reject => None,
+            DateTsMismatch::UseTs => Some(Cow::Owned(ts_date.to_string())),
+        }
+    }
+}
+
+/// The UTC date of a `YYYY-MM-DDThh:mm:ss[.fff][Z|±hh:mm]` timestamp.
+fn utc_date(event_ts: &str) -> Option<String> {
+    let number = |start: usize, end: usize| {
+        let digits = event_ts.get(start..end)?;
+        digits
+            .bytes()
+            .all(|byte| byte.is_ascii_digit())
+            .then(|| digits.parse::<i64>().ok())?
+    };
+    let bytes = event_ts.as_bytes();
+    if bytes.len() < 19
+        || bytes[4] != b'-'
+        || bytes[7] != b'-'
+        || bytes[10] != b'T'
+        || bytes[13] != b':'
+        || bytes[16] != b':'
+    {
+        return None;
+    }
+    let (year, month, day) = (number(0, 4)?, number(5, 7)?, number(8, 10)?);
+    let (hour, minute) = (number(11, 13)?, number(14, 16)?);
+    number(17, 19)?;
+    if !(1..=12).contains(&month)
+        || !(1..=days_in_month(year, month)).contains(&day)
+        || hour > 23
+        || minute > 59
+    {
+        return None;
+    }
+
+    let mut zone = &event_ts[19..];
+    if let Some(fraction) = zone.strip_prefix('.') {
+        zone = fraction.trim_start_matches(|ch: char| ch.is_ascii_digit());
+    }
+    let offset_minutes = match zone {
+        "" | "Z" => 0,
+        _ => {
+            let sign = match zone.as_bytes()[0] {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            let (hours, minutes) = zone[1..].split_once(':')?;
+            let (hours, minutes) = (hours.parse::<i64>().ok()?, minutes.parse::<i64>().ok()?);
+            if hours > 23 || minutes > 59 || zone.len() != 6 {
+                return None;
+            }
+            sign * (hours * 60 + minutes)
+        }
+    };
+
+    let (mut year, mut month, mut day) = (year, month, day);
+    match (hour * 60 + minute - offset_minutes).div_euclid(24 * 60) {
+        -1 if day > 1 => day -= 1,
+        -1 if month > 1 => {
+            month -= 1;
+            day = days_in_month(year, month);
+        }
+        -1 => (year, month, day) = (year - 1, 12, 31),
+        1 if day < days_in_month(year, month) => day += 1,
+        1 if month < 12 => (month, day) = (month + 1, 1),
+        1 => (year, month, day) = (year + 1, 1, 1),
+        _ => {}
+    }
+    Some(format!("{:04}-{:02}-{:02}", year, month, day))
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Adds per-order measures, each summed per group into a trailing column.
+pub trait Deriver: Send + Sync {
+    fn derive_extra(&self, record: &EventRecord, derived: &DerivedRecord) -> Vec<(String, i64)>;
+}
+
+#[derive(Default)]
+pub struct TransformOptions {
+    pub emit_schema: bool,
+    pub max_unknown_tier_rate: Option<f64>,
+    pub country_aliases_path: Option<PathBuf>,
+    pub require_country_dim: bool,
+    /// Events column that keys the FX rate instead of `country`.
+    pub fx_key_column: Option<String>,
+    /// Whether shipping is in the discount and tax bases; both when unset.
+    pub discount_applies_to_shipping: Option<bool>,
+    pub tax_applies_to_shipping: Option<bool>,
+    pub category_margins: HashMap<String, i64>,
+    /// Per-country unit (1, 10 or 100 cents) for the per-order USD measures.
+    pub usd_rounding_units: HashMap<String, i64>,
+    /// Per-tier VIP daily spend; other tiers use `VIP_SPEND_THRESHOLD_USD_CENTS`.
+    pub vip_thresholds: HashMap<String, i64>,
+    /// Patches applied to the loaded dims in order.
+    pub product_overrides: Vec<ProductOverride>,
+    pub country_overrides: Vec<CountryOverride>,
+    pub dated_fx_path: Option<PathBuf>,
+    pub columns: Option<Vec<usize>>,
+    pub pivot: Option<PivotSpec>,
+    pub profit_floor: ProfitFloor,
+    pub risk_mode: RiskMode,
+    pub input_format: InputFormat,
+    pub max_amount_cents: Option<i64>,
+    pub max_quantity: Option<i64>,
+    #[cfg(feature = "sqlite")]
+    pub sqlite_dims: Option<sqlite_dims::SqliteDimSource>,
+    pub output_format: OutputFormat,
+    pub parquet_compression: ParquetCompression,
+    pub min_order_count: Option<i64>,
+    pub suppressed_other: bool,
+    pub unknown_label: Option<String>,
+    /// Skips lowercasing categories and subcategories at load.
+    pub preserve_category_case: bool,
+    /// Lowercases the emitted category only.
+    pub lowercase_output_category: bool,
+    /// Prefix of comment lines before a dim's header; `#` when unset.
+    pub dim_comment_prefix: Option<String>,
+    pub timestamp_layout: TimestampLayout,
+    pub reject_full_discount: bool,
+    pub partition_by_date: bool,
+    pub min_net_usd_cents: Option<i64>,
+    pub dedup_keep: DedupKeep,
+    pub dedup_conflict: DedupConflict,
+    pub vip_key: VipKey,
+    pub superseded_out_path: Option<PathBuf>,
+    /// Where to dump the per-customer-day spend behind vip_customer_orders.
+    pub customer_day_out_path: Option<PathBuf>,
+    /// The CLI always sets one.
+    pub run_id: Option<String>,
+    pub run_id_column: bool,
+    /// Renames each data file into place once complete.
+    pub atomic_output: bool,
+    /// Writes `<file>.sha256` over each data file as stored (after gzip).
+    pub checksum_output: bool,
+    /// Where `--audit-versions` reports event_version gaps and decreases.
+    pub audit_versions_path: Option<PathBuf>,
+    /// Products in `<output>.top_products.csv`, ranked by net revenue.
+    pub top_products: Option<usize>,
+    pub output_number_format: OutputNumberFormat,
+    pub output_unit: OutputUnit,
+    /// Rounding of whole-dollar output; truncation when unset.
+    pub output_unit_rounding: Option<RoundingMode>,
+    pub verify: bool,
+    pub customer_tier_dim_path: Option<PathBuf>,
+    /// Lower clamp for shipping_cents; negative values keep shipping credits.
+    pub min_shipping_cents: i64,
+    pub derivers: Vec<Box<dyn Deriver>>,
+    /// Measure columns to accumulate and emit after the dimensions.
+    pub measures: Option<Vec<usize>>,
+    /// Every column, in `canonical_columns` order.
+    pub canonical: bool,
+    /// Debug only: shuffle the final rows with this seed before writing.
+    pub shuffle_output_seed: Option<u64>,
+    pub explain_event_id: Option<String>,
+    pub fx_round_places: Option<u32>,
+    /// Categories whose orders are always heavy, whatever their weight.
+    pub heavy_categories: BTreeSet<String>,
+    /// Per-category heavy weight in grams; others use `HEAVY_ORDER_GRAMS`.
+    pub heavy_grams_by_category: HashMap<String, i64>,
+    /// Adds a `qty_bin_*` order count per bin after the standard columns.
+    pub quantity_bins: Option<QuantityBins>,
+    /// Value domain per dimension index for `--dense` zero-filling.
+    pub dense_domains: BTreeMap<usize, Vec<String>>,
+    /// Adds `risk_bucket`, from the country's risk_bps, to the group key.
+    pub risk_buckets: Option<RiskBuckets>,
+    /// Splits output into numbered part files of at most this many data rows.
+    pub max_rows_per_file: Option<usize>,
+    /// Earlier aggregate to compare against; the diff lands next to the output.
+    pub diff_baseline_path: Option<PathBuf>,
+    /// Product_ids to keep or drop while reading events.
+    pub include_products_path: Option<PathBuf>,
+    pub exclude_products_path: Option<PathBuf>,
+    /// File of customer_ids whose events are dropped while reading.
+    pub exclude_customers_path: Option<PathBuf>,
+    pub allow_missing_dims: bool,
+    pub invalid_version: InvalidVersionPolicy,
+    pub numeric_missing: NumericMissing,
+    pub progress: bool,
+    /// Per-input overrides of `input_format.delimiter`.
+    pub events_delimiter: Option<char>,
+    pub product_delimiter: Option<char>,
+    pub country_delimiter: Option<char>,
+    /// Accepted tiers from lowest to highest; `None` keeps `DEFAULT_TIERS`.
+    pub tiers: Option<Vec<String>>,
+    /// Directory for one file per country, written from the final aggregate.
+    pub country_subtotals_dir: Option<PathBuf>,
+    pub mark_missing_dims: bool,
+    pub events_format: EventsFormat,
+    /// Adds total_gross_local_cents; needs `groups_by_country`.
+    pub gross_local: bool,
+    /// Adds effective_tax_bps; like `gross_local`, needs `groups_by_country`.
+    pub effective_tax: bool,
+    /// Rounding of the per-order discount, tax, cost and risk divisions.
+    pub rounding: RoundingMode,
+    /// Rounding of the ppm FX conversion to USD cents.
+    pub fx_rounding: RoundingMode,
+    /// Raised by the CLI's SIGINT handler; see `check_interrupt`.
+    pub interrupt: Option<Arc<AtomicBool>>,
+    pub date_from: DateSource,
+    pub date_ts_mismatch: DateTsMismatch,
+    pub category_level: CategoryLevel,
+    /// Fail instead of writing a header-only output when no groups remain.
+    pub fail_on_empty: bool,
+    /// Longer events lines are rejected as `line_too_long` without being buffered.
+    pub max_line_bytes: Option<usize>,
+    /// Leading data rows ignored; counted in `skipped_rows`, not `raw_rows`.
+    pub skip_rows: usize,
+    /// Aggregates by external sort; bounds group memory, not deduplication.
+    pub spill_dir: Option<PathBuf>,
+    /// Orders per sorted run; `DEFAULT_SPILL_CHUNK_ROWS` when unset.
+    pub spill_chunk_rows: Option<usize>,
+}
+
+const DEFAULT_UNKNOWN_LABEL: &str = "unknown";
+const DEFAULT_DIM_COMMENT_PREFIX: &str = "#";
+/// A customer-day (see `VipKey`) at or above this net spend makes its orders VIP.
+const VIP_SPEND_THRESHOLD_USD_CENTS: i64 = 50_000;
+
+/// Customer tiers from lowest to highest when `--tiers` is not given.
+const DEFAULT_TIERS: [&str; 4] = ["bronze", "silver", "gold", "platinum"];
+
+/// A customer seen under several tiers in a day ranks as the highest of them.
+#[derive(Clone, Copy)]
+struct CustomerDaySpend {
+    net_usd_cents: i64,
+    tier_rank: Option<usize>,
+    vip_threshold_usd_cents: i64,
+}
+
+impl CustomerDaySpend {
+    fn is_vip(&self) -> bool {
+        self.net_usd_cents >= self.vip_threshold_usd_cents
+    }
+}
+
+/// Order weight (`weight_grams * quantity`) from which an order is heavy.
+const HEAVY_ORDER_GRAMS: i64 = 5_000;
+/// Category for events whose product_id has no dim row, with `--mark-missing-dims`.
+const MISSING_PRODUCT_LABEL: &str = "missing_product";
+
+const DEFAULT_SPILL_CHUNK_ROWS: usize = 1_000_000;
+
+impl TransformOptions {
+    /// Sentinel used for unknown categories, tiers and time buckets.
+    fn unknown_label(&self) -> &str {
+        self.unknown_label
+            .as_deref()
+            .unwrap_or(DEFAULT_UNKNOWN_LABEL)
+    }
+
+    fn output_unit_rounding(&self) -> RoundingMode {
+        self.output_unit_rounding.unwrap_or(RoundingMode::Truncate)
+    }
+
+    /// Daily net spend at which a customer of `tier` is VIP.
+    fn vip_threshold(&self, tier: &str) -> i64 {
+        self.vip_thresholds
+            .get(tier)
+            .copied()
+            .unwrap_or(VIP_SPEND_THRESHOLD_USD_CENTS)
+    }
+
+    fn fx_key_column(&self) -> &str {
+        self.fx_key_column.as_deref().unwrap_or("country")
+    }
+
+    fn discount_applies_to_shipping(&self) -> bool {
+        self.discount_applies_to_shipping.unwrap_or(true)
+    }
+
+    fn tax_applies_to_shipping(&self) -> bool {
+        self.tax_applies_to_shipping.unwrap_or(true)
+    }
+
+    fn dim_comment_prefix(&self) -> &str {
+        self.dim_comment_prefix
+            .as_deref()
+            .unwrap_or(DEFAULT_DIM_COMMENT_PREFIX)
+    }
+
+    /// Lowercased unless `--preserve-category-case`.
+    fn stored_category_case(&self, value: &str) -> String {
+        if self.preserve_category_case {
+            value.to_string()
+        } else {
+            value.to_ascii_lowercase()
+        }
+    }
+
+    /// The lowercased category that `--category-margin` and the heavy flags match.
+    fn category_match_key<'a>(&self, category: &'a str) -> Cow<'a, str> {
+        if self.preserve_category_case {
+            Cow::Owned(category.to_ascii_lowercase())
+        } else {
+            Cow::Borrowed(category)
+        }
+    }
+
+    /// The unknown and missing-product labels keep their case.
+    fn output_category_case(&self, value: String) -> String {
+        if self.lowercase_output_category
+            && value != self.unknown_label()
+            && value != MISSING_PRODUCT_LABEL
+        {
+            value.to_ascii_lowercase()
+        } else {
+            value
+        }
+    }
+
+    fn is_known_tier(&self, tier: &str) -> bool {
+        self.tier_rank(tier).is_some()
+    }
+
+    fn tier_rank(&self, tier: &str) -> Option<usize> {
+        match &self.tiers {
+            Some(tiers) => tiers.iter().position(|known| known == tier),
+            None => DEFAULT_TIERS.iter().position(|known| *known == tier),
+        }
+    }
+
+    /// Whether each output row stays within one country.
+    pub fn groups_by_country(&self) -> bool {
+        let country_emitted = self
+            .columns
+            .as_ref()
+            .is_none_or(|columns| columns.contains(&COUNTRY_COLUMN));
+        country_emitted && self.pivot.is_none() && !self.suppressed_other
+    }
+
+    /// `risk_bucket` only exists under `--risk-buckets`.
+    pub fn references_risk_bucket(&self) -> bool {
+        self.columns
+            .as_ref()
+            .is_some_and(|columns| columns.contains(&RISK_BUCKET_COLUMN))
+            || self
+                .pivot
+                .as_ref()
+                .is_some_and(|pivot| pivot.dimension == RISK_BUCKET_COLUMN)
+            || self.dense_domains.contains_key(&RISK_BUCKET_COLUMN)
+    }
+
+    /// A flag that needs every group at once.
+    pub fn spill_conflict(&self) -> Option<&'static str> {
+        if self.partition_by_date {
+            Some("--partition-by")
+        } else if self.max_rows_per_file.is_some() {
+            Some("--max-rows-per-file")
+        } else if self.pivot.is_some() {
+            Some("--pivot")
+        } else if self.min_order_count.is_some() {
+            Some("--min-order-count")
+        } else if !self.dense_domains.is_empty() {
+            Some("--dense")
+        } else if self.shuffle_output_seed.is_some() {
+            Some("--shuffle-output")
+        } else if self.country_subtotals_dir.is_some() {
+            Some("--country-subtotals-dir")
+        } else if self.diff_baseline_path.is_some() {
+            Some("--diff")
+        } else if self.top_products.is_some() {
+            Some("--top-products")
+        } else if self.verify {
+            Some("--verify")
+        } else {
+            None
+        }
+    }
+
+    /// The grouping columns in use, in output order.
+    fn dimension_columns(&self) -> Vec<usize> {
+        (0..DIMENSION_COLUMN_COUNT)
+            .filter(|&idx| idx != RISK_BUCKET_COLUMN || self.risk_buckets.is_some())
+            .collect()
+    }
+
+    fn output_columns(&self) -> Vec<usize> {
+        let dimensions = self.dimension_columns();
+        if self.canonical {
+            return canonical_columns(dimensions);
+        }
+        match (&self.columns, &self.measures) {
+            (Some(columns), _) => columns.clone(),
+            (None, Some(measures)) => dimensions
+                .into_iter()
+                .chain(measures.iter().copied())
+                .collect(),
+            (None, None) => dimensions
+                .into_iter()
+                .chain(DIMENSION_COLUMN_COUNT..OUTPUT_COLUMNS.len())
+                .collect(),
+        }
+    }
+
+    /// Grouping columns left out by `--columns`, which roll up.
+    fn collapsed_dimensions(&self) -> [bool; DIMENSION_COLUMN_COUNT] {
+        let mut collapsed = [false; DIMENSION_COLUMN_COUNT];
+        if let Some(columns) = &self.columns {
+            for (idx, flag) in collapsed.iter_mut().enumerate() {
+                *flag = !columns.contains(&idx);
+            }
+        }
+        collapsed
+    }
+
+    /// A flag that needs a grouping column `--columns` rolls up.
+    pub fn collapsed_dimension_use(&self) -> Option<&'static str> {
+        let collapsed = self.collapsed_dimensions();
+        if self.partition_by_date && collapsed[0] {
+            Some("--partition-by event_date")
+        } else if self.dense_domains.keys().any(|&idx| collapsed[idx]) {
+            Some("--dense")
+        } else {
+            None
+        }
+    }
+}
+
+/// The dimensions, then every standard measure sorted by name.
+fn canonical_columns(dimensions: Vec<usize>) -> Vec<usize> {
+    let mut measures: Vec<usize> = (DIMENSION_COLUMN_COUNT..OUTPUT_COLUMNS.len()).collect();
+    measures.sort_by_key(|&idx| OUTPUT_COLUMNS[idx].name);
+    dimensions.into_iter().chain(measures).collect()
+}
+
+/// Approximate bytes of the three big structures when aggregation ends.
+#[derive(Default)]
+pub struct MemoryEstimate {
+    pub dedup_entries: u64,
+    pub dedup_bytes: u64,
+    pub enriched_rows: u64,
+    pub enriched_bytes: u64,
+    pub aggregated_groups: u64,
+    pub aggregated_bytes: u64,
+    pub peak_bytes: u64,
+}
+
+impl MemoryEstimate {
+    fn measure(
+        dedup: &HashMap<String, EventRecord>,
+        enriched_rows: &[DerivedRecord],
+        aggregated: &HashMap<GroupKey, AggregateRecord>,
+    ) -> Self {
+        // Hash tables hold one control byte per slot next to each entry.
+        let table_bytes = |capacity: usize, entry: usize| (capacity * (entry + 1)) as u64;
+
+        let dedup_bytes = table_bytes(
+            dedup.capacity(),
+            std::mem::size_of::<(String, EventRecord)>(),
+        ) + dedup
+            .iter()
+            .map(|(key, record)| {
+                (key.capacity()
+                    + record.event_ts.capacity()
+                    + record.event_date.capacity()
+                    + record.country.capacity()
+                    + record.customer_tier.capacity()
+                    + record.fx_key.capacity()) as u64
+            })
+            .sum::<u64>();
+
+        let enriched_bytes = std::mem::size_of_val(enriched_rows) as u64
+            + enriched_rows
+                .iter()
+                .map(|row| {
+                    (row.event_date.capacity()
+                        + row.customer_tier.capacity()
+                        + row.category.capacity()
+                        + row.country.capacity()
+                        + row.time_bucket.capacity()
+                        + row.order_size_bucket.capacity()
+                        + row.risk_bucket.capacity()
+                        + row.extra.capacity() * std::mem::size_of::<(String, i64)>()
+                        + row
+                            .extra
+                            .iter()
+                            .map(|(name, _)| name.capacity())
+                            .sum::<usize>()) as u64
+                })
+                .sum::<u64>();
+
+        let aggregated_bytes = table_bytes(
+            aggregated.capacity(),
+            std::mem::size_of::<(GroupKey, AggregateRecord)>(),
+        ) + aggregated
+            .iter()
+            .map(|(key, agg)| {
+                let key_bytes: usize = group_key_dims(key).iter().map(|dim| dim.len()).sum();
+                let extra_bytes: usize = agg
+                    .extra
+                    .keys()
+                    .map(|name| name.capacity() + std::mem::size_of::<(String, i64)>())
+                    .sum();
+                (key_bytes + extra_bytes) as u64
+            })
+            .sum::<u64>();
+
+        MemoryEstimate {
+            dedup_entries: dedup.len() as u64,
+            dedup_bytes,
+            enriched_rows: enriched_rows.len() as u64,
+            enriched_bytes,
+            aggregated_groups: aggregated.len() as u64,
+            aggregated_bytes,
+            peak_bytes: dedup_bytes + enriched_bytes + aggregated_bytes,
+        }
+    }
+}
+
+pub struct TransformSummary {
+    pub raw_rows: i64,
+    pub filtered_rows: i64,
+    pub dedup_rows: i64,
+    /// Deduplicated rows rejected while joining the dimensions.
+    pub enrich_rejected_rows: i64,
+    /// Orders grouped before any group is dropped or rolled up.
+    pub grouped_orders: i64,
+    pub unknown_tier_rows: i64,
+    pub full_discount_rows: i64,
+    pub superseded_rows: i64,
+    /// Same-version, same-timestamp duplicates whose amounts disagreed.
+    pub dedup_conflicts: i64,
+    pub repeated_header_rows: i64,
+    /// Leading data rows passed over by `--skip-rows`.
+    pub skipped_rows: i64,
+    pub invalid_version_rows: i64,
+    pub dedup_rows_by_date: BTreeMap<String, i64>,
+    pub reject_counts: BTreeMap<&'static str, i64>,
+    pub suppressed_groups: i64,
+    pub suppressed_orders: i64,
+    pub negligible_groups: i64,
+    pub dense_groups: i64,
+    /// Every data file written, in write order.
+    pub output_files: Vec<PathBuf>,
+    pub diff: Option<DiffSummary>,
+    pub memory_estimate: MemoryEstimate,
+    pub run_id: Option<String>,
+}
+
+fn count_reject(reject_counts: &mut BTreeMap<&'static str, i64>, reason: &'static str) {
+    *reject_counts.entry(reason).or_insert(0) += 1;
+}
+
+pub fn format_reject_counts(reject_counts: &BTreeMap<&'static str, i64>) -> String {
+    if reject_counts.is_empty() {
+        return "none".to_string();
+    }
+    reject_counts
+        .iter()
+        .map(|(reason, count)| format!("{}:{}", reason, count))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Fails with `ErrorKind::Interrupted`, carrying the counts so far.
+fn check_interrupt(
+    options: &TransformOptions,
+    raw_rows: i64,
+    filtered_rows: i64,
+    reject_counts: &BTreeMap<&'static str, i64>,
+) -> io::Result<()> {
+    match &options.interrupt {
+        Some(flag) if flag.load(Ordering::Relaxed) => Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            format!(
+                "interrupted | raw_rows={} filtered_rows={} rejects={}",
+                raw_rows,
+                filtered_rows,
+                format_reject_counts(reject_counts)
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn parse_i64(value: &str) -> i64 {
+    try_parse_i64(value).unwrap_or(0)
+}
+
+fn try_parse_i64(value: &str) -> Option<i64> {
+    value.trim().parse::<i64>().ok()
+}
+
+fn clamp_i64(value: i64, low: i64, high: i64) -> i64 {
+    if value < low {
+        low
+    } else if value > high {
+        high
+    } else {
+        value
+    }
+}
+
+/// Rounds half away from zero; 0 for a non-positive denominator.
+fn round_div(numerator: i64, denominator: i64) -> i64 {
+    if denominator <= 0 {
+        return 0;
+    }
+    if numerator < 0 {
+        return -((numerator.saturating_neg() + (denominator / 2)) / denominator);
+    }
+    (numerator + (denominator / 2)) / denominator
+}
+
+/// `round_div` of an i128 numerator; the quotient saturates.
+fn round_div_wide(numerator: i128, denominator: i64) -> i64 {
+    if denominator <= 0 {
+        return 0;
+    }
+    let denominator = i128::from(denominator);
+    let magnitude = (numerator.unsigned_abs() + (denominator / 2) as u128) / denominator as u128;
+    let magnitude = i64::try_from(magnitude).unwrap_or(i64::MAX);
+    if numerator < 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+/// Reject reason for a row whose per-order arithmetic overflows i64.
+fn no_overflow(value: Option<i64>) -> Result<i64, &'static str> {
+    value.ok_or("numeric_overflow")
+}
+
+/// Rounds cents half away from zero to a multiple of `unit`.
+fn round_to_unit(cents: i64, unit: i64) -> i64 {
+    round_div(cents, unit) * unit
+}
+
+/// Rounds a ppm FX rate (6 decimal places) half-up to `places` decimals.
+fn round_fx_ppm(fx_to_usd_ppm: i64, places: u32) -> i64 {
+    let unit = 10_i64.pow(6 - places.min(6));
+    round_div(fx_to_usd_ppm, unit) * unit
+}
+
+fn parse_event_hour(event_ts: &str) -> i64 {
+    if event_ts.len() < 13 {
+        return -1;
+    }
+
+    if event_ts.as_bytes()[10] != b'T' {
+        return -1;
+    }
+
+    let hour = parse_i64(&event_ts[11..13]);
+    if (0..=23).contains(&hour) {
+        hour
+    } else {
+        -1
+    }
+}
+
+fn time_bucket_from_hour(hour: i64, unknown_label: &str) -> String {
+    if (0..6).contains(&hour) {
+        "night".to_string()
+    } else if (6..12).contains(&hour) {
+        "morning".to_string()
+    } else if (12..18).contains(&hour) {
+        "afternoon".to_string()
+    } else if (18..24).contains(&hour) {
+        "evening".to_string()
+    } else {
+        unknown_label.to_string()
+    }
+}
+
+fn order_size_bucket(quantity: i64) -> String {
+    if quantity <= 1 {
+        "single".to_string()
+    } else if quantity <= 3 {
+        "small_multi".to_string()
+    } else {
+        "bulk".to_string()
+    }
+}
+
+/// risk_bps below `medium_from_bps` is `low`, below `high_from_bps` `medium`.
+#[derive(Clone, Copy)]
+pub struct RiskBuckets {
+    medium_from_bps: i64,
+    high_from_bps: i64,
+}
+
+impl RiskBuckets {
+    /// Parses `--risk-buckets <medium_from_bps>,<high_from_bps>`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || {
+            format!(
+                "--risk-buckets expects <medium_from_bps>,<high_from_bps> with 0 < medium < high, got {:?}",
+                value
+            )
+        };
+        let (medium, high) = value.split_once(',').ok_or_else(invalid)?;
+        let medium_from_bps: i64 = medium.trim().parse().map_err(|_| invalid())?;
+        let high_from_bps: i64 = high.trim().parse().map_err(|_| invalid())?;
+        if medium_from_bps <= 0 || high_from_bps <= medium_from_bps {
+            return Err(invalid());
+        }
+        Ok(RiskBuckets {
+            medium_from_bps,
+            high_from_bps,
+        })
+    }
+
+    fn bucket(self, risk_bps: i64) -> &'static str {
+        if risk_bps < self.medium_from_bps {
+            "low"
+        } else if risk_bps < self.high_from_bps {
+            "medium"
+        } else {
+            "high"
+        }
+    }
+}
+
+/// Trims an input field; every input reader goes through here.
+pub(crate) fn normalize_field(field: &str) -> &str {
+    field.trim()
+}
+
+fn split_csv_line(line: &str, delimiter: char) -> Vec<&str> {
+    line.trim_end_matches(&['\r', '\n'][..])
+        .split(delimiter)
+        .map(normalize_field)
+        .collect()
+}
+
+/// Grouping and decimal separators of numeric input fields.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum NumberLocale {
+    #[default]
+    Plain,
+    En,
+    De,
+    Fr,
+    Ch,
+}
+
+impl NumberLocale {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "plain" => Ok(NumberLocale::Plain),
+            "en" => Ok(NumberLocale::En),
+            "de" => Ok(NumberLocale::De),
+            "fr" => Ok(NumberLocale::Fr),
+            "ch" => Ok(NumberLocale::Ch),
+            _ => Err(format!(
+                "--number-locale expects plain, en, de, fr or ch, got {:?}",
+                value
+            )),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NumberLocale::Plain => "plain",
+            NumberLocale::En => "en",
+            NumberLocale::De => "de",
+            NumberLocale::Fr => "fr",
+            NumberLocale::Ch => "ch",
+        }
+    }
+
+    fn grouping_separators(self) -> &'static [char] {
+        match self {
+            NumberLocale::Plain => &[],
+            NumberLocale::En => &[','],
+            NumberLocale::De => &['.'],
+            NumberLocale::Fr => &[' ', '\u{a0}', '\u{202f}'],
+            NumberLocale::Ch => &['\''],
+        }
+    }
+
+    fn decimal_separator(self) -> Option<char> {
+        match self {
+            NumberLocale::Plain => None,
+            NumberLocale::En | NumberLocale::Ch => Some('.'),
+            NumberLocale::De | NumberLocale::Fr => Some(','),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct InputFormat {
+    pub delimiter: char,
+    pub number_locale: NumberLocale,
+    pub has_header: bool,
+    /// Symbols stripped from numeric fields under `--lenient-numbers`.
+    pub currency_symbols: Option<String>,
+}
+
+impl Default for InputFormat {
+    fn default() -> Self {
+        InputFormat {
+            delimiter: ',',
+            number_locale: NumberLocale::Plain,
+            has_header: true,
+            currency_symbols: None,
+        }
+    }
+}
+
+impl InputFormat {
+    pub fn validate(&self) -> Result<(), String> {
+        if self
+            .currency_symbols
+            .as_ref()
+            .is_some_and(|symbols| symbols.contains(self.delimiter))
+        {
+            return Err(format!(
+                "--lenient-numbers symbols conflict with field delimiter {:?}",
+                self.delimiter
+            ));
+        }
+        let locale = self.number_locale;
+        if locale.grouping_separators().contains(&self.delimiter)
+            || locale.decimal_separator() == Some(self.delimiter)
+        {
+            return Err(format!(
+                "--number-locale separators conflict with field delimiter {:?}",
+                self.delimiter
+            ));
+        }
+        Ok(())
+    }
+
+    /// This format with its delimiter replaced by a per-input override.
+    pub fn with_delimiter(&self, delimiter: Option<char>) -> Self {
+        InputFormat {
+            delimiter: delimiter.unwrap_or(self.delimiter),
+            ..self.clone()
+        }
+    }
+
+    /// Whether line `idx` of an events or dimension file is its header.
+    fn is_header_line(&self, idx: usize) -> bool {
+        self.has_header && idx == 0
+    }
+
+    fn split_line<'a>(&self, line: &'a str) -> Vec<&'a str> {
+        split_csv_line(line, self.delimiter)
+    }
+
+    fn parse_i64(&self, value: &str) -> i64 {
+        self.try_parse_i64(value).unwrap_or(0)
+    }
+
+    /// `None` for a blank or unparseable field, which `parse_i64` reads as 0.
+    fn try_parse_i64(&self, value: &str) -> Option<i64> {
+        let grouping = self.number_locale.grouping_separators();
+        if let Some(symbols) = &self.currency_symbols {
+            // Plain numbers group thousands with commas.
+            let grouping = if grouping.is_empty() {
+                &[',']
+            } else {
+                grouping
+            };
+            let stripped: String = value
+                .chars()
+                .filter(|ch| !symbols.contains(*ch) && !grouping.contains(ch))
+                .collect();
+            return try_parse_i64(&stripped);
+        }
+        if grouping.is_empty() || !value.contains(grouping) {
+            return try_parse_i64(value);
+        }
+        try_parse_i64(&value.replace(grouping, ""))
+    }
+
+    /// `None` unless the field is a non-negative integer.
+    fn parse_version(&self, value: &str) -> Option<i64> {
+        let grouping = self.number_locale.grouping_separators();
+        let version = if grouping.is_empty() {
+            value.parse::<i64>()
+        } else {
+            value.replace(grouping, "").parse::<i64>()
+        };
+        version.ok().filter(|version| *version >= 0)
+    }
+}
+
+fn is_gzip_path(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("gz"))
+}
+
+fn ensure_not_directory(path: &Path, role: &str) -> io::Result<()> {
+    if std::fs::metadata(path).is_ok_and(|metadata| metadata.is_dir()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} {}: expected a file, got a directory",
+                role,
+                path.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Fails on an unwritable output location before the events are read.
+fn ensure_writable_dir(dir: &Path, role: &str) -> io::Result<()> {
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let probe = dir.join(format!(".process_rust-write-check-{}", std::process::id()));
+    std::fs::create_dir_all(dir)
+        .and_then(|()| File::create(&probe).map(drop))
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!(
+                    "{} {}: directory is not writable: {}",
+                    role,
+                    dir.display(),
+                    err
+                ),
+            )
+        })?;
+    std::fs::remove_file(&probe)
+}
+
+/// Leaves the file itself untouched.
+pub fn ensure_writable_file(path: &Path, role: &str) -> io::Result<()> {
+    ensure_writable_dir(path.parent().unwrap_or(Path::new("")), role)?;
+    if path.exists() {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("{} {}: file is not writable: {}", role, path.display(), err),
+                )
+            })?;
+    }
+    Ok(())
+}
+
+/// Decompresses `.gz` files with the `compression` feature.
+fn open_input(path: &Path, role: &str) -> io::Result<Box<dyn BufRead>> {
+    ensure_not_directory(path, role)?;
+    wrap_input(path, File::open(path)?)
+}
+
+/// The decompression half of `open_input`, over any byte source for `path`.
+fn wrap_input<R: Read + 'static>(path: &Path, file: R) -> io::Result<Box<dyn BufRead>> {
+    if !is_gzip_path(path) {
+        return Ok(Box::new(BufReader::new(file)));
+    }
+
+    #[cfg(feature = "compression")]
+    {
+        Ok(Box::new(BufReader::new(flate2::read::MultiGzDecoder::new(
+            file,
+        ))))
+    }
+    #[cfg(not(feature = "compression"))]
+    {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "{} is gzip-compressed; rebuild with --features compression",
+                path.display()
+            ),
+        ))
+    }
+}
+
+/// Counts the compressed bytes read, for `--progress`.
+struct CountingReader<R> {
+    inner: R,
+    bytes_read: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.bytes_read.set(self.bytes_read.get() + read as u64);
+        Ok(read)
+    }
+}
+
+/// `--progress` on stderr: a percentage when the file size is known.
+struct Progress {
+    bytes_read: Rc<Cell<u64>>,
+    total_bytes: Option<u64>,
+    last_update: Instant,
+    frame: usize,
+}
+
+impl Progress {
+    const INTERVAL: Duration = Duration::from_millis(200);
+
+    fn tick(&mut self, rows: usize) {
+        if !rows.is_multiple_of(4096) || self.last_update.elapsed() < Self::INTERVAL {
+            return;
+        }
+        self.last_update = Instant::now();
+        self.draw(rows);
+    }
+
+    fn draw(&mut self, rows: usize) {
+        match self.total_bytes {
+            Some(total) => {
+                let percent = self.bytes_read.get() as f64 * 100.0 / total as f64;
+                eprint!(
+                    "\rreading events: {:5.1}% ({} rows)",
+                    percent.min(100.0),
+                    rows
+                );
+            }
+            None => {
+                const FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+                eprint!("\rreading events: {} {} rows", FRAMES[self.frame % 4], rows);
+                self.frame += 1;
+            }
+        }
+    }
+
+    fn finish(mut self, rows: usize) {
+        self.draw(rows);
+        eprintln!();
+    }
+}
+
+/// Opens the events file, with a byte counter for `--progress`.
+fn open_events(
+    events_path: &Path,
+    options: &TransformOptions,
+) -> io::Result<(Box<dyn BufRead>, Option<Progress>)> {
+    ensure_not_directory(events_path, "events")?;
+    let file = File::open(events_path)?;
+    if !options.progress {
+        return Ok((wrap_input(events_path, file)?, None));
+    }
+
+    // Pipes and other special files report no usable size.
+    let total_bytes = file
+        .metadata()
+        .ok()
+        .filter(|metadata| metadata.is_file() && metadata.len() > 0)
+        .map(|metadata| metadata.len());
+    let bytes_read = Rc::new(Cell::new(0));
+    let reader = CountingReader {
+        inner: file,
+        bytes_read: Rc::clone(&bytes_read),
+    };
+    let progress = Progress {
+        bytes_read,
+        total_bytes,
+        last_update: Instant::now(),
+        frame: 0,
+    };
+    Ok((wrap_input(events_path, reader)?, Some(progress)))
+}
+
+/// Unlike `BufRead::lines`, also breaks lines at a lone `\r`.
+struct InputLines<R> {
+    reader: R,
+    skip_lf: bool,
+    max_line_bytes: Option<usize>,
+}
+
+fn input_lines<R: BufRead>(reader: R) -> InputLines<R> {
+    InputLines {
+        reader,
+        skip_lf: false,
+        max_line_bytes: None,
+    }
+}
+
+impl<R> InputLines<R> {
+    /// Longer lines are skipped unbuffered and yield `LineTooLong`.
+    fn max_line_bytes(mut self, limit: Option<usize>) -> Self {
+        self.max_line_bytes = limit;
+        self
+    }
+}
+
+/// Error payload for a line over `InputLines::max_line_bytes`.
+#[derive(Debug)]
+struct LineTooLong;
+
+impl fmt::Display for LineTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("line exceeds --max-line-bytes")
+    }
+}
+
+impl std::error::Error for LineTooLong {}
+
+fn is_line_too_long(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|inner| inner.is::<LineTooLong>())
+}
+
+impl<R: BufRead> Iterator for InputLines<R> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = Vec::new();
+        let mut too_long = false;
+        let finish = |line: Vec<u8>, too_long: bool| {
+            if too_long {
+                Err(io::Error::new(io::ErrorKind::InvalidData, LineTooLong))
+            } else {
+                line_to_string(line)
+            }
+        };
+        loop {
+            let buf = match self.reader.fill_buf() {
+                Ok(buf) => buf,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Some(Err(err)),
+            };
+            if buf.is_empty() {
+                return (!line.is_empty() || too_long).then(|| finish(line, too_long));
+            }
+            if self.skip_lf {
+                self.skip_lf = false;
+                if buf[0] == b'\n' {
+                    self.reader.consume(1);
+                    continue;
+                }
+            }
+
+            let (chunk, line_end) = match buf.iter().position(|&b| b == b'\n' || b == b'\r') {
+                Some(pos) => (&buf[..pos], Some(buf[pos])),
+                None => (buf, None),
+            };
+            let consumed = chunk.len() + usize::from(line_end.is_some());
+            if !too_long {
+                line.extend_from_slice(chunk);
+                if self.max_line_bytes.is_some_and(|max| line.len() > max) {
+                    too_long = true;
+                    line = Vec::new();
+                }
+            }
+            self.reader.consume(consumed);
+            if let Some(byte) = line_end {
+                self.skip_lf = byte == b'\r';
+                return Some(finish(line, too_long));
+            }
+        }
+    }
+}
+
+fn line_to_string(line: Vec<u8>) -> io::Result<String> {
+    String::from_utf8(line).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "stream did not contain valid UTF-8",
+        )
+    })
+}
+
+fn parse_product_row(
+    cols: &[&str],
+    format: &InputFormat,
+    options: &TransformOptions,
+) -> Option<(i64, ProductDim)> {
+    if cols.len() < 4 {
+        return None;
+    }
+
+    let product_id = format.parse_i64(cols[0]);
+    if product_id <= 0 {
+        return None;
+    }
+
+    let unknown_label = options.unknown_label();
+    let category_raw = options.stored_category_case(cols[1]);
+    let category = if category_raw.is_empty() {
+        unknown_label.to_string()
+    } else {
+        category_raw
+    };
+
+    let margin_bps = clamp_i64(format.parse_i64(cols[2]), 0, 9500);
+    let weight_grams = clamp_i64(format.parse_i64(cols[3]), 1, 20_000);
+    // Optional fifth column; four-column dims leave every product unknown.
+    let subcategory = match cols.get(4) {
+        Some(value) if !value.is_empty() => options.stored_category_case(value),
+        _ => unknown_label.to_string(),
+    };
+
+    Some((
+        product_id,
+        ProductDim {
+            category,
+            subcategory,
+            margin_bps,
+            weight_grams,
+        },
+    ))
+}
+
+/// Skips the comment and blank lines before a dim's header.
+fn dim_lines(
+    reader: Box<dyn BufRead>,
+    comment_prefix: &str,
+) -> impl Iterator<Item = io::Result<String>> + '_ {
+    input_lines(reader).skip_while(move |line| match line {
+        Ok(line) => {
+            let line = line.trim_start();
+            line.is_empty() || line.starts_with(comment_prefix)
+        }
+        Err(_) => false,
+    })
+}
+
+fn load_product_dim(
+    dim_path: &Path,
+    format: &InputFormat,
+    options: &TransformOptions,
+) -> io::Result<HashMap<i64, ProductDim>> {
+    let reader = open_input(dim_path, "product dim")?;
+
+    let mut product_map = HashMap::new();
+
+    for (idx, line_res) in dim_lines(reader, options.dim_comment_prefix()).enumerate() {
+        let line = line_res?;
+        if format.is_header_line(idx) || line.trim().is_empty() {
+            continue;
+        }
+
+        if let Some((product_id, product)) =
+            parse_product_row(&format.split_line(&line), format, options)
+        {
+            product_map.insert(product_id, product);
+        }
+    }
+
+    Ok(product_map)
+}
+
+fn parse_country_row(cols: &[&str], format: &InputFormat) -> Option<(String, CountryDim)> {
+    if cols.len() < 4 {
+        return None;
+    }
+
+    let country = cols[0].to_ascii_uppercase();
+    if country.is_empty() {
+        return None;
+    }
+
+    let fx_to_usd_ppm = clamp_i64(format.parse_i64(cols[1]), 1, 2_500_000);
+    let risk_bps = clamp_i64(format.parse_i64(cols[2]), 1, 20_000);
+    let tax_bps = clamp_i64(format.parse_i64(cols[3]), 0, 5_000);
+
+    Some((
+        country,
+        CountryDim {
+            fx_to_usd_ppm,
+            risk_bps,
+            tax_bps,
+        },
+    ))
+}
+
+fn load_country_dim(
+    dim_path: &Path,
+    format: &InputFormat,
+    comment_prefix: &str,
+) -> io::Result<HashMap<String, CountryDim>> {
+    let reader = open_input(dim_path, "country dim")?;
+
+    let mut country_map = HashMap::new();
+
+    for (idx, line_res) in dim_lines(reader, comment_prefix).enumerate() {
+        let line = line_res?;
+        if format.is_header_line(idx) || line.trim().is_empty() {
+            continue;
+        }
+
+        if let Some((country, factor)) = parse_country_row(&format.split_line(&line), format) {
+            country_map.insert(country, factor);
+        }
+    }
+
+    Ok(country_map)
+}
+
+/// A `--product-override`; `None` fields keep the dim's values.
+#[derive(Clone)]
+pub struct ProductOverride {
+    product_id: i64,
+    category: Option<String>,
+    subcategory: Option<String>,
+    margin_bps: Option<i64>,
+    weight_grams: Option<i64>,
+    spec: String,
+}
+
+impl ProductOverride {
+    /// Parses `<product_id>:<field>=<value>,...`.
+    pub fn parse(flag: &str, value: &str) -> Result<Self, String> {
+        let (key, fields) = split_override(flag, value, "<product_id>")?;
+        let product_id = key
+            .parse::<i64>()
+            .map_err(|_| format!("{} expects an integer product_id, got {:?}", flag, key))?;
+        let mut parsed = ProductOverride {
+            product_id,
+            category: None,
+            subcategory: None,
+            margin_bps: None,
+            weight_grams: None,
+            spec: value.to_string(),
+        };
+        for (field, field_value) in fields {
+            match field {
+                "category" => parsed.category = Some(field_value.to_string()),
+                "subcategory" => parsed.subcategory = Some(field_value.to_string()),
+                "margin_bps" => {
+                    parsed.margin_bps = Some(override_i64(flag, field, field_value, 0, 9500)?)
+                }
+                "weight_grams" => {
+                    parsed.weight_grams = Some(override_i64(flag, field, field_value, 1, 20_000)?)
+                }
+                _ => {
+                    return Err(format!(
+                    "{} field must be category, subcategory, margin_bps or weight_grams, got {:?}",
+                    flag, field
+                ))
+                }
+            }
+        }
+        Ok(parsed)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.spec
+    }
+}
+
+/// A `--country-override`; `None` fields keep the dim's values.
+#[derive(Clone)]
+pub struct CountryOverride {
+    country: String,
+    fx_to_usd_ppm: Option<i64>,
+    risk_bps: Option<i64>,
+    tax_bps: Option<i64>,
+    spec: String,
+}
+
+impl CountryOverride {
+    /// Parses `<country>:<field>=<value>,...`.
+    pub fn parse(flag: &str, value: &str) -> Result<Self, String> {
+        let (key, fields) = split_override(flag, value, "<country>")?;
+        let mut parsed = CountryOverride {
+            country: key.to_ascii_uppercase(),
+            fx_to_usd_ppm: None,
+            risk_bps: None,
+            tax_bps: None,
+            spec: value.to_string(),
+        };
+        for (field, field_value) in fields {
+            match field {
+                "fx_to_usd_ppm" => {
+                    parsed.fx_to_usd_ppm =
+                        Some(override_i64(flag, field, field_value, 1, 2_500_000)?)
+                }
+                "risk_bps" => {
+                    parsed.risk_bps = Some(override_i64(flag, field, field_value, 1, 20_000)?)
+                }
+                "tax_bps" => {
+                    parsed.tax_bps = Some(override_i64(flag, field, field_value, 0, 5_000)?)
+                }
+                _ => {
+                    return Err(format!(
+                        "{} field must be fx_to_usd_ppm, risk_bps or tax_bps, got {:?}",
+                        flag, field
+                    ))
+                }
+            }
+        }
+        Ok(parsed)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.spec
+    }
+}
+
+/// `(field, value)` pairs of an override, in the order given.
+type OverrideFields<'a> = Vec<(&'a str, &'a str)>;
+
+fn split_override<'a>(
+    flag: &str,
+    value: &'a str,
+    key_name: &str,
+) -> Result<(&'a str, OverrideFields<'a>), String> {
+    let malformed = || {
+        format!(
+            "{} expects {}:<field>=<value>[,<field>=<value>...], got {:?}",
+            flag, key_name, value
+        )
+    };
+    let (key, fields) = value.split_once(':').ok_or_else(malformed)?;
+    let key = key.trim();
+    if key.is_empty() {
+        return Err(malformed());
+    }
+    let fields = fields
+        .split(',')
+        .map(|pair| match pair.split_once('=') {
+            Some((field, field_value))
+                if !field.trim().is_empty() && !field_value.trim().is_empty() =>
+            {
+                Ok((field.trim(), field_value.trim()))
+            }
+            _ => Err(malformed()),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((key, fields))
+}
+
+fn override_i64(flag: &str, field: &str, value: &str, low: i64, high: i64) -> Result<i64, String> {
+    match value.parse::<i64>() {
+        Ok(parsed) if (low..=high).contains(&parsed) => Ok(parsed),
+        _ => Err(format!(
+            "{} {} expects an integer between {} and {}, got {:?}",
+            flag, field, low, high, value
+        )),
+    }
+}
+
+/// A key the dim lacks starts from the dim-miss defaults.
+fn apply_dim_overrides(
+    product_map: &mut HashMap<i64, ProductDim>,
+    country_map: &mut HashMap<String, CountryDim>,
+    options: &TransformOptions,
+) {
+    for patch in &options.product_overrides {
+        let product = product_map
+            .entry(patch.product_id)
+            .or_insert_with(|| ProductDim::missing(options.unknown_label()));
+        if let Some(category) = &patch.category {
+            product.category = options.stored_category_case(category);
+        }
+        if let Some(subcategory) = &patch.subcategory {
+            product.subcategory = options.stored_category_case(subcategory);
+        }
+        if let Some(margin_bps) = patch.margin_bps {
+            product.margin_bps = margin_bps;
+        }
+        if let Some(weight_grams) = patch.weight_grams {
+            product.weight_grams = weight_grams;
+        }
+    }
+    for patch in &options.country_overrides {
+        let country = country_map
+            .entry(patch.country.clone())
+            .or_insert_with(CountryDim::missing);
+        if let Some(fx_to_usd_ppm) = patch.fx_to_usd_ppm {
+            country.fx_to_usd_ppm = fx_to_usd_ppm;
+        }
+        if let Some(risk_bps) = patch.risk_bps {
+            country.risk_bps = risk_bps;
+        }
+        if let Some(tax_bps) = patch.tax_bps {
+            country.tax_bps = tax_bps;
+        }
+    }
+}
+
+fn load_dimensions(
+    product_dim_path: &Path,
+    country_dim_path: &Path,
+    options: &TransformOptions,
+) -> io::Result<(HashMap<i64, ProductDim>, HashMap<String, CountryDim>)> {
+    let product_format = &options
+        .input_format
+        .with_delimiter(options.product_delimiter);
+    let country_format = &options
+        .input_format
+        .with_delimiter(options.country_delimiter);
+
+    let (mut product_map, mut country_map) = load_dimension_maps(
+        product_dim_path,
+        country_dim_path,
+        product_format,
+        country_format,
+        options,
+    )?;
+    apply_dim_overrides(&mut product_map, &mut country_map, options);
+    Ok((product_map, country_map))
+}
+
+fn load_dimension_maps(
+    product_dim_path: &Path,
+    country_dim_path: &Path,
+    product_format: &InputFormat,
+    country_format: &InputFormat,
+    options: &TransformOptions,
+) -> io::Result<(HashMap<i64, ProductDim>, HashMap<String, CountryDim>)> {
+    // With a SQLite source the dimension arguments name tables, not files.
+    #[cfg(feature = "sqlite")]
+    if let Some(source) = &options.sqlite_dims {
+        return Ok((
+            sqlite_dims::load_product_dim(
+                source,
+                &product_dim_path.to_string_lossy(),
+                product_format,
+                options,
+            )?,
+            sqlite_dims::load_country_dim(
+                source,
+                &country_dim_path.to_string_lossy(),
+                country_format,
+            )?,
+        ));
+    }
+
+    let product_map = if missing_dim_allowed(product_dim_path, "product dim", options) {
+        HashMap::new()
+    } else {
+        load_product_dim(product_dim_path, product_format, options)?
+    };
+    let country_map = if missing_dim_allowed(country_dim_path, "country dim", options) {
+        HashMap::new()
+    } else {
+        load_country_dim(
+            country_dim_path,
+            country_format,
+            options.dim_comment_prefix(),
+        )?
+    };
+    Ok((product_map, country_map))
+}
+
+/// `--allow-missing-dims` loads a non-existent dim as an empty map, with a warning.
+fn missing_dim_allowed(dim_path: &Path, role: &str, options: &TransformOptions) -> bool {
+    if !options.allow_missing_dims || dim_path.exists() {
+        return false;
+    }
+    eprintln!(
+        "WARNING: {} {} does not exist; --allow-missing-dims continues with an empty {} and default values",
+        role,
+        dim_path.display(),
+        role
+    );
+    true
+}
+
+fn output_header(columns: &[(String, &OutputColumn)]) -> String {
+    columns
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for ch in value.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn schema_path_for(output_path: &Path) -> PathBuf {
+    output_path.with_extension("schema.json")
+}
+
+// Each entry is the emitted column name plus the column it takes its type and unit from.
+fn write_schema(schema_path: &Path, columns: &[(String, &OutputColumn)]) -> io::Result<()> {
+    let schema_file = File::create(schema_path)?;
+    let mut writer = BufWriter::new(schema_file);
+
+    writeln!(writer, "{{")?;
+    writeln!(writer, "  \"columns\": [")?;
+    for (pos, (name, column)) in columns.iter().enumerate() {
+        let unit = column.unit.map_or_else(|| "null".to_string(), json_string);
+        let separator = if pos + 1 < columns.len() { "," } else { "" };
+        writeln!(
+            writer,
+            "    {{\"name\": {}, \"type\": {}, \"unit\": {}}}{}",
+            json_string(name),
+            json_string(column.kind.as_str()),
+            unit,
+            separator
+        )?;
+    }
+    writeln!(writer, "  ]")?;
+    writeln!(writer, "}}")?;
+
+    writer.flush()
+}
+
+fn diff_path_for(output_path: &Path) -> PathBuf {
+    output_path.with_extension("diff.csv")
+}
+
+/// Group-level changes between a baseline aggregate and this run's output.
+#[derive(Default)]
+pub struct DiffSummary {
+    pub added_groups: i64,
+    pub removed_groups: i64,
+    pub changed_groups: i64,
+}
+
+type DiffTable = BTreeMap<Vec<String>, Vec<i64>>;
+
+/// Keyed by the baseline's dimension columns.
+fn load_diff_baseline(
+    baseline_path: &Path,
+    columns: &[(String, &OutputColumn)],
+) -> io::Result<DiffTable> {
+    let reader = open_input(baseline_path, "diff baseline")?;
+    let mut table = DiffTable::new();
+
+    for (idx, line_res) in input_lines(reader).enumerate() {
+        let line = line_res?;
+        if idx == 0 {
+            if line != output_header(columns) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{} header does not match this run's output columns",
+                        baseline_path.display()
+                    ),
+                ));
+            }
+            continue;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(',').collect();
+        if fields.len() != columns.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} line {} has {} fields, expected {}",
+                    baseline_path.display(),
+                    idx + 1,
+                    fields.len(),
+                    columns.len()
+                ),
+            ));
+        }
+        let mut key = Vec::new();
+        let mut measures = Vec::new();
+        for (field, (name, column)) in fields.iter().zip(columns) {
+            match column.kind {
+                ColumnKind::String => key.push(field.to_string()),
+                ColumnKind::Int64 => measures.push(field.trim().parse::<i64>().map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "{} line {}: {} is not an integer: {:?}",
+                            baseline_path.display(),
+                            idx + 1,
+                            name,
+                            field
+                        ),
+                    )
+                })?),
+            }
+        }
+        table.insert(key, measures);
+    }
+
+    Ok(table)
+}
+
+/// Follows each event_id's version in file order for `--audit-versions`.
+struct VersionAudit {
+    writer: BufWriter<File>,
+    last_versions: HashMap<String, i64>,
+}
+
+impl VersionAudit {
+    fn create(path: &Path) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "line,event_id,previous_version,event_version,issue")?;
+        Ok(VersionAudit {
+            writer,
+            last_versions: HashMap::new(),
+        })
+    }
+
+    fn observe(&mut self, line: usize, event_id: &str, version: i64) -> io::Result<()> {
+        let Some(previous) = self.last_versions.insert(event_id.to_string(), version) else {
+            return Ok(());
+        };
+        let issue = if version < previous {
+            "decrease"
+        } else if version > previous.saturating_add(1) {
+            "gap"
+        } else {
+            return Ok(());
+        };
+        writeln!(
+            self.writer,
+            "{},{},{},{},{}",
+            line, event_id, previous, version, issue
+        )
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Writes `--customer-day-out`, sorted by date then customer.
+fn write_customer_day_spend(
+    path: &Path,
+    customer_day_spend: &HashMap<(String, i64, String), CustomerDaySpend>,
+    vip_key: VipKey,
+) -> io::Result<()> {
+    let by_country = matches!(vip_key, VipKey::DateCustomerCountry);
+    let mut spend: Vec<_> = customer_day_spend.iter().collect();
+    spend.sort_unstable_by(|a, b| a.0.cmp(b.0));
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    if by_country {
+        writeln!(
+            writer,
+            "event_date,customer_id,country,total_net_usd_cents,is_vip"
+        )?;
+    } else {
+        writeln!(writer, "event_date,customer_id,total_net_usd_cents,is_vip")?;
+    }
+    for ((event_date, customer_id, country), day) in spend {
+        let total = day.net_usd_cents;
+        let is_vip = u8::from(day.is_vip());
+        if by_country {
+            writeln!(
+                writer,
+                "{},{},{},{},{}",
+                event_date, customer_id, country, total, is_vip
+            )?;
+        } else {
+            writeln!(
+                writer,
+                "{},{},{},{}",
+                event_date, customer_id, total, is_vip
+            )?;
+        }
+    }
+    writer.flush()
+}
+
+fn top_products_path_for(output_path: &Path) -> PathBuf {
+    output_path.with_extension("top_products.csv")
+}
+
+/// Products by net revenue for `--top-products`; ties go to the lower id.
+fn rank_top_products(
+    enriched_rows: &[DerivedRecord],
+    limit: usize,
+) -> io::Result<Vec<(i64, (i64, i64))>> {
+    let mut by_product: HashMap<i64, (i64, i64)> = HashMap::new();
+    for row in enriched_rows {
+        let (net, orders) = by_product.entry(row.product_id).or_insert((0, 0));
+        add_total(net, row.net_usd_cents, "total_net_usd_cents")?;
+        *orders += 1;
+    }
+
+    let mut ranked: Vec<_> = by_product.into_iter().collect();
+    ranked.sort_unstable_by(|a, b| b.1 .0.cmp(&a.1 .0).then(a.0.cmp(&b.0)));
+    ranked.truncate(limit);
+    Ok(ranked)
+}
+
+fn write_top_products(path: &Path, ranked: &[(i64, (i64, i64))]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "product_id,total_net_usd_cents,order_count")?;
+    for (product_id, (net, orders)) in ranked {
+        writeln!(writer, "{},{},{}", product_id, net, orders)?;
+    }
+    writer.flush()
+}
+
+fn diff_overflow() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "a --diff delta overflowed i64")
+}
+
+/// Writes a row per added, removed or changed group; unchanged groups are left out.
+fn write_diff(
+    baseline_path: &Path,
+    diff_path: &Path,
+    columns: &[(String, &OutputColumn)],
+    rows: &[(GroupKey, AggregateRecord)],
+    selected: &[usize],
+    extra_names: &[String],
+) -> io::Result<DiffSummary> {
+    let mut baseline = load_diff_baseline(baseline_path, columns)?;
+
+    let mut current = DiffTable::new();
+    for (key, agg) in rows {
+        let values = output_values(key, agg);
+        let mut dims = Vec::new();
+        let mut measures = Vec::new();
+        for &idx in selected {
+            match values[idx] {
+                CellValue::Text(value) => dims.push(value.to_string()),
+                CellValue::Int(value) => measures.push(value),
+            }
+        }
+        measures.extend(extra_names.iter().map(|name| extra_value(agg, name)));
+        current.insert(dims, measures);
+    }
+
+    let mut diff_rows: BTreeMap<Vec<String>, (&'static str, Vec<i64>)> = BTreeMap::new();
+    let mut summary = DiffSummary::default();
+    for (key, measures) in current {
+        match baseline.remove(&key) {
+            None => {
+                summary.added_groups += 1;
+                diff_rows.insert(key, ("added", measures));
+            }
+            Some(old) if old != measures => {
+                summary.changed_groups += 1;
+                let deltas = measures
+                    .iter()
+                    .zip(&old)
+                    .map(|(new, old)| new.checked_sub(*old).ok_or_else(diff_overflow))
+                    .collect::<io::Result<_>>()?;
+                diff_rows.insert(key, ("changed", deltas));
+            }
+            Some(_) => {}
+        }
+    }
+    for (key, old) in baseline {
+        summary.removed_groups += 1;
+        let deltas = old
+            .iter()
+            .map(|value| value.checked_neg().ok_or_else(diff_overflow))
+            .collect::<io::Result<_>>()?;
+        diff_rows.insert(key, ("removed", deltas));
+    }
+
+    let mut writer = BufWriter::new(File::create(diff_path)?);
+    let mut header = vec!["change".to_string()];
+    header.extend(
+        columns
+            .iter()
+            .filter(|(_, column)| matches!(column.kind, ColumnKind::String))
+            .map(|(name, _)| name.clone()),
+    );
+    header.extend(
+        columns
+            .iter()
+            .filter(|(_, column)| matches!(column.kind, ColumnKind::Int64))
+            .map(|(name, _)| format!("{}_delta", name)),
+    );
+    writeln!(writer, "{}", header.join(","))?;
+    for (key, (change, deltas)) in &diff_rows {
+        let deltas: Vec<String> = deltas.iter().map(i64::to_string).collect();
+        let mut fields = vec![*change];
+        fields.extend(key.iter().map(String::as_str));
+        fields.extend(deltas.iter().map(String::as_str));
+        writeln!(writer, "{}", fields.join(","))?;
+    }
+    writer.flush()?;
+
+    Ok(summary)
+}
+
+fn json_opt<T: fmt::Display>(value: Option<T>) -> String {
+    value.map_or_else(|| "null".to_string(), |value| value.to_string())
+}
+
+fn json_path(path: &Option<PathBuf>) -> String {
+    path.as_ref().map_or_else(
+        || "null".to_string(),
+        |path| json_string(&path.display().to_string()),
+    )
+}
+
+fn json_strings<'a>(values: impl IntoIterator<Item = &'a str>) -> String {
+    let values: Vec<String> = values.into_iter().map(json_string).collect();
+    format!("[{}]", values.join(", "))
+}
+
+fn json_delimiter(delimiter: Option<char>) -> String {
+    delimiter.map_or_else(|| "null".to_string(), |ch| json_string(&ch.to_string()))
+}
+
+/// Every resolved setting as `(key, JSON value)`, for `--print-config`.
+pub fn effective_config(options: &TransformOptions) -> Vec<(&'static str, String)> {
+    let column_names =
+        |columns: &[usize]| json_strings(columns.iter().map(|&idx| OUTPUT_COLUMNS[idx].name));
+    let category_margins: BTreeMap<&String, &i64> = options.category_margins.iter().collect();
+    let category_margins: Vec<String> = category_margins
+        .iter()
+        .map(|(category, bps)| format!("{}: {}", json_string(category), bps))
+        .collect();
+    let vip_thresholds: BTreeMap<&String, &i64> = options.vip_thresholds.iter().collect();
+    let vip_thresholds: Vec<String> = vip_thresholds
+        .iter()
+        .map(|(tier, cents)| format!("{}: {}", json_string(tier), cents))
+        .collect();
+    let heavy_grams_by_category: BTreeMap<&String, &i64> =
+        options.heavy_grams_by_category.iter().collect();
+    let heavy_grams_by_category: Vec<String> = heavy_grams_by_category
+        .iter()
+        .map(|(category, grams)| format!("{}: {}", json_string(category), grams))
+        .collect();
+    let usd_rounding_units: BTreeMap<&String, &i64> = options.usd_rounding_units.iter().collect();
+    let usd_rounding_units: Vec<String> = usd_rounding_units
+        .iter()
+        .map(|(country, unit)| format!("{}: {}", json_string(country), unit))
+        .collect();
+    let dense_domains: Vec<String> = options
+        .dense_domains
+        .iter()
+        .map(|(&idx, values)| {
+            format!(
+                "{}: {}",
+                json_string(OUTPUT_COLUMNS[idx].name),
+                json_strings(values.iter().map(String::as_str))
+            )
+        })
+        .collect();
+    let tiers = match &options.tiers {
+        Some(tiers) => json_strings(tiers.iter().map(String::as_str)),
+        None => json_strings(DEFAULT_TIERS),
+    };
+    let format = &options.input_format;
+
+    let mut config = Vec::new();
+    config.extend([
+        ("events_format", json_string(options.events_format.as_str())),
+        ("delimiter", json_string(&format.delimiter.to_string())),
+        ("events_delimiter", json_delimiter(options.events_delimiter)),
+        (
+            "product_delimiter",
+            json_delimiter(options.product_delimiter),
+        ),
+        (
+            "country_delimiter",
+            json_delimiter(options.country_delimiter),
+        ),
+        ("number_locale", json_string(format.number_locale.as_str())),
+        ("has_header", format.has_header.to_string()),
+        (
+            "currency_symbols",
+            format
+                .currency_symbols
+                .as_deref()
+                .map_or_else(|| "null".to_string(), json_string),
+        ),
+        (
+            "timestamp_layout",
+            json_string(options.timestamp_layout.as_str()),
+        ),
+        ("date_from", json_string(options.date_from.as_str())),
+        (
+            "date_ts_mismatch",
+            json_string(options.date_ts_mismatch.as_str()),
+        ),
+        (
+            "category_level",
+            json_string(options.category_level.as_str()),
+        ),
+        ("unknown_label", json_string(options.unknown_label())),
+        (
+            "preserve_category_case",
+            options.preserve_category_case.to_string(),
+        ),
+        (
+            "lowercase_output_category",
+            options.lowercase_output_category.to_string(),
+        ),
+        (
+            "dim_comment_prefix",
+            json_string(options.dim_comment_prefix()),
+        ),
+        ("tiers", tiers),
+        (
+            "invalid_version",
+            json_string(options.invalid_version.as_str()),
+        ),
+        (
+            "numeric_missing",
+            json_string(options.numeric_missing.as_str()),
+        ),
+        ("dedup_keep", json_string(options.dedup_keep.as_str())),
+        (
+            "dedup_conflict",
+            json_string(options.dedup_conflict.as_str()),
+        ),
+        ("rounding", json_string(options.rounding.as_str())),
+        ("fx_rounding", json_string(options.fx_rounding.as_str())),
+        ("vip_key", json_string(options.vip_key.as_str())),
+        ("profit_floor", json_string(options.profit_floor.as_str())),
+        ("risk_mode", json_string(options.risk_mode.as_str())),
+        ("max_amount_cents", json_opt(options.max_amount_cents)),
+        ("max_quantity", json_opt(options.max_quantity)),
+        ("min_shipping_cents", options.min_shipping_cents.to_string()),
+        (
+            "reject_full_discount",
+            options.reject_full_discount.to_string(),
+        ),
+        (
+            "max_unknown_tier_rate",
+            json_opt(options.max_unknown_tier_rate),
+        ),
+        (
+            "require_country_dim",
+            options.require_country_dim.to_string(),
+        ),
+        ("fx_key_column", json_string(options.fx_key_column())),
+        (
+            "discount_applies_to_shipping",
+            options.discount_applies_to_shipping().to_string(),
+        ),
+        (
+            "tax_applies_to_shipping",
+            options.tax_applies_to_shipping().to_string(),
+        ),
+        ("allow_missing_dims", options.allow_missing_dims.to_string()),
+        ("mark_missing_dims", options.mark_missing_dims.to_string()),
+        ("gross_local", options.gross_local.to_string()),
+        (
+            "quantity_bins",
+            options
+                .quantity_bins
+                .as_ref()
+                .map_or_else(|| "null".to_string(), |bins| json_string(&bins.as_spec())),
+        ),
+        ("effective_tax", options.effective_tax.to_string()),
+        ("fail_on_empty", options.fail_on_empty.to_string()),
+        ("max_line_bytes", json_opt(options.max_line_bytes)),
+        ("skip_rows", options.skip_rows.to_string()),
+        ("fx_round_places", json_opt(options.fx_round_places)),
+        (
+            "category_margins",
+            format!("{{{}}}", category_margins.join(", ")),
+        ),
+        (
+            "usd_rounding_units",
+            format!("{{{}}}", usd_rounding_units.join(", ")),
+        ),
+        (
+            "product_overrides",
+            json_strings(
+                options
+                    .product_overrides
+                    .iter()
+                    .map(ProductOverride::as_str),
+            ),
+        ),
+        (
+            "country_overrides",
+            json_strings(
+                options
+                    .country_overrides
+                    .iter()
+                    .map(CountryOverride::as_str),
+            ),
+        ),
+        (
+            "heavy_categories",
+            json_strings(options.heavy_categories.iter().map(String::as_str)),
+        ),
+        (
+            "vip_thresholds",
+            format!("{{{}}}", vip_thresholds.join(", ")),
+        ),
+        (
+            "heavy_grams_by_category",
+            format!("{{{}}}", heavy_grams_by_category.join(", ")),
+        ),
+        ("derivers", options.derivers.len().to_string()),
+        (
+            "country_aliases_path",
+            json_path(&options.country_aliases_path),
+        ),
+        ("dated_fx_path", json_path(&options.dated_fx_path)),
+        (
+            "customer_tier_dim_path",
+            json_path(&options.customer_tier_dim_path),
+        ),
+        (
+            "include_products_path",
+            json_path(&options.include_products_path),
+        ),
+        (
+            "exclude_products_path",
+            json_path(&options.exclude_products_path),
+        ),
+        (
+            "exclude_customers_path",
+            json_path(&options.exclude_customers_path),
+        ),
+        (
+            "superseded_out_path",
+            json_path(&options.superseded_out_path),
+        ),
+        (
+            "customer_day_out_path",
+            json_path(&options.customer_day_out_path),
+        ),
+        (
+            "run_id",
+            options
+                .run_id
+                .as_deref()
+                .map_or_else(|| "null".to_string(), json_string),
+        ),
+        ("run_id_column", options.run_id_column.to_string()),
+        ("atomic_output", options.atomic_output.to_string()),
+        ("checksum_output", options.checksum_output.to_string()),
+        (
+            "audit_versions_path",
+            json_path(&options.audit_versions_path),
+        ),
+        ("top_products", json_opt(options.top_products)),
+        ("diff_baseline_path", json_path(&options.diff_baseline_path)),
+        (
+            "country_subtotals_dir",
+            json_path(&options.country_subtotals_dir),
+        ),
+        ("min_order_count", json_opt(options.min_order_count)),
+        ("suppressed_other", options.suppressed_other.to_string()),
+        ("min_net_usd_cents", json_opt(options.min_net_usd_cents)),
+        ("dense_domains", format!("{{{}}}", dense_domains.join(", "))),
+        (
+            "risk_buckets",
+            options.risk_buckets.map_or_else(
+                || "null".to_string(),
+                |buckets| {
+                    format!(
+                        "{{\"medium_from_bps\": {}, \"high_from_bps\": {}}}",
+                        buckets.medium_from_bps, buckets.high_from_bps
+                    )
+                },
+            ),
+        ),
+        ("output_columns", column_names(&options.output_columns())),
+        (
+            "pivot",
+            options.pivot.as_ref().map_or_else(
+                || "null".to_string(),
+                |pivot| {
+                    format!(
+                        "{{\"dimension\": {}, \"measure\": {}}}",
+                        json_string(OUTPUT_COLUMNS[pivot.dimension].name),
+                        json_string(OUTPUT_COLUMNS[pivot.measure].name)
+                    )
+                },
+            ),
+        ),
+        (
+            "output_format",
+            json_string(options.output_format.extension()),
+        ),
+        (
+            "parquet_compression",
+            json_string(options.parquet_compression.as_str()),
+        ),
+        (
+            "output_number_format",
+            json_string(options.output_number_format.as_str()),
+        ),
+        ("output_unit", json_string(options.output_unit.as_str())),
+        (
+            "output_unit_rounding",
+            json_string(options.output_unit_rounding().as_str()),
+        ),
+        ("partition_by_date", options.partition_by_date.to_string()),
+        ("max_rows_per_file", json_opt(options.max_rows_per_file)),
+        ("spill_dir", json_path(&options.spill_dir)),
+        (
+            "spill_chunk_rows",
+            options
+                .spill_chunk_rows
+                .unwrap_or(DEFAULT_SPILL_CHUNK_ROWS)
+                .to_string(),
+        ),
+        ("emit_schema", options.emit_schema.to_string()),
+        ("verify", options.verify.to_string()),
+        ("canonical", options.canonical.to_string()),
+        ("shuffle_output_seed", json_opt(options.shuffle_output_seed)),
+        (
+            "explain_event_id",
+            options
+                .explain_event_id
+                .as_deref()
+                .map_or_else(|| "null".to_string(), json_string),
+        ),
+        ("progress", options.progress.to_string()),
+    ]);
+    #[cfg(feature = "sqlite")]
+    config.push((
+        "sqlite_dims",
+        options.sqlite_dims.as_ref().map_or_else(
+            || "null".to_string(),
+            |source| {
+                format!(
+                    "{{\"db_path\": {}, \"product_columns\": {}, \"country_columns\": {}}}",
+                    json_string(&source.db_path.display().to_string()),
+                    json_strings(source.product_columns.iter().map(String::as_str)),
+                    json_strings(source.country_columns.iter().map(String::as_str))
+                )
+            },
+        ),
+    ));
+    config
+}
+
+/// Writes the run summary as JSON, with the per-date dedup counts.
+pub fn write_metrics(
+    metrics_path: &Path,
+    summary: &TransformSummary,
+    elapsed_sec: f64,
+    rows_per_sec: f64,
+) -> io::Result<()> {
+    let metrics_file = File::create(metrics_path)?;
+    let mut writer = BufWriter::new(metrics_file);
+
+    writeln!(writer, "{{")?;
+    writeln!(
+        writer,
+        "  \"run_id\": {},",
+        summary
+            .run_id
+            .as_deref()
+            .map_or_else(|| "null".to_string(), json_string)
+    )?;
+    writeln!(writer, "  \"raw_rows\": {},", summary.raw_rows)?;
+    writeln!(writer, "  \"filtered_rows\": {},", summary.filtered_rows)?;
+    writeln!(writer, "  \"dedup_rows\": {},", summary.dedup_rows)?;
+    writeln!(
+        writer,
+        "  \"enrich_rejected_rows\": {},",
+        summary.enrich_rejected_rows
+    )?;
+    writeln!(writer, "  \"grouped_orders\": {},", summary.grouped_orders)?;
+    writeln!(
+        writer,
+        "  \"superseded_rows\": {},",
+        summary.superseded_rows
+    )?;
+    writeln!(
+        writer,
+        "  \"dedup_conflicts\": {},",
+        summary.dedup_conflicts
+    )?;
+    writeln!(
+        writer,
+        "  \"repeated_header_rows\": {},",
+        summary.repeated_header_rows
+    )?;
+    writeln!(writer, "  \"skipped_rows\": {},", summary.skipped_rows)?;
+    writeln!(
+        writer,
+        "  \"invalid_version_rows\": {},",
+        summary.invalid_version_rows
+    )?;
+    writeln!(
+        writer,
+        "  \"unknown_tier_rows\": {},",
+        summary.unknown_tier_rows
+    )?;
+    writeln!(
+        writer,
+        "  \"full_discount_rows\": {},",
+        summary.full_discount_rows
+    )?;
+    writeln!(
+        writer,
+        "  \"suppressed_groups\": {},",
+        summary.suppressed_groups
+    )?;
+    writeln!(
+        writer,
+        "  \"suppressed_orders\": {},",
+        summary.suppressed_orders
+    )?;
+    writeln!(
+        writer,
+        "  \"negligible_groups\": {},",
+        summary.negligible_groups
+    )?;
+    writeln!(writer, "  \"dense_groups\": {},", summary.dense_groups)?;
+    let rejects: Vec<String> = summary
+        .reject_counts
+        .iter()
+        .map(|(reason, count)| format!("{}: {}", json_string(reason), count))
+        .collect();
+    writeln!(writer, "  \"rejects\": {{{}}},", rejects.join(", "))?;
+    let by_date: Vec<String> = summary
+        .dedup_rows_by_date
+        .iter()
+        .map(|(date, count)| format!("{}: {}", json_string(date), count))
+        .collect();
+    writeln!(
+        writer,
+        "  \"dedup_rows_by_date\": {{{}}},",
+        by_date.join(", ")
+    )?;
+    let output_files: Vec<String> = summary
+        .output_files
+        .iter()
+        .map(|path| json_string(&path.display().to_string()))
+        .collect();
+    writeln!(writer, "  \"output_files\": [{}],", output_files.join(", "))?;
+    let memory = &summary.memory_estimate;
+    writeln!(writer, "  \"memory_estimate\": {{")?;
+    writeln!(writer, "    \"dedup_entries\": {},", memory.dedup_entries)?;
+    writeln!(writer, "    \"dedup_bytes\": {},", memory.dedup_bytes)?;
+    writeln!(writer, "    \"enriched_rows\": {},", memory.enriched_rows)?;
+    writeln!(writer, "    \"enriched_bytes\": {},", memory.enriched_bytes)?;
+    writeln!(
+        writer,
+        "    \"aggregated_groups\": {},",
+        memory.aggregated_groups
+    )?;
+    writeln!(
+        writer,
+        "    \"aggregated_bytes\": {},",
+        memory.aggregated_bytes
+    )?;
+    writeln!(writer, "    \"peak_bytes\": {}", memory.peak_bytes)?;
+    writeln!(writer, "  }},")?;
+    writeln!(writer, "  \"elapsed_sec\": {:.3},", elapsed_sec)?;
+    writeln!(writer, "  \"rows_per_sec\": {:.0}", rows_per_sec)?;
+    writeln!(writer, "}}")?;
+
+    writer.flush()
+}
+
+fn load_dated_fx(
+    fx_path: &Path,
+    format: &InputFormat,
+) -> io::Result<HashMap<String, HashMap<String, i64>>> {
+    let reader = open_input(fx_path, "dated fx")?;
+
+    let mut fx_map: HashMap<String, HashMap<String, i64>> = HashMap::new();
+
+    for (idx, line_res) in input_lines(reader).enumerate() {
+        let line = line_res?;
+        if format.is_header_line(idx) || line.trim().is_empty() {
+            continue;
+        }
+
+        let cols = format.split_line(&line);
+        if cols.len() < 3 {
+            continue;
+        }
+
+        let country = cols[0].to_ascii_uppercase();
+        let fx_date = cols[1];
+        if country.is_empty() || fx_date.is_empty() {
+            continue;
+        }
+
+        let fx_to_usd_ppm = clamp_i64(format.parse_i64(cols[2]), 1, 2_500_000);
+        fx_map
+            .entry(country)
+            .or_default()
+            .insert(fx_date.to_string(), fx_to_usd_ppm);
+    }
+
+    Ok(fx_map)
+}
+
+/// Tiers are normalized like event tiers.
+fn load_customer_tier_dim(
+    tier_path: &Path,
+    format: &InputFormat,
+    options: &TransformOptions,
+) -> io::Result<HashMap<i64, String>> {
+    let reader = open_input(tier_path, "customer tier dim")?;
+
+    let mut tier_map = HashMap::new();
+
+    for (idx, line_res) in input_lines(reader).enumerate() {
+        let line = line_res?;
+        if format.is_header_line(idx) || line.trim().is_empty() {
+            continue;
+        }
+
+        let cols = format.split_line(&line);
+        if cols.len() < 2 {
+            continue;
+        }
+
+        let customer_id = format.parse_i64(cols[0]);
+        if customer_id <= 0 {
+            continue;
+        }
+
+        let tier = cols[1].to_ascii_lowercase();
+        let tier = if options.is_known_tier(&tier) {
+            tier
+        } else {
+            options.unknown_label().to_string()
+        };
+        tier_map.insert(customer_id, tier);
+    }
+
+    Ok(tier_map)
+}
+
+/// Lines that are not a positive id, such as a header, are skipped.
+fn load_id_list(path: &Path, format: &InputFormat, role: &str) -> io::Result<HashSet<i64>> {
+    let reader = open_input(path, role)?;
+
+    let mut ids = HashSet::new();
+    for line_res in input_lines(reader) {
+        let line = line_res?;
+        let cols = format.split_line(&line);
+        let Some(first) = cols.first() else {
+            continue;
+        };
+        if let Ok(id) = first.parse::<i64>() {
+            if id > 0 {
+                ids.insert(id);
+            }
+        }
+    }
+
+    Ok(ids)
+}
+
+fn load_country_aliases(
+    alias_path: &Path,
+    format: &InputFormat,
+) -> io::Result<HashMap<String, String>> {
+    let reader = open_input(alias_path, "country aliases")?;
+
+    let mut alias_map = HashMap::new();
+
+    for (idx, line_res) in input_lines(reader).enumerate() {
+        let line = line_res?;
+        if format.is_header_line(idx) || line.trim().is_empty() {
+            continue;
+        }
+
+        let cols = format.split_line(&line);
+        if cols.len() < 2 {
+            continue;
+        }
+
+        let alias = cols[0].to_ascii_uppercase();
+        let canonical = cols[1].to_ascii_uppercase();
+        if alias.is_empty() || canonical.is_empty() {
+            continue;
+        }
+
+        alias_map.insert(alias, canonical);
+    }
+
+    Ok(alias_map)
+}
+
+/// Output file format for the aggregate rows.
+#[derive(Clone, Copy, Default)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    Json,
+    Parquet,
+}
+
+impl OutputFormat {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "parquet" if cfg!(feature = "parquet") => Ok(OutputFormat::Parquet),
+            "parquet" => {
+                Err("--output-format parquet requires building with --features parquet".to_string())
+            }
+            _ => Err(format!(
+                "--output-format expects csv, json or parquet, got {:?}",
+                value
+            )),
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Json => "json",
+            OutputFormat::Parquet => "parquet",
+        }
+    }
+}
+
+/// Codec for every column of Parquet output.
+#[derive(Clone, Copy, Default)]
+pub enum ParquetCompression {
+    None,
+    #[default]
+    Snappy,
+    Zstd,
+    Gzip,
+}
+
+impl ParquetCompression {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "none" => Ok(ParquetCompression::None),
+            "snappy" => Ok(ParquetCompression::Snappy),
+            "zstd" => Ok(ParquetCompression::Zstd),
+            "gzip" => Ok(ParquetCompression::Gzip),
+            _ => Err(format!(
+                "--parquet-compression expects none, snappy, zstd or gzip, got {:?}",
+                value
+            )),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ParquetCompression::None => "none",
+            ParquetCompression::Snappy => "snappy",
+            ParquetCompression::Zstd => "zstd",
+            ParquetCompression::Gzip => "gzip",
+        }
+    }
+}
+
+/// Destination for the final aggregate rows; implemented once per output format.
+trait RowSink {
+    fn write_header(&mut self, columns: &[(String, &'static OutputColumn)]) -> io::Result<()>;
+    fn write_row(&mut self, values: &[CellValue<'_>]) -> io::Result<()>;
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+/// How `usd_cents` columns are rendered in CSV output.
+#[derive(Clone, Copy, Default)]
+pub enum OutputNumberFormat {
+    #[default]
+    Cents,
+    Dollars,
+    GroupedDollars,
+}
+
+impl OutputNumberFormat {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "cents" => Ok(OutputNumberFormat::Cents),
+            "dollars" => Ok(OutputNumberFormat::Dollars),
+            "grouped-dollars" => Ok(OutputNumberFormat::GroupedDollars),
+            _ => Err(format!(
+                "--output-number-format expects cents, dollars or grouped-dollars, got {:?}",
+                value
+            )),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OutputNumberFormat::Cents => "cents",
+            OutputNumberFormat::Dollars => "dollars",
+            OutputNumberFormat::GroupedDollars => "grouped-dollars",
+        }
+    }
+
+    fn format_usd_cents(self, cents: i64) -> String {
+        let sign = if cents < 0 { "-" } else { "" };
+        let whole = (cents.unsigned_abs() / 100).to_string();
+        let fraction = cents.unsigned_abs() % 100;
+        match self {
+            OutputNumberFormat::Cents => cents.to_string(),
+            OutputNumberFormat::Dollars => format!("{}{}.{:02}", sign, whole, fraction),
+            OutputNumberFormat::GroupedDollars => {
+                let mut grouped = String::with_capacity(whole.len() + whole.len() / 3);
+                for (pos, digit) in whole.chars().enumerate() {
+                    if pos > 0 && (whole.len() - pos).is_multiple_of(3) {
+                        grouped.push(',');
+                    }
+                    grouped.push(digit);
+                }
+                // Grouping commas would split the field, so quote it.
+                if grouped.contains(',') {
+                    format!("\"{}{}.{:02}\"", sign, grouped, fraction)
+                } else {
+                    format!("{}{}.{:02}", sign, grouped, fraction)
+                }
+            }
+        }
+    }
+}
+
+/// `.gz` paths are gzip-compressed with the `compression` feature.
+enum OutputWriter {
+    Plain(BufWriter<File>),
+    #[cfg(feature = "compression")]
+    // Boxed: the encoder's state dwarfs a plain BufWriter.
+    Gzip(Box<flate2::write::GzEncoder<BufWriter<File>>>),
+}
+
+impl OutputWriter {
+    fn create(path: &Path) -> io::Result<Self> {
+        let writer = BufWriter::new(File::create(path)?);
+        if !is_gzip_path(path) {
+            return Ok(OutputWriter::Plain(writer));
+        }
+
+        #[cfg(feature = "compression")]
+        {
+            Ok(OutputWriter::Gzip(Box::new(flate2::write::GzEncoder::new(
+                writer,
+                flate2::Compression::default(),
+            ))))
+        }
+        #[cfg(not(feature = "compression"))]
+        {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "{} needs gzip output; rebuild with --features compression",
+                    path.display()
+                ),
+            ))
+        }
+    }
+
+    /// Flushes buffered output and, for gzip, writes the stream trailer.
+    fn finish(self) -> io::Result<()> {
+        match self {
+            OutputWriter::Plain(mut writer) => writer.flush(),
+            #[cfg(feature = "compression")]
+            OutputWriter::Gzip(encoder) => encoder.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputWriter::Plain(writer) => writer.write(buf),
+            #[cfg(feature = "compression")]
+            OutputWriter::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputWriter::Plain(writer) => writer.flush(),
+            #[cfg(feature = "compression")]
+            OutputWriter::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+struct CsvSink {
+    writer: OutputWriter,
+    number_format: OutputNumberFormat,
+    money_columns: Vec<bool>,
+}
+
+impl RowSink for CsvSink {
+    fn write_header(&mut self, columns: &[(String, &'static OutputColumn)]) -> io::Result<()> {
+        self.money_columns = columns
+            .iter()
+            .map(|(_, column)| column.unit == Some("usd_cents"))
+            .collect();
+        writeln!(self.writer, "{}", output_header(columns))
+    }
+
+    fn write_row(&mut self, values: &[CellValue<'_>]) -> io::Result<()> {
+        for (pos, value) in values.iter().enumerate() {
+            if pos > 0 {
+                self.writer.write_all(b",")?;
+            }
+            match value {
+                CellValue::Int(cents)
+                    if !matches!(self.number_format, OutputNumberFormat::Cents)
+                        && self.money_columns[pos] =>
+                {
+                    self.writer
+                        .write_all(self.number_format.format_usd_cents(*cents).as_bytes())?
+                }
+                _ => write!(self.writer, "{}", value)?,
+            }
+        }
+        self.writer.write_all(b"\n")
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        self.writer.finish()
+    }
+}
+
+/// One JSON array of objects; an empty result is `[]`.
+struct JsonSink {
+    writer: OutputWriter,
+    keys: Vec<String>,
+    rows_written: usize,
+}
+
+impl RowSink for JsonSink {
+    fn write_header(&mut self, columns: &[(String, &'static OutputColumn)]) -> io::Result<()> {
+        self.keys = columns.iter().map(|(name, _)| json_string(name)).collect();
+        self.writer.write_all(b"[")
+    }
+
+    fn write_row(&mut self, values: &[CellValue<'_>]) -> io::Result<()> {
+        let separator: &[u8] = if self.rows_written == 0 {
+            b"\n"
+        } else {
+            b",\n"
+        };
+        self.writer.write_all(separator)?;
+        self.writer.write_all(b"{")?;
+        for (pos, (key, value)) in self.keys.iter().zip(values).enumerate() {
+            if pos > 0 {
+                self.writer.write_all(b", ")?;
+            }
+            match value {
+                CellValue::Text(text) => write!(self.writer, "{}: {}", key, json_string(text))?,
+                CellValue::Int(number) => write!(self.writer, "{}: {}", key, number)?,
+            }
+        }
+        self.rows_written += 1;
+        self.writer.write_all(b"}")
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        if self.rows_written > 0 {
+            self.writer.write_all(b"\n")?;
+        }
+        self.writer.write_all(b"]\n")?;
+        self.writer.finish()
+    }
+}
+
+fn open_sink(output_path: &Path, options: &TransformOptions) -> io::Result<Box<dyn RowSink>> {
+    let mut sink = if options.atomic_output || options.checksum_output {
+        let written_path = if options.atomic_output {
+            atomic_temp_path(output_path)
+        } else {
+            output_path.to_path_buf()
+        };
+        Box::new(CommitSink {
+            inner: open_format_sink(&written_path, options)?,
+            written_path,
+            path: output_path.to_path_buf(),
+            checksum: options.checksum_output,
+        })
+    } else {
+        open_format_sink(output_path, options)?
+    };
+    if options.run_id_column {
+        sink = Box::new(RunIdSink {
+            inner: sink,
+            run_id: options.run_id.clone().unwrap_or_default(),
+        });
+    }
+    Ok(match options.output_unit {
+        OutputUnit::Cents => sink,
+        OutputUnit::Dollars => Box::new(WholeDollarSink {
+            inner: sink,
+            rounding: options.output_unit_rounding(),
+            usd_columns: Vec::new(),
+        }),
+    })
+}
+
+/// Keeps the name's suffix, so `.gz` still selects gzip.
+fn atomic_temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".tmp.{}", name))
+}
+
+/// Writes `<name>.sha256` in `sha256sum` format, over the bytes as stored.
+#[cfg(feature = "checksum")]
+fn write_checksum(written_path: &Path, name_path: &Path) -> io::Result<()> {
+    use sha2::{Digest, Sha256};
+
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(written_path)?, &mut hasher)?;
+    let name = name_path.file_name().unwrap_or_default().to_string_lossy();
+    std::fs::write(
+        name_path.with_file_name(format!("{}.sha256", name)),
+        format!("{:x}  {}\n", hasher.finalize(), name),
+    )
+}
+
+#[cfg(not(feature = "checksum"))]
+fn write_checksum(_written_path: &Path, name_path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "{} needs a checksum; rebuild with --features checksum",
+            name_path.display()
+        ),
+    ))
+}
+
+/// Writes the checksum, then renames the file into place, once it is complete.
+struct CommitSink {
+    inner: Box<dyn RowSink>,
+    written_path: PathBuf,
+    path: PathBuf,
+    checksum: bool,
+}
+
+impl RowSink for CommitSink {
+    fn write_header(&mut self, columns: &[(String, &'static OutputColumn)]) -> io::Result<()> {
+        self.inner.write_header(columns)
+    }
+
+    fn write_row(&mut self, values: &[CellValue<'_>]) -> io::Result<()> {
+        self.inner.write_row(values)
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        let CommitSink {
+            inner,
+            written_path,
+            path,
+            checksum,
+        } = *self;
+        let renamed = written_path != path;
+        let committed = inner.finish().and_then(|()| {
+            if checksum {
+                write_checksum(&written_path, &path)?;
+            }
+            if renamed {
+                std::fs::rename(&written_path, &path)?;
+            }
+            Ok(())
+        });
+        if committed.is_err() && renamed {
+            let _ = std::fs::remove_file(&written_path);
+        }
+        committed
+    }
+}
+
+static RUN_ID_COLUMN: OutputColumn = dimension_column("run_id");
+
+/// Appends the run's id to every row, for `--run-id-column`.
+struct RunIdSink {
+    inner: Box<dyn RowSink>,
+    run_id: String,
+}
+
+impl RowSink for RunIdSink {
+    fn write_header(&mut self, columns: &[(String, &'static OutputColumn)]) -> io::Result<()> {
+        let mut columns = columns.to_vec();
+        columns.push((RUN_ID_COLUMN.name.to_string(), &RUN_ID_COLUMN));
+        self.inner.write_header(&columns)
+    }
+
+    fn write_row(&mut self, values: &[CellValue<'_>]) -> io::Result<()> {
+        let mut values = values.to_vec();
+        values.push(CellValue::Text(&self.run_id));
+        self.inner.write_row(&values)
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        self.inner.finish()
+    }
+}
+
+/// Scales the usd_cents columns to whole dollars.
+struct WholeDollarSink {
+    inner: Box<dyn RowSink>,
+    rounding: RoundingMode,
+    usd_columns: Vec<bool>,
+}
+
+impl RowSink for WholeDollarSink {
+    fn write_header(&mut self, columns: &[(String, &'static OutputColumn)]) -> io::Result<()> {
+        self.usd_columns = columns
+            .iter()
+            .map(|(_, column)| column.unit == Some("usd_cents"))
+            .collect();
+        self.inner
+            .write_header(&OutputUnit::Dollars.columns(columns))
+    }
+
+    fn write_row(&mut self, values: &[CellValue<'_>]) -> io::Result<()> {
+        let scaled: Vec<CellValue<'_>> = values
+            .iter()
+            .zip(&self.usd_columns)
+            .map(|(value, &usd)| match value {
+                CellValue::Int(cents) if usd => CellValue::Int(self.rounding.divide(*cents, 100)),
+                _ => *value,
+            })
+            .collect();
+        self.inner.write_row(&scaled)
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        self.inner.finish()
+    }
+}
+
+fn open_format_sink(
+    output_path: &Path,
+    options: &TransformOptions,
+) -> io::Result<Box<dyn RowSink>> {
+    match options.output_format {
+        OutputFormat::Csv => Ok(Box::new(CsvSink {
+            writer: OutputWriter::create(output_path)?,
+            number_format: options.output_number_format,
+            money_columns: Vec::new(),
+        })),
+        OutputFormat::Json => Ok(Box::new(JsonSink {
+            writer: OutputWriter::create(output_path)?,
+            keys: Vec::new(),
+            rows_written: 0,
+        })),
+        OutputFormat::Parquet if is_gzip_path(output_path) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "gzip output applies to csv and json; parquet compresses internally",
+        )),
+        #[cfg(feature = "parquet")]
+        OutputFormat::Parquet => Ok(Box::new(parquet_output::ParquetSink::create(
+            output_path,
+            options.parquet_compression,
+        )?)),
+        #[cfg(not(feature = "parquet"))]
+        OutputFormat::Parquet => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "parquet output requires building with --features parquet",
+        )),
+    }
+}
+
+fn selected_output_columns(selected: &[usize]) -> Vec<(String, &'static OutputColumn)> {
+    selected
+        .iter()
+        .map(|&idx| (OUTPUT_COLUMNS[idx].name.to_string(), &OUTPUT_COLUMNS[idx]))
+        .collect()
+}
+
+/// Deriver measures follow the selected standard columns, in name order.
+fn write_rows<'r>(
+    sink: &mut dyn RowSink,
+    rows: impl IntoIterator<Item = &'r (GroupKey, AggregateRecord)>,
+    selected: &[usize],
+    extra_names: &[String],
+) -> io::Result<Vec<(String, &'static OutputColumn)>> {
+    let mut columns = selected_output_columns(selected);
+    columns.extend(
+        extra_names
+            .iter()
+            .map(|name| (name.clone(), extra_output_column(name))),
+    );
+    sink.write_header(&columns)?;
+
+    let mut row_values = Vec::with_capacity(columns.len());
+    for (key, agg) in rows {
+        write_group_row(sink, &mut row_values, key, agg, selected, extra_names)?;
+    }
+
+    Ok(columns)
+}
+
+fn write_group_row<'k>(
+    sink: &mut dyn RowSink,
+    row_values: &mut Vec<CellValue<'k>>,
+    key: &'k GroupKey,
+    agg: &AggregateRecord,
+    selected: &[usize],
+    extra_names: &[String],
+) -> io::Result<()> {
+    let values = output_values(key, agg);
+    row_values.clear();
+    row_values.extend(selected.iter().map(|&idx| values[idx]));
+    row_values.extend(
+        extra_names
+            .iter()
+            .map(|name| CellValue::Int(extra_value(agg, name))),
+    );
+    sink.write_row(row_values)
+}
+
+/// One file per country, in the main output's order; blank is the unknown label.
+fn write_country_subtotals(
+    output_dir: &Path,
+    rows: &[(GroupKey, AggregateRecord)],
+    options: &TransformOptions,
+    extra_names: &[String],
+    extension: &str,
+    output_files: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    let mut by_country: BTreeMap<&str, Vec<&(GroupKey, AggregateRecord)>> = BTreeMap::new();
+    for row in rows {
+        by_country.entry(&row.0 .3).or_default().push(row);
+    }
+
+    std::fs::create_dir_all(output_dir)?;
+    let selected = options.output_columns();
+    for (country, country_rows) in by_country {
+        // Events with a blank country would otherwise land in a hidden `.csv`.
+        let file_name = match country {
+            "" => options.unknown_label().to_string(),
+            _ => country.replace(['/', '\\'], "_"),
+        };
+        let path = output_dir.join(format!("{}.{}", file_name, extension));
+        let mut sink = open_sink(&path, options)?;
+        write_rows(sink.as_mut(), country_rows, &selected, extra_names)?;
+        sink.finish()?;
+        output_files.push(path);
+    }
+
+    Ok(())
+}
+
+/// The format's extension, plus `.gz` when the output is gzipped.
+fn data_file_extension(output_path: &Path, options: &TransformOptions) -> String {
+    let extension = options.output_format.extension();
+    if is_gzip_path(output_path) {
+        format!("{}.gz", extension)
+    } else {
+        extension.to_string()
+    }
+}
+
+/// An empty result still gets one header-only part.
+fn write_rotated_parts(
+    output_dir: &Path,
+    rows: &[(GroupKey, AggregateRecord)],
+    options: &TransformOptions,
+    extra_names: &[String],
+    max_rows: usize,
+    extension: &str,
+    output_files: &mut Vec<PathBuf>,
+) -> io::Result<Vec<(String, &'static OutputColumn)>> {
+    let selected = options.output_columns();
+    let mut columns = selected_output_columns(&selected);
+    std::fs::create_dir_all(output_dir)?;
+    let parts: Vec<_> = if rows.is_empty() {
+        vec![rows]
+    } else {
+        rows.chunks(max_rows).collect()
+    };
+    for (part, part_rows) in parts.into_iter().enumerate() {
+        let part_path = output_dir.join(format!("part-{:05}.{}", part, extension));
+        let mut sink = open_sink(&part_path, options)?;
+        columns = write_rows(sink.as_mut(), part_rows, &selected, extra_names)?;
+        sink.finish()?;
+        output_files.push(part_path);
+    }
+
+    Ok(columns)
+}
+
+/// Seeded Fisher-Yates over splitmix64, so a seed gives the same order everywhere.
+fn shuffle_rows<T>(rows: &mut [T], seed: u64) {
+    let mut state = seed;
+    let mut next = || {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    };
+    for idx in (1..rows.len()).rev() {
+        let swap_with = (next() % (idx as u64 + 1)) as usize;
+        rows.swap(idx, swap_with);
+    }
+}
+
+/// Relies on `rows` being sorted by event_date first.
+fn write_partitioned_by_date(
+    output_dir: &Path,
+    rows: &[(GroupKey, AggregateRecord)],
+    options: &TransformOptions,
+    extra_names: &[String],
+    output_files: &mut Vec<PathBuf>,
+) -> io::Result<Vec<(String, &'static OutputColumn)>> {
+    let extension = data_file_extension(output_dir, options);
+    let selected = options.output_columns();
+    let mut columns = selected_output_columns(&selected);
+    for partition in rows.chunk_by(|a, b| a.0 .0 == b.0 .0) {
+        let partition_dir = output_dir.join(format!("event_date={}", partition[0].0 .0));
+        std::fs::create_dir_all(&partition_dir)?;
+        if let Some(max_rows) = options.max_rows_per_file {
+            columns = write_rotated_parts(
+                &partition_dir,
+                partition,
+                options,
+                extra_names,
+                max_rows,
+                &extension,
+                output_files,
+            )?;
+            continue;
+        }
+        let part_path = partition_dir.join(format!("part.{}", extension));
+        let mut sink = open_sink(&part_path, options)?;
+        columns = write_rows(sink.as_mut(), partition, &selected, extra_names)?;
+        sink.finish()?;
+        output_files.push(part_path);
+    }
+
+    Ok(columns)
+}
+
+fn write_pivoted_rows(
+    sink: &mut dyn RowSink,
+    rows: &[(GroupKey, AggregateRecord)],
+    pivot: &PivotSpec,
+    dimensions: &[usize],
+) -> io::Result<Vec<(String, &'static OutputColumn)>> {
+    let mut pivot_values: BTreeSet<&str> = BTreeSet::new();
+    let mut pivoted: BTreeMap<Vec<&str>, HashMap<&str, i64>> = BTreeMap::new();
+
+    for (key, agg) in rows {
+        let dims = group_key_dims(key);
+        let row_key: Vec<&str> = dimensions
+            .iter()
+            .filter(|&&idx| idx != pivot.dimension)
+            .map(|&idx| dims[idx])
+            .collect();
+        let measure = match output_values(key, agg)[pivot.measure] {
+            CellValue::Int(value) => value,
+            CellValue::Text(_) => 0,
+        };
+
+        pivot_values.insert(dims[pivot.dimension]);
+        pivoted
+            .entry(row_key)
+            .or_default()
+            .insert(dims[pivot.dimension], measure);
+    }
+
+    let measure_column = &OUTPUT_COLUMNS[pivot.measure];
+    let mut columns: Vec<(String, &'static OutputColumn)> = dimensions
+        .iter()
+        .filter(|&&idx| idx != pivot.dimension)
+        .map(|&idx| (OUTPUT_COLUMNS[idx].name.to_string(), &OUTPUT_COLUMNS[idx]))
+        .collect();
+    for value in &pivot_values {
+        columns.push((format!("{}_{}", measure_column.name, value), measure_column));
+    }
+    sink.write_header(&columns)?;
+
+    let mut row_values = Vec::with_capacity(columns.len());
+    for (row_key, cells) in &pivoted {
+        row_values.clear();
+        row_values.extend(row_key.iter().map(|dim| CellValue::Text(dim)));
+        row_values.extend(
+            pivot_values
+                .iter()
+                .map(|value| CellValue::Int(cells.get(value).copied().unwrap_or(0))),
+        );
+        sink.write_row(&row_values)?;
+    }
+
+    Ok(columns)
+}
+
+/// Read-only, so records can be enriched in parallel.
+struct EnrichContext<'a> {
+    product_map: &'a HashMap<i64, ProductDim>,
+    country_map: &'a HashMap<String, CountryDim>,
+    dated_fx: &'a HashMap<String, HashMap<String, i64>>,
+    customer_tiers: &'a HashMap<i64, String>,
+    options: &'a TransformOptions,
+}
+
+impl EnrichContext<'_> {
+    /// Returns the reject reason when the event is dropped.
+    fn enrich(&self, record: &EventRecord) -> Result<DerivedRecord, &'static str> {
+        self.enrich_traced(record, |_, _| {})
+    }
+
+    /// Reports each intermediate value to `trace`, for `--explain`.
+    fn enrich_traced(
+        &self,
+        record: &EventRecord,
+        mut trace: impl FnMut(&'static str, &dyn fmt::Display),
+    ) -> Result<DerivedRecord, &'static str> {
+        let options = self.options;
+        let product = self
+            .product_map
+            .get(&record.product_id)
+            .cloned()
+            .unwrap_or_else(|| ProductDim {
+                // Same numeric defaults either way; only the label differs.
+                category: if options.mark_missing_dims {
+                    MISSING_PRODUCT_LABEL.to_string()
+                } else {
+                    options.unknown_label().to_string()
+                },
+                ..ProductDim::missing(options.unknown_label())
+            });
+
+        let country_factor = match self.country_map.get(&record.country) {
+            Some(factor) => factor.clone(),
+            None if options.require_country_dim => return Err("country_dim_miss"),
+            None => CountryDim::missing(),
+        };
+
+        trace("category", &product.category);
+        trace("margin_bps", &product.margin_bps);
+        trace("weight_grams", &product.weight_grams);
+        trace("country", &record.country);
+        trace("tax_bps", &country_factor.tax_bps);
+        trace("risk_bps", &country_factor.risk_bps);
+
+        let goods_local_cents = no_overflow(record.amount_cents.checked_mul(record.quantity))?;
+        let gross_local_cents = no_overflow(goods_local_cents.checked_add(record.shipping_cents))?;
+        trace("gross_local_cents", &gross_local_cents);
+        let discount_base_local_cents = if options.discount_applies_to_shipping() {
+            gross_local_cents
+        } else {
+            goods_local_cents
+        };
+        let discount_local_cents = options.rounding.divide(
+            no_overflow(discount_base_local_cents.checked_mul(record.discount_bps))?,
+            10_000,
+        );
+        trace("discount_local_cents", &discount_local_cents);
+        let discounted_local_cents = std::cmp::max(gross_local_cents - discount_local_cents, 0);
+        let taxable_local_cents = if options.tax_applies_to_shipping() {
+            discounted_local_cents
+        } else {
+            // Shipping's share of a discount that covered it is rounded on its
+            // own; the goods keep the rest, so the two shares sum to the discount.
+            let shipping_discount_local_cents = if options.discount_applies_to_shipping() {
+                options.rounding.divide(
+                    no_overflow(record.shipping_cents.checked_mul(record.discount_bps))?,
+                    10_000,
+                )
+            } else {
+                0
+            };
+            std::cmp::max(
+                goods_local_cents - (discount_local_cents - shipping_discount_local_cents),
+                0,
+            )
+        };
+        trace("taxable_local_cents", &taxable_local_cents);
+        let tax_local_cents = options.rounding.divide(
+            no_overflow(taxable_local_cents.checked_mul(country_factor.tax_bps))?,
+            10_000,
+        );
+        trace("tax_local_cents", &tax_local_cents);
+        let net_local_cents = no_overflow(discounted_local_cents.checked_add(tax_local_cents))?;
+        trace("net_local_cents", &net_local_cents);
+
+        // Tax and risk always follow the country; only the rate can be keyed
+        // by another column. A blank key falls back to the country.
+        let (fx_key, fx_factor) = if record.fx_key.is_empty() {
+            (&record.country, country_factor.fx_to_usd_ppm)
+        } else {
+            let fx_factor = match self.country_map.get(&record.fx_key) {
+                Some(factor) => factor.fx_to_usd_ppm,
+                None if options.require_country_dim => return Err("fx_key_dim_miss"),
+                None => CountryDim::missing().fx_to_usd_ppm,
+            };
+            trace("fx_key", &record.fx_key);
+            (&record.fx_key, fx_factor)
+        };
+        let fx_to_usd_ppm = self
+            .dated_fx
+            .get(fx_key)
+            .and_then(|by_date| by_date.get(&record.event_date))
+            .copied()
+            .unwrap_or(fx_factor);
+        let fx_to_usd_ppm = match options.fx_round_places {
+            Some(places) => round_fx_ppm(fx_to_usd_ppm, places),
+            None => fx_to_usd_ppm,
+        };
+        trace("fx_to_usd_ppm", &fx_to_usd_ppm);
+        let mut net_usd_cents = options.fx_rounding.divide(
+            no_overflow(net_local_cents.checked_mul(fx_to_usd_ppm))?,
+            1_000_000,
+        );
+        trace("net_usd_cents", &net_usd_cents);
+        // The checked product above bounds net_usd_cents by i64::MAX / 1e6,
+        // so the bps products below (at most 20_000x) cannot overflow.
+        let category_key = options.category_match_key(&product.category);
+        let margin_bps = options
+            .category_margins
+            .get(category_key.as_ref())
+            .copied()
+            .unwrap_or(product.margin_bps);
+        trace("applied_margin_bps", &margin_bps);
+        let cost_usd_cents = options
+            .rounding
+            .divide(net_usd_cents * (10_000 - margin_bps), 10_000);
+        trace("cost_usd_cents", &cost_usd_cents);
+        let mut profit_usd_cents = options.profit_floor.apply(net_usd_cents - cost_usd_cents);
+        trace("profit_usd_cents", &profit_usd_cents);
+        let mut risk_adjusted_usd_cents =
+            options
+                .risk_mode
+                .apply(net_usd_cents, country_factor.risk_bps, options.rounding);
+        trace("risk_adjusted_usd_cents", &risk_adjusted_usd_cents);
+        // Reporting granularity only: every value above is computed in cents.
+        if let Some(&unit) = options.usd_rounding_units.get(&record.country) {
+            trace("usd_rounding_unit", &unit);
+            net_usd_cents = round_to_unit(net_usd_cents, unit);
+            profit_usd_cents = round_to_unit(profit_usd_cents, unit);
+            risk_adjusted_usd_cents = round_to_unit(risk_adjusted_usd_cents, unit);
+            trace("rounded_net_usd_cents", &net_usd_cents);
+            trace("rounded_profit_usd_cents", &profit_usd_cents);
+            trace("rounded_risk_adjusted_usd_cents", &risk_adjusted_usd_cents);
+        }
+
+        let hour = parse_event_hour(&record.event_ts);
+        let time_bucket = time_bucket_from_hour(hour, options.unknown_label());
+        trace("time_bucket", &time_bucket);
+        let size_bucket = order_size_bucket(record.quantity);
+        trace("order_size_bucket", &size_bucket);
+        let risk_bucket = match options.risk_buckets {
+            Some(buckets) => {
+                let risk_bucket = buckets.bucket(country_factor.risk_bps);
+                trace("risk_bucket", &risk_bucket);
+                risk_bucket.to_string()
+            }
+            None => String::new(),
+        };
+        let heavy_threshold_grams = options
+            .heavy_grams_by_category
+            .get(category_key.as_ref())
+            .copied()
+            .unwrap_or(HEAVY_ORDER_GRAMS);
+        trace("heavy_threshold_grams", &heavy_threshold_grams);
+        // Saturates rather than wrapping for pathological quantities.
+        let heavy_item_order = if product.weight_grams.saturating_mul(record.quantity)
+            >= heavy_threshold_grams
+            || options.heavy_categories.contains(category_key.as_ref())
+        {
+            1
+        } else {
+            0
+        };
+        trace("heavy_item_order", &heavy_item_order);
+
+        let mut derived = DerivedRecord {
+            event_date: record.event_date.clone(),
+            customer_id: record.customer_id,
+            product_id: record.product_id,
+            customer_tier: self
+                .customer_tiers
+                .get(&record.customer_id)
+                .unwrap_or(&record.customer_tier)
+                .clone(),
+            category: match options.category_level {
+                CategoryLevel::Category => options.output_category_case(product.category),
+                CategoryLevel::Subcategory => format!(
+                    "{}/{}",
+                    options.output_category_case(product.category),
+                    options.output_category_case(product.subcategory)
+                ),
+            },
+            country: record.country.clone(),
+            time_bucket,
+            order_size_bucket: size_bucket,
+            risk_bucket,
+            quantity: record.quantity,
+            net_usd_cents,
+            profit_usd_cents,
+            risk_adjusted_usd_cents,
+            heavy_item_order,
+            taxable_local_cents,
+            tax_local_cents,
+            extra: Vec::new(),
+        };
+        if !options.derivers.is_empty() {
+            derived.extra = options
+                .derivers
+                .iter()
+                .flat_map(|deriver| deriver.derive_extra(record, &derived))
+                .collect();
+        }
+        if options.gross_local {
+            derived.extra.push((
+                GROSS_LOCAL_COLUMN_NAME.to_string(),
+                record.amount_cents.saturating_mul(record.quantity),
+            ));
+        }
+        if let Some(bins) = &options.quantity_bins {
+            let bin = bins.column_for(record.quantity);
+            trace("quantity_bin", &bin);
+            derived.extra.push((bin.to_string(), 1));
+        }
+        Ok(derived)
+    }
+}
+
+/// Prints the `--explain` calculation chain for one deduplicated event to stderr.
+fn explain_event(context: &EnrichContext<'_>, event_id: &str, record: Option<&EventRecord>) {
+    let Some(record) = record else {
+        eprintln!(
+            "explain event_id={}: not found after filtering and deduplication",
+            event_id
+        );
+        return;
+    };
+
+    eprintln!("explain event_id={}", event_id);
+    eprintln!("  event_version = {}", record.event_version);
+    eprintln!("  event_ts = {}", record.event_ts);
+    eprintln!("  amount_cents = {}", record.amount_cents);
+    eprintln!("  quantity = {}", record.quantity);
+    eprintln!("  discount_bps = {}", record.discount_bps);
+    eprintln!("  shipping_cents = {}", record.shipping_cents);
+    let outcome = context.enrich_traced(record, |name, value| {
+        eprintln!("  {} = {}", name, value);
+    });
+    if let Err(reason) = outcome {
+        eprintln!("  rejected = {}", reason);
+    }
+}
+
+/// Cross-checks the grouped totals against the per-order rows.
+fn verify_aggregation(
+    enriched_rows: &[DerivedRecord],
+    aggregated: &HashMap<GroupKey, AggregateRecord>,
+) -> io::Result<()> {
+    let row_net: i128 = enriched_rows
+        .iter()
+        .map(|row| i128::from(row.net_usd_cents))
+        .sum();
+    let group_net: i128 = aggregated
+        .values()
+        .map(|agg| i128::from(agg.total_net_usd_cents))
+        .sum();
+    let group_orders: i64 = aggregated.values().map(|agg| agg.order_count).sum();
+
+    if row_net != group_net || group_orders != enriched_rows.len() as i64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "verification failed: rows net_usd_cents={} orders={} but groups total_net_usd_cents={} order_count={}",
+                row_net,
+                enriched_rows.len(),
+                group_net,
+                group_orders
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that every filtered row is accounted for exactly once.
+fn reconcile_row_counts(
+    filtered_rows: i64,
+    superseded_rows: i64,
+    conflict_rejected_rows: i64,
+    dedup_rows: i64,
+    enrich_rejected_rows: i64,
+    grouped_orders: i64,
+) -> io::Result<()> {
+    if filtered_rows != dedup_rows + superseded_rows + conflict_rejected_rows
+        || dedup_rows != grouped_orders + enrich_rejected_rows
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "row counts do not reconcile: filtered_rows={} superseded_rows={} conflict_rejected_rows={} dedup_rows={} enrich_rejected_rows={} grouped_orders={}",
+                filtered_rows,
+                superseded_rows,
+                conflict_rejected_rows,
+                dedup_rows,
+                enrich_rejected_rows,
+                grouped_orders
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Zero-fills the `--dense` combinations; returns the number of groups added.
+fn densify_groups(
+    aggregated: &mut HashMap<GroupKey, AggregateRecord>,
+    domains: &BTreeMap<usize, Vec<String>>,
+    dimensions: &[usize],
+) -> i64 {
+    let mut bases: BTreeSet<[String; DIMENSION_COLUMN_COUNT]> = aggregated
+        .keys()
+        .map(|key| {
+            let mut dims = group_key_dims(key).map(str::to_string);
+            for &idx in domains.keys() {
+                dims[idx].clear();
+            }
+            dims
+        })
+        .collect();
+    if dimensions.iter().all(|idx| domains.contains_key(idx)) {
+        bases.insert(Default::default());
+    }
+
+    let mut added = 0_i64;
+    for base in bases {
+        let mut combos = vec![base];
+        for (&idx, values) in domains {
+            combos = combos
+                .into_iter()
+                .flat_map(|combo| {
+                    values.iter().map(move |value| {
+                        let mut dims = combo.clone();
+                        dims[idx] = value.clone();
+                        dims
+                    })
+                })
+                .collect();
+        }
+        for [date, tier, category, country, bucket, size, risk] in combos {
+            if let std::collections::hash_map::Entry::Vacant(slot) =
+                aggregated.entry((date, tier, category, country, bucket, size, risk))
+            {
+                slot.insert(AggregateRecord::default());
+                added += 1;
+            }
+        }
+    }
+    added
+}
+
+const OTHER_LABEL: &str = "OTHER";
+
+/// Returns the number of suppressed groups and orders.
+fn suppress_small_groups(
+    aggregated: &mut HashMap<GroupKey, AggregateRecord>,
+    min_order_count: i64,
+    rollup_into_other: bool,
+) -> io::Result<(i64, i64)> {
+    let mut other = AggregateRecord::default();
+    let mut suppressed_groups = 0_i64;
+
+    let small_keys: Vec<GroupKey> = aggregated
+        .iter()
+        .filter(|(_, agg)| agg.order_count < min_order_count)
+        .map(|(key, _)| key.clone())
+        .collect();
+    for key in small_keys {
+        if let Some(agg) = aggregated.remove(&key) {
+            suppressed_groups += 1;
+            other.absorb(&agg)?;
+        }
+    }
+
+    let suppressed_orders = other.order_count;
+    if rollup_into_other && suppressed_groups > 0 {
+        let other_key = (
+            OTHER_LABEL.to_string(),
+            OTHER_LABEL.to_string(),
+            OTHER_LABEL.to_string(),
+            OTHER_LABEL.to_string(),
+            OTHER_LABEL.to_string(),
+            OTHER_LABEL.to_string(),
+            OTHER_LABEL.to_string(),
+        );
+        aggregated.entry(other_key).or_default().absorb(&other)?;
+    }
+
+    Ok((suppressed_groups, suppressed_orders))
+}
+
+pub fn transform(
+    events_path: &Path,
+    product_dim_path: &Path,
+    country_dim_path: &Path,
+    output_path: &Path,
+    options: &TransformOptions,
+) -> io::Result<TransformSummary> {
+    let format = &options.input_format;
+    ensure_not_directory(events_path, "events")?;
+    if options.partition_by_date || options.max_rows_per_file.is_some() {
+        ensure_writable_dir(output_path, "output")?;
+    } else {
+        ensure_writable_file(output_path, "output")?;
+    }
+    if let Some(dir) = &options.country_subtotals_dir {
+        ensure_writable_dir(dir, "country subtotals")?;
+    }
+    if let Some(path) = &options.customer_day_out_path {
+        ensure_writable_file(path, "customer-day output")?;
+    }
+    if let Some(path) = &options.audit_versions_path {
+        ensure_writable_file(path, "version audit")?;
+    }
+    if options.top_products.is_some() {
+        ensure_writable_file(&top_products_path_for(output_path), "top products")?;
+    }
+    if let Some(dir) = &options.spill_dir {
+        if let Some(flag) = options.spill_conflict() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("--spill-dir cannot be combined with {}", flag),
+            ));
+        }
+        ensure_writable_dir(dir, "spill")?;
+    }
+    let (product_map, country_map) = load_dimensions(product_dim_path, country_dim_path, options)?;
+    let country_aliases = match &options.country_aliases_path {
+        Some(path) => load_country_aliases(path, format)?,
+        None => HashMap::new(),
+    };
+    let dated_fx = match &options.dated_fx_path {
+        Some(path) => load_dated_fx(path, format)?,
+        None => HashMap::new(),
+    };
+    let customer_tiers = match &options.customer_tier_dim_path {
+        Some(path) => load_customer_tier_dim(path, format, options)?,
+        None => HashMap::new(),
+    };
+
+    let included_products = match &options.include_products_path {
+        Some(path) => Some(load_id_list(path, format, "product id list")?),
+        None => None,
+    };
+    let excluded_products = match &options.exclude_products_path {
+        Some(path) => load_id_list(path, format, "product id list")?,
+        None => HashSet::new(),
+    };
+    let excluded_customers = match &options.exclude_customers_path {
+        Some(path) => load_id_list(path, format, "customer id list")?,
+        None => HashSet::new(),
+    };
+
+    let mut events_format = format.with_delimiter(options.events_delimiter);
+    if matches!(options.events_format, EventsFormat::Ndjson) {
+        events_format.has_header = false;
+    }
+    let events_format = &events_format;
+    let (reader, mut progress) = open_events(events_path, options)?;
+
+    let mut dedup: HashMap<String, EventRecord> = HashMap::new();
+
+    let mut raw_rows = 0_i64;
+    let mut filtered_rows = 0_i64;
+    let mut unknown_tier_rows = 0_i64;
+    let mut full_discount_rows = 0_i64;
+    let mut superseded_rows = 0_i64;
+    let mut dedup_conflicts = 0_i64;
+    // Event ids whose current survivor is in an unresolved conflict; only
+    // filled under `--dedup-conflict reject-conflict`.
+    let mut conflicted_ids: HashSet<String> = HashSet::new();
+    let mut reject_counts: BTreeMap<&'static str, i64> = BTreeMap::new();
+
+    // Raw lines of the current dedup survivors, kept only when losers are written out.
+    let mut superseded_out = match &options.superseded_out_path {
+        Some(path) => Some(BufWriter::new(File::create(path)?)),
+        None => None,
+    };
+    let mut survivor_lines: HashMap<String, String> = HashMap::new();
+    let mut version_audit = match &options.audit_versions_path {
+        Some(path) => Some(VersionAudit::create(path)?),
+        None => None,
+    };
+    let mut header_line: Option<String> = None;
+    let mut repeated_header_rows = 0_i64;
+    let mut skipped_rows = 0_i64;
+    let mut invalid_version_rows = 0_i64;
+    let mut lines_read = 0_usize;
+    // Column of `--fx-key-column` in each events row; CSV takes it from the
+    // header, NDJSON appends the field after the 14 event fields.
+    let mut fx_key_idx = match (&options.fx_key_column, options.events_format) {
+        (None, _) => None,
+        (Some(_), EventsFormat::Ndjson) => Some(14),
+        (Some(_), EventsFormat::Csv) if events_format.has_header => None,
+        (Some(name), EventsFormat::Csv) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "--fx-key-column {:?} needs an events header to find the column",
+                    name
+                ),
+            ));
+        }
+    };
+
+    let lines = input_lines(reader).max_line_bytes(options.max_line_bytes);
+    for (idx, line_res) in lines.enumerate() {
+        lines_read = idx + 1;
+        check_interrupt(options, raw_rows, filtered_rows, &reject_counts)?;
+        let line = match line_res {
+            Ok(line) => line,
+            Err(err) if is_line_too_long(&err) => {
+                if (skipped_rows as usize) < options.skip_rows {
+                    skipped_rows += 1;
+                    continue;
+                }
+                raw_rows += 1;
+                count_reject(&mut reject_counts, "line_too_long");
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        if let Some(progress) = progress.as_mut() {
+            progress.tick(lines_read);
+        }
+        if events_format.is_header_line(idx) {
+            if let Some(out) = superseded_out.as_mut() {
+                writeln!(out, "{}", line)?;
+            }
+            if let Some(name) = &options.fx_key_column {
+                let position = events_format
+                    .split_line(&line)
+                    .iter()
+                    .position(|column| column.eq_ignore_ascii_case(name));
+                if position.is_none() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("--fx-key-column {:?} is not in the events header", name),
+                    ));
+                }
+                fx_key_idx = position;
+            }
+            header_line = Some(line);
+            continue;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        // Shards concatenated with `cat` repeat the header mid-file.
+        if header_line.as_deref() == Some(line.as_str()) {
+            repeated_header_rows += 1;
+            continue;
+        }
+        if (skipped_rows as usize) < options.skip_rows {
+            skipped_rows += 1;
+            continue;
+        }
+
+        raw_rows += 1;
+        let ndjson_fields;
+        let cols = match options.events_format {
+            EventsFormat::Csv => events_format.split_line(&line),
+            EventsFormat::Ndjson => {
+                // A line that is not a flat JSON object is skipped like a short CSV row.
+                let Some(fields) =
+                    ndjson_input::parse_event_fields(&line, options.fx_key_column.as_deref())
+                else {
+                    continue;
+                };
+                ndjson_fields = fields;
+                ndjson_fields.iter().map(String::as_str).collect()
+            }
+        };
+        if cols.len() < 14 {
+            continue;
+        }
+
+        let event_id = cols[0];
+        if event_id.is_empty() {
+            continue;
+        }
+
+        let parsed_version = events_format.parse_version(cols[1]);
+        if let (Some(audit), Some(version)) = (version_audit.as_mut(), parsed_version) {
+            audit.observe(lines_read, event_id, version)?;
+        }
+        let event_ts = options.timestamp_layout.event_ts(cols[2], cols[3]);
+        let event_date = options.date_from.event_date(cols[3], &event_ts);
+        let customer_id = events_format.parse_i64(cols[4]);
+        let product_id = events_format.parse_i64(cols[5]);
+        let amount_cents = events_format.parse_i64(cols[6]);
+        let quantity = events_format.parse_i64(cols[7]);
+        let parsed_discount_bps = events_format.try_parse_i64(cols[8]);
+        let parsed_shipping_cents = events_format.try_parse_i64(cols[9]);
+        let declared_discount_bps = parsed_discount_bps.unwrap_or(0);
+        let discount_bps = clamp_i64(declared_discount_bps, 0, 5000);
+        let shipping_cents = clamp_i64(
+            parsed_shipping_cents.unwrap_or(0),
+            options.min_shipping_cents,
+            25_000,
+        );
+        let status = cols[10].to_ascii_uppercase();
+        let country_raw = cols[11].to_ascii_uppercase();
+        let country = match country_aliases.get(&country_raw) {
+            Some(canonical) => canonical.clone(),
+            None => country_raw,
+        };
+
+        let fx_key = match fx_key_idx.and_then(|idx| cols.get(idx)) {
+            Some(value) if !value.is_empty() => {
+                let fx_key = value.to_ascii_uppercase();
+                if fx_key == country {
+                    String::new()
+                } else {
+                    fx_key
+                }
+            }
+            _ => String::new(),
+        };
+
+        let customer_tier_raw = cols[12].to_ascii_lowercase();
+        let known_tier = options.is_known_tier(&customer_tier_raw);
+        let customer_tier = if known_tier {
+            customer_tier_raw
+        } else {
+            options.unknown_label().to_string()
+        };
+
+        if status != "COMPLETE" || amount_cents <= 0 || quantity <= 0 {
+            continue;
+        }
+        if customer_id <= 0 || product_id <= 0 || event_date.is_empty() || event_ts.is_empty() {
+            continue;
+        }
+        let Some(event_date) = options.date_ts_mismatch.resolve(event_date, &event_ts) else {
+            count_reject(&mut reject_counts, "date_ts_mismatch");
+            continue;
+        };
+        if matches!(options.numeric_missing, NumericMissing::Reject) {
+            if parsed_discount_bps.is_none() {
+                count_reject(&mut reject_counts, "missing_discount");
+                continue;
+            }
+            if parsed_shipping_cents.is_none() {
+                count_reject(&mut reject_counts, "missing_shipping");
+                continue;
+            }
+        }
+
+        let event_version = match parsed_version {
+            Some(version) => version,
+            None => {
+                invalid_version_rows += 1;
+                match options.invalid_version {
+                    InvalidVersionPolicy::Zero => 0,
+                    InvalidVersionPolicy::Lowest => i64::MIN,
+                    InvalidVersionPolicy::Reject => {
+                        count_reject(&mut reject_counts, "invalid_event_version");
+                        continue;
+                    }
+                }
+            }
+        };
+
+        if included_products
+            .as_ref()
+            .is_some_and(|included| !included.contains(&product_id))
+        {
+            count_reject(&mut reject_counts, "product_not_included");
+            continue;
+        }
+        if excluded_products.contains(&product_id) {
+            count_reject(&mut reject_counts, "product_excluded");
+            continue;
+        }
+        if excluded_customers.contains(&customer_id) {
+            count_reject(&mut reject_counts, "excluded_customer");
+            continue;
+        }
+
+        if options
+            .max_amount_cents
+            .is_some_and(|max| amount_cents > max)
+        {
+            count_reject(&mut reject_counts, "amount_too_large");
+            continue;
+        }
+        if options.max_quantity.is_some_and(|max| quantity > max) {
+            count_reject(&mut reject_counts, "quantity_implausible");
+            continue;
+        }
+
+        // The 50% discount clamp hides discounts that would wipe out the
+        // whole order, so check the declared rate before it is applied.
+        let gross_local_cents = amount_cents
+            .saturating_mul(quantity)
+            .saturating_add(shipping_cents);
+        let declared_discount_cents = round_div(
+            gross_local_cents.saturating_mul(declared_discount_bps),
+            10_000,
+        );
+        if gross_local_cents > 0 && declared_discount_cents >= gross_local_cents {
+            full_discount_rows += 1;
+            if options.reject_full_discount {
+                count_reject(&mut reject_counts, "discount_exceeds_gross");
+                continue;
+            }
+        }
+
+        filtered_rows += 1;
+        if !known_tier {
+            unknown_tier_rows += 1;
+        }
+
+        let candidate = EventRecord {
+            event_version,
+            event_ts: event_ts.into_owned(),
+            event_date: event_date.into_owned(),
+            customer_id,
+            product_id,
+            amount_cents,
+            quantity,
+            discount_bps,
+            shipping_cents,
+            country,
+            customer_tier,
+            fx_key,
+        };
+
+        let (should_replace, superseded) = match dedup.get(event_id) {
+            Some(current)
+                if matches!(options.dedup_keep, DedupKeep::LatestVersion)
+                    && DedupConflict::conflicts(current, &candidate) =>
+            {
+                dedup_conflicts += 1;
+                if matches!(options.dedup_conflict, DedupConflict::RejectConflict) {
+                    conflicted_ids.insert(event_id.to_string());
+                }
+                (options.dedup_conflict.replaces(current, &candidate), true)
+            }
+            Some(current) => (options.dedup_keep.replaces(current, &candidate), true),
+            None => (true, false),
+        };
+        if should_replace && !conflicted_ids.is_empty() {
+            conflicted_ids.remove(event_id);
+        }
+
+        if superseded {
+            superseded_rows += 1;
+        }
+        if let Some(out) = superseded_out.as_mut() {
+            if !should_replace {
+                writeln!(out, "{}", line)?;
+            } else if let Some(losing_line) =
+                survivor_lines.insert(event_id.to_string(), line.clone())
+            {
+                writeln!(out, "{}", losing_line)?;
+            }
+        }
+
+        if should_replace {
+            dedup.insert(event_id.to_string(), candidate);
+        }
+    }
+    if let Some(progress) = progress {
+        progress.finish(lines_read);
+    }
+
+    if let Some(mut out) = superseded_out {
+        out.flush()?;
+    }
+    if let Some(audit) = version_audit {
+        audit.finish()?;
+    }
+
+    if let Some(max_rate) = options.max_unknown_tier_rate {
+        let rate = if filtered_rows > 0 {
+            unknown_tier_rows as f64 / filtered_rows as f64
+        } else {
+            0.0
+        };
+        if rate > max_rate {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "unknown customer_tier rate {:.6} ({} of {} filtered rows) exceeds --max-unknown-tier-rate {}",
+                    rate, unknown_tier_rows, filtered_rows, max_rate
+                ),
+            ));
+        }
+    }
+
+    let conflict_rejected_rows = conflicted_ids.len() as i64;
+    for event_id in &conflicted_ids {
+        dedup.remove(event_id);
+        count_reject(&mut reject_counts, "dedup_conflict");
+    }
+
+    let mut dedup_rows_by_date: BTreeMap<String, i64> = BTreeMap::new();
+    for record in dedup.values() {
+        *dedup_rows_by_date
+            .entry(record.event_date.clone())
+            .or_insert(0) += 1;
+    }
+
+    let mut customer_day_spend: HashMap<(String, i64, String), CustomerDaySpend> = HashMap::new();
+    let mut enriched_rows: Vec<DerivedRecord> = Vec::with_capacity(dedup.len());
+
+    let context = EnrichContext {
+        product_map: &product_map,
+        country_map: &country_map,
+        dated_fx: &dated_fx,
+        customer_tiers: &customer_tiers,
+        options,
+    };
+
+    if let Some(event_id) = &options.explain_event_id {
+        explain_event(&context, event_id, dedup.get(event_id));
+    }
+
+    let mask = MeasureMask::for_options(options);
+    let measure = |selected: bool, value: i64| if selected { value } else { 0 };
+    let collapsed = options.collapsed_dimensions();
+    let dimension = |idx: usize, value: &str| {
+        if collapsed[idx] {
+            String::new()
+        } else {
+            value.to_string()
+        }
+    };
+    let group_order = |row: &DerivedRecord, vip_customer_order: i64| {
+        let key = (
+            dimension(0, &row.event_date),
+            dimension(1, &row.customer_tier),
+            dimension(2, &row.category),
+            dimension(COUNTRY_COLUMN, &row.country),
+            dimension(4, &row.time_bucket),
+            dimension(5, &row.order_size_bucket),
+            dimension(RISK_BUCKET_COLUMN, &row.risk_bucket),
+        );
+
+        let mut order = AggregateRecord {
+            order_count: 1,
+            vip_customer_orders: vip_customer_order,
+            total_quantity: measure(mask.quantity, row.quantity),
+            total_net_usd_cents: measure(mask.net, row.net_usd_cents),
+            total_profit_usd_cents: measure(mask.profit, row.profit_usd_cents),
+            total_risk_adjusted_usd_cents: measure(mask.risk, row.risk_adjusted_usd_cents),
+            total_items: measure(mask.items, row.quantity),
+            heavy_item_orders: measure(mask.heavy, row.heavy_item_order),
+            total_taxable_local_cents: measure(options.effective_tax, row.taxable_local_cents),
+            total_tax_local_cents: measure(options.effective_tax, row.tax_local_cents),
+            total_net_squared: if mask.net_squared {
+                i128::from(row.net_usd_cents).pow(2)
+            } else {
+                0
+            },
+            extra: BTreeMap::new(),
+        };
+        for (name, value) in &row.extra {
+            add_total(order.extra.entry(name.clone()).or_insert(0), *value, name)?;
+        }
+        Ok::<_, io::Error>((key, order))
+    };
+
+    // Under `--spill-dir` each enriched row goes straight to a sorted run
+    // instead of `enriched_rows`; VIP is resolved when the runs are merged.
+    let mut spill = options.spill_dir.as_deref().map(|dir| {
+        external_sort::SpillWriter::new(
+            dir,
+            options.spill_chunk_rows.unwrap_or(DEFAULT_SPILL_CHUNK_ROWS),
+        )
+    });
+
+    let mut enrich_rejected_rows = 0_i64;
+    let mut accept = |outcome: Result<DerivedRecord, &'static str>| match outcome {
+        Ok(row) => {
+            let customer_day_key = options.vip_key.spend_key(&row);
+            let tier_rank = options.tier_rank(&row.customer_tier);
+            let threshold = options.vip_threshold(&row.customer_tier);
+            let day = customer_day_spend
+                .entry(customer_day_key)
+                .or_insert(CustomerDaySpend {
+                    net_usd_cents: 0,
+                    tier_rank,
+                    vip_threshold_usd_cents: threshold,
+                });
+            day.net_usd_cents += row.net_usd_cents;
+            if tier_rank > day.tier_rank {
+                day.tier_rank = tier_rank;
+                day.vip_threshold_usd_cents = threshold;
+            }
+            match spill.as_mut() {
+                Some(spill) => {
+                    let (key, order) = group_order(&row, 0)?;
+                    spill.push(key, options.vip_key.spend_key(&row), order)
+                }
+                None => {
+                    enriched_rows.push(row);
+                    Ok(())
+                }
+            }
+        }
+        Err(reason) => {
+            enrich_rejected_rows += 1;
+            count_reject(&mut reject_counts, reason);
+            Ok(())
+        }
+    };
+
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        let outcomes: Vec<_> = dedup
+            .par_iter()
+            .map(|(_, record)| context.enrich(record))
+            .collect();
+        outcomes.into_iter().try_for_each(&mut accept)?;
+    }
+    #[cfg(not(feature = "parallel"))]
+    dedup
+        .values()
+        .map(|record| context.enrich(record))
+        .try_for_each(&mut accept)?;
+
+    let is_vip_day = |spend_key: &external_sort::SpendKey| {
+        let vip_day = mask.vip
+            && customer_day_spend
+                .get(spend_key)
+                .is_some_and(CustomerDaySpend::is_vip);
+        i64::from(vip_day)
+    };
+
+    let mut aggregated: HashMap<GroupKey, AggregateRecord> = HashMap::new();
+    for row in &enriched_rows {
+        let (key, order) = group_order(row, is_vip_day(&options.vip_key.spend_key(row)))?;
+        aggregated.entry(key).or_default().absorb(&order)?;
+    }
+
+    if options.verify {
+        verify_aggregation(&enriched_rows, &aggregated)?;
+    }
+    let grouped_orders: i64 = match &spill {
+        Some(spill) => spill.orders(),
+        None => aggregated.values().map(|agg| agg.order_count).sum(),
+    };
+    reconcile_row_counts(
+        filtered_rows,
+        superseded_rows,
+        conflict_rejected_rows,
+        dedup.len() as i64,
+        enrich_rejected_rows,
+        grouped_orders,
+    )?;
+
+    let memory_estimate = MemoryEstimate::measure(&dedup, &enriched_rows, &aggregated);
+
+    // Nothing past this point needs the per-event structures, so release
+    // them before the groups are sorted and written.
+    let dedup_rows = dedup.len() as i64;
+    let top_products = match options.top_products {
+        Some(limit) => Some(rank_top_products(&enriched_rows, limit)?),
+        None => None,
+    };
+    drop(dedup);
+    drop(enriched_rows);
+
+    let mut negligible_groups = 0_i64;
+    if let Some(min_net_usd_cents) = options.min_net_usd_cents {
+        aggregated.retain(|_, agg| {
+            let keep = agg.total_net_usd_cents.unsigned_abs() >= min_net_usd_cents as u64;
+            if !keep {
+                negligible_groups += 1;
+            }
+            keep
+        });
+    }
+
+    let (suppressed_groups, suppressed_orders) = match options.min_order_count {
+        Some(min_order_count) => {
+            suppress_small_groups(&mut aggregated, min_order_count, options.suppressed_other)?
+        }
+        None => (0, 0),
+    };
+
+    let dense_groups = if options.dense_domains.is_empty() {
+        0
+    } else {
+        densify_groups(
+            &mut aggregated,
+            &options.dense_domains,
+            &options.dimension_columns(),
+        )
+    };
+
+    let mut rows: Vec<_> = aggregated.into_iter().collect();
+    rows.sort_by(|a, b| {
+        a.0 .0
+            .cmp(&b.0 .0)
+            .then(a.0 .1.cmp(&b.0 .1))
+            .then(a.0 .2.cmp(&b.0 .2))
+            .then(a.0 .3.cmp(&b.0 .3))
+            .then(a.0 .4.cmp(&b.0 .4))
+            .then(a.0 .5.cmp(&b.0 .5))
+            .then(a.0 .6.cmp(&b.0 .6))
+    });
+
+    let mut extra_names: Vec<String> = rows
+        .iter()
+        .flat_map(|(_, agg)| agg.extra.keys())
+        .chain(spill.iter().flat_map(|spill| spill.extra_names()))
+        .filter(|name| options.quantity_bins.is_none() || !name.starts_with(QUANTITY_BIN_PREFIX))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .cloned()
+        .collect();
+    // Bins keep their numeric order and appear even when no order fell in them.
+    if let Some(bins) = &options.quantity_bins {
+        extra_names.extend(bins.column_names().iter().cloned());
+    }
+    if options.effective_tax {
+        extra_names.push(EFFECTIVE_TAX_COLUMN_NAME.to_string());
+    }
+    if options.canonical {
+        extra_names.sort();
+    }
+    // The sorted row order is part of the `--canonical` contract.
+    if let Some(seed) = options.shuffle_output_seed.filter(|_| !options.canonical) {
+        shuffle_rows(&mut rows, seed);
+    }
+
+    // Under `--spill-dir` `rows` is empty and the groups come off the merge
+    // in the same order, written as they complete.
+    let mut merge = match spill {
+        Some(spill) => Some(spill.into_merge()?),
+        None => None,
+    };
+    let mut next_merged_group = || {
+        let Some(merge) = merge.as_mut() else {
+            return Ok(None);
+        };
+        while let Some((key, agg)) = merge.next_group(&is_vip_day)? {
+            if options
+                .min_net_usd_cents
+                .is_some_and(|min| agg.total_net_usd_cents.unsigned_abs() < min as u64)
+            {
+                negligible_groups += 1;
+                continue;
+            }
+            return Ok(Some((key, agg)));
+        }
+        Ok::<_, io::Error>(None)
+    };
+    let mut merged_group = next_merged_group()?;
+
+    check_interrupt(options, raw_rows, filtered_rows, &reject_counts)?;
+    if options.fail_on_empty && rows.is_empty() && merged_group.is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "--fail-on-empty: no aggregate rows to write | raw_rows={} filtered_rows={} rejects={}",
+                raw_rows,
+                filtered_rows,
+                format_reject_counts(&reject_counts)
+            ),
+        ));
+    }
+    if let Some(path) = &options.customer_day_out_path {
+        write_customer_day_spend(path, &customer_day_spend, options.vip_key)?;
+    }
+    if let Some(ranked) = &top_products {
+        write_top_products(&top_products_path_for(output_path), ranked)?;
+    }
+    let mut output_files = Vec::new();
+    let columns = if options.partition_by_date {
+        write_partitioned_by_date(output_path, &rows, options, &extra_names, &mut output_files)?
+    } else if let Some(max_rows) = options.max_rows_per_file {
+        write_rotated_parts(
+            output_path,
+            &rows,
+            options,
+            &extra_names,
+            max_rows,
+            &data_file_extension(output_path, options),
+            &mut output_files,
+        )?
+    } else {
+        let mut sink = open_sink(output_path, options)?;
+        let columns = match &options.pivot {
+            Some(pivot) => {
+                write_pivoted_rows(sink.as_mut(), &rows, pivot, &options.dimension_columns())?
+            }
+            None => {
+                let selected = options.output_columns();
+                let columns = write_rows(sink.as_mut(), &rows, &selected, &extra_names)?;
+                while let Some((key, agg)) = merged_group {
+                    write_group_row(
+                        sink.as_mut(),
+                        &mut Vec::new(),
+                        &key,
+                        &agg,
+                        &selected,
+                        &extra_names,
+                    )?;
+                    merged_group = next_merged_group()?;
+                }
+                columns
+            }
+        };
+        sink.finish()?;
+        output_files.push(output_path.to_path_buf());
+        columns
+    };
+
+    if let Some(subtotals_dir) = &options.country_subtotals_dir {
+        write_country_subtotals(
+            subtotals_dir,
+            &rows,
+            options,
+            &extra_names,
+            &data_file_extension(output_path, options),
+            &mut output_files,
+        )?;
+    }
+
+    if options.emit_schema {
+        let mut schema_columns = options.output_unit.columns(&columns);
+        if options.run_id_column {
+            schema_columns.push((RUN_ID_COLUMN.name.to_string(), &RUN_ID_COLUMN));
+        }
+        write_schema(&schema_path_for(output_path), &schema_columns)?;
+    }
+
+    let diff = match &options.diff_baseline_path {
+        Some(baseline_path) => Some(write_diff(
+            baseline_path,
+            &diff_path_for(output_path),
+            &columns,
+            &rows,
+            &options.output_columns(),
+            &extra_names,
+        )?),
+        None => None,
+    };
+
+    Ok(TransformSummary {
+        raw_rows,
+        filtered_rows,
+        dedup_rows,
+        enrich_rejected_rows,
+        grouped_orders,
+        unknown_tier_rows,
+        full_discount_rows,
+        superseded_rows,
+        dedup_conflicts,
+        repeated_header_rows,
+        skipped_rows,
+        invalid_version_rows,
+        dedup_rows_by_date,
+        reject_counts,
+        suppressed_groups,
+        suppressed_orders,
+        negligible_groups,
+        dense_groups,
+        output_files,
+        diff,
+        memory_estimate,
+        run_id: options.run_id.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests;
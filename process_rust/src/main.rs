@@ -1,466 +1,1223 @@
-use std::collections::HashMap;
 use std::env;
-use std::fs::File;
-use std::io::{self, BufRead, BufReader, BufWriter, Write};
-use std::path::Path;
-
-#[derive(Clone)]
-struct ProductDim {
-    category: String,
-    margin_bps: i64,
-    weight_grams: i64,
+use std::io;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "signals")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "signals")]
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "sqlite")]
+use process_rust::sqlite_dims;
+use process_rust::{
+    effective_config, ensure_writable_file, format_reject_counts, json_string, parse_dense_domain,
+    parse_pivot_spec, resolve_measures, resolve_output_columns, transform, write_metrics,
+    CategoryLevel, CountryOverride, DateSource, DateTsMismatch, DedupConflict, DedupKeep,
+    EventsFormat, InvalidVersionPolicy, NumberLocale, NumericMissing, OutputFormat,
+    OutputNumberFormat, OutputUnit, ParquetCompression, ProductOverride, ProfitFloor, QuantityBins,
+    RiskBuckets, RiskMode, RoundingMode, TimestampLayout, TransformOptions, TransformSummary,
+    VipKey,
+};
+
+#[cfg(feature = "watch")]
+mod watch;
+
+struct CliArgs {
+    events_path: PathBuf,
+    product_dim_path: PathBuf,
+    country_dim_path: PathBuf,
+    output_path: PathBuf,
+    options: TransformOptions,
+    config_path: Option<PathBuf>,
+    metrics_path: Option<PathBuf>,
+    quiet: bool,
+    print_config: bool,
+    verify_idempotent: bool,
+    #[cfg(feature = "watch")]
+    watch: bool,
 }
 
-#[derive(Clone)]
-struct CountryDim {
-    fx_to_usd_ppm: i64,
-    risk_bps: i64,
-    tax_bps: i64,
+fn option_value<'a>(
+    flag: &str,
+    iter: &mut impl Iterator<Item = &'a String>,
+) -> Result<&'a str, String> {
+    iter.next()
+        .map(String::as_str)
+        .ok_or_else(|| format!("missing value for {}", flag))
 }
 
-#[derive(Clone)]
-struct EventRecord {
-    event_version: i64,
-    event_ts: String,
-    event_date: String,
-    customer_id: i64,
-    product_id: i64,
-    amount_cents: i64,
-    quantity: i64,
-    discount_bps: i64,
-    shipping_cents: i64,
-    country: String,
-    customer_tier: String,
+fn parse_rate(flag: &str, value: &str) -> Result<f64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err(format!(
+            "{} expects a rate between 0 and 1, got {:?}",
+            flag, value
+        )),
+    }
 }
 
-#[derive(Clone)]
-struct DerivedRecord {
-    event_date: String,
-    customer_id: i64,
-    customer_tier: String,
-    category: String,
-    country: String,
-    time_bucket: String,
-    order_size_bucket: String,
-    quantity: i64,
-    net_usd_cents: i64,
-    profit_usd_cents: i64,
-    risk_adjusted_usd_cents: i64,
-    heavy_item_order: i64,
+fn parse_key_value<'a>(flag: &str, value: &'a str) -> Result<(&'a str, &'a str), String> {
+    match value.split_once('=') {
+        Some((key, val)) if !key.trim().is_empty() && !val.trim().is_empty() => {
+            Ok((key.trim(), val.trim()))
+        }
+        _ => Err(format!("{} expects key=value, got {:?}", flag, value)),
+    }
 }
 
-#[derive(Default)]
-struct AggregateRecord {
-    order_count: i64,
-    vip_customer_orders: i64,
-    total_quantity: i64,
-    total_net_usd_cents: i64,
-    total_profit_usd_cents: i64,
-    total_risk_adjusted_usd_cents: i64,
-    total_items: i64,
-    heavy_item_orders: i64,
+fn parse_bounded_i64(flag: &str, value: &str, low: i64, high: i64) -> Result<i64, String> {
+    match value.trim().parse::<i64>() {
+        Ok(parsed) if (low..=high).contains(&parsed) => Ok(parsed),
+        _ => Err(format!(
+            "{} expects an integer between {} and {}, got {:?}",
+            flag, low, high, value
+        )),
+    }
 }
 
-fn parse_i64(value: &str) -> i64 {
-    value.trim().parse::<i64>().unwrap_or(0)
+/// The start time in nanoseconds and the process id, in hex.
+fn generate_run_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos());
+    format!("{:x}-{:x}", nanos, std::process::id())
 }
 
-fn clamp_i64(value: i64, low: i64, high: i64) -> i64 {
-    if value < low {
-        low
-    } else if value > high {
-        high
-    } else {
-        value
+/// Run ids land unquoted in CSV rows, so they need no escaping.
+fn parse_run_id(flag: &str, value: &str) -> Result<String, String> {
+    let valid = !value.is_empty()
+        && value
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '-' | '_' | '.' | ':'));
+    if !valid {
+        return Err(format!(
+            "{} expects letters, digits, '-', '_', '.' or ':', got {:?}",
+            flag, value
+        ));
     }
+    Ok(value.to_string())
 }
 
-fn round_div(numerator: i64, denominator: i64) -> i64 {
-    if numerator <= 0 || denominator <= 0 {
-        return 0;
+fn parse_bool(flag: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(format!("{} expects true or false, got {:?}", flag, value)),
     }
-    (numerator + (denominator / 2)) / denominator
 }
 
-fn parse_event_hour(event_ts: &str) -> i64 {
-    if event_ts.len() < 13 {
-        return -1;
+fn parse_delimiter(flag: &str, value: &str) -> Result<char, String> {
+    if value == "tab" || value == "\\t" {
+        return Ok('\t');
     }
-
-    if event_ts.as_bytes()[10] != b'T' {
-        return -1;
+    let mut chars = value.chars();
+    match (chars.next(), chars.next()) {
+        (Some(ch), None) if ch != '\r' && ch != '\n' => Ok(ch),
+        _ => Err(format!(
+            "{} expects a single character or \"tab\", got {:?}",
+            flag, value
+        )),
     }
+}
 
-    let hour = parse_i64(&event_ts[11..13]);
-    if (0..=23).contains(&hour) {
-        hour
-    } else {
-        -1
+/// One option per line, as on the command line; blank and `#` lines are skipped.
+fn read_config_file(path: &Path) -> Result<Vec<String>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| format!("--config {}: {}", path.display(), err))?;
+    let mut args = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if !line.starts_with("--") || line.starts_with("--config") {
+            return Err(format!(
+                "--config {} line {}: expected an option other than --config, got {:?}",
+                path.display(),
+                idx + 1,
+                line
+            ));
+        }
+        match line.split_once(char::is_whitespace) {
+            Some((flag, value)) => {
+                args.push(flag.to_string());
+                args.push(value.trim().to_string());
+            }
+            None => args.push(line.to_string()),
+        }
     }
+    Ok(args)
 }
 
-fn time_bucket_from_hour(hour: i64) -> String {
-    if (0..6).contains(&hour) {
-        "night".to_string()
-    } else if (6..12).contains(&hour) {
-        "morning".to_string()
-    } else if (12..18).contains(&hour) {
-        "afternoon".to_string()
-    } else if (18..24).contains(&hour) {
-        "evening".to_string()
-    } else {
-        "unknown".to_string()
+/// Command-line flags win over the `--config` file.
+fn merge_config_file(args: &[String]) -> Result<(Option<PathBuf>, Vec<String>), String> {
+    let mut config_path = None;
+    let mut command_line = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--config" {
+            if config_path.is_some() {
+                return Err("--config given twice".to_string());
+            }
+            config_path = Some(PathBuf::from(option_value(arg, &mut iter)?));
+        } else {
+            command_line.push(arg.clone());
+        }
     }
+    let mut merged = match &config_path {
+        Some(path) => read_config_file(path)?,
+        None => Vec::new(),
+    };
+    merged.extend(command_line);
+    Ok((config_path, merged))
 }
 
-fn order_size_bucket(quantity: i64) -> String {
-    if quantity <= 1 {
-        "single".to_string()
-    } else if quantity <= 3 {
-        "small_multi".to_string()
-    } else {
-        "bulk".to_string()
+fn parse_args(args: &[String]) -> Result<CliArgs, String> {
+    let (config_path, args) = merge_config_file(args)?;
+    let mut positional: Vec<&str> = Vec::new();
+    let mut options = TransformOptions::default();
+    let mut metrics_path = None;
+    let mut quiet = false;
+    let mut print_config = false;
+    let mut verify_idempotent = false;
+    #[cfg(feature = "watch")]
+    let mut watch = false;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--emit-schema" => options.emit_schema = true,
+            "--quiet" => quiet = true,
+            "--print-config" => print_config = true,
+            "--verify-idempotent" => verify_idempotent = true,
+            "--metrics-file" => metrics_path = Some(PathBuf::from(option_value(arg, &mut iter)?)),
+            #[cfg(feature = "watch")]
+            "--watch" => watch = true,
+            #[cfg(not(feature = "watch"))]
+            "--watch" => return Err("--watch requires building with --features watch".to_string()),
+            "--max-unknown-tier-rate" => {
+                let value = option_value(arg, &mut iter)?;
+                options.max_unknown_tier_rate = Some(parse_rate(arg, value)?);
+            }
+            "--category-margin" => {
+                let (category, bps) = parse_key_value(arg, option_value(arg, &mut iter)?)?;
+                let margin_bps = parse_bounded_i64(arg, bps, 0, 9500)?;
+                options
+                    .category_margins
+                    .insert(category.to_ascii_lowercase(), margin_bps);
+            }
+            "--vip-threshold" => {
+                let (tier, cents) = parse_key_value(arg, option_value(arg, &mut iter)?)?;
+                let cents = parse_bounded_i64(arg, cents, 0, i64::MAX)?;
+                options
+                    .vip_thresholds
+                    .insert(tier.to_ascii_lowercase(), cents);
+            }
+            "--heavy-grams" => {
+                let (category, grams) = parse_key_value(arg, option_value(arg, &mut iter)?)?;
+                let grams = parse_bounded_i64(arg, grams, 1, i64::MAX)?;
+                options
+                    .heavy_grams_by_category
+                    .insert(category.to_ascii_lowercase(), grams);
+            }
+            "--product-override" => options
+                .product_overrides
+                .push(ProductOverride::parse(arg, option_value(arg, &mut iter)?)?),
+            "--country-override" => options
+                .country_overrides
+                .push(CountryOverride::parse(arg, option_value(arg, &mut iter)?)?),
+            "--usd-rounding-unit" => {
+                let (country, unit) = parse_key_value(arg, option_value(arg, &mut iter)?)?;
+                let unit = match unit {
+                    "1" => 1,
+                    "10" => 10,
+                    "100" => 100,
+                    _ => {
+                        return Err(format!(
+                            "{} expects <country>=1, 10 or 100, got {:?}",
+                            arg, unit
+                        ))
+                    }
+                };
+                options
+                    .usd_rounding_units
+                    .insert(country.to_ascii_uppercase(), unit);
+            }
+            "--dated-fx" => {
+                options.dated_fx_path = Some(PathBuf::from(option_value(arg, &mut iter)?));
+            }
+            "--columns" => {
+                options.columns = Some(resolve_output_columns(option_value(arg, &mut iter)?)?);
+            }
+            "--measures" => {
+                options.measures = Some(resolve_measures(option_value(arg, &mut iter)?)?);
+            }
+            "--pivot" => options.pivot = Some(parse_pivot_spec(option_value(arg, &mut iter)?)?),
+            "--profit-floor" => {
+                options.profit_floor = ProfitFloor::parse(option_value(arg, &mut iter)?)?
+            }
+            "--risk-mode" => options.risk_mode = RiskMode::parse(option_value(arg, &mut iter)?)?,
+            "--delimiter" => {
+                options.input_format.delimiter =
+                    parse_delimiter(arg, option_value(arg, &mut iter)?)?
+            }
+            "--number-locale" => {
+                options.input_format.number_locale =
+                    NumberLocale::parse(option_value(arg, &mut iter)?)?;
+            }
+            "--max-amount-cents" => {
+                let value = option_value(arg, &mut iter)?;
+                options.max_amount_cents = Some(parse_bounded_i64(arg, value, 1, i64::MAX)?);
+            }
+            "--max-quantity" => {
+                let value = option_value(arg, &mut iter)?;
+                options.max_quantity = Some(parse_bounded_i64(arg, value, 1, i64::MAX)?);
+            }
+            #[cfg(feature = "sqlite")]
+            "--dims-sqlite" => {
+                let db_path = PathBuf::from(option_value(arg, &mut iter)?);
+                options.sqlite_dims = Some(sqlite_dims::SqliteDimSource::new(db_path));
+            }
+            #[cfg(feature = "sqlite")]
+            "--product-columns" | "--country-columns" => {
+                let columns = sqlite_dims::parse_dim_columns(arg, option_value(arg, &mut iter)?)?;
+                let source = options
+                    .sqlite_dims
+                    .as_mut()
+                    .ok_or_else(|| format!("{} must follow --dims-sqlite", arg))?;
+                if arg == "--product-columns" {
+                    source.product_columns = columns;
+                } else {
+                    source.country_columns = columns;
+                }
+            }
+            #[cfg(not(feature = "sqlite"))]
+            "--dims-sqlite" | "--product-columns" | "--country-columns" => {
+                return Err(format!("{} requires building with --features sqlite", arg));
+            }
+            "--output-format" => {
+                options.output_format = OutputFormat::parse(option_value(arg, &mut iter)?)?;
+            }
+            "--min-order-count" => {
+                let value = option_value(arg, &mut iter)?;
+                options.min_order_count = Some(parse_bounded_i64(arg, value, 1, i64::MAX)?);
+            }
+            "--suppressed-other" => options.suppressed_other = true,
+            "--preserve-category-case" => options.preserve_category_case = true,
+            "--lowercase-output-category" => options.lowercase_output_category = true,
+            "--unknown-label" => {
+                let label = option_value(arg, &mut iter)?.trim();
+                if label.is_empty() || label.contains([',', '\r', '\n']) {
+                    return Err(format!("{} expects a non-empty label without commas", arg));
+                }
+                options.unknown_label = Some(label.to_string());
+            }
+            "--dim-comment-prefix" => {
+                let prefix = option_value(arg, &mut iter)?.trim();
+                if prefix.is_empty() {
+                    return Err(format!("{} expects a non-empty prefix", arg));
+                }
+                options.dim_comment_prefix = Some(prefix.to_string());
+            }
+            "--timestamp-layout" => {
+                options.timestamp_layout = TimestampLayout::parse(option_value(arg, &mut iter)?)?;
+            }
+            "--parquet-compression" => {
+                options.parquet_compression =
+                    ParquetCompression::parse(option_value(arg, &mut iter)?)?
+            }
+            "--category-level" => {
+                options.category_level = CategoryLevel::parse(option_value(arg, &mut iter)?)?
+            }
+            "--date-from" => options.date_from = DateSource::parse(option_value(arg, &mut iter)?)?,
+            "--date-ts-mismatch" => {
+                options.date_ts_mismatch = DateTsMismatch::parse(option_value(arg, &mut iter)?)?
+            }
+            "--reject-full-discount" => options.reject_full_discount = true,
+            "--no-header" => options.input_format.has_header = false,
+            "--partition-by" => match option_value(arg, &mut iter)? {
+                "event_date" => options.partition_by_date = true,
+                other => {
+                    return Err(format!(
+                        "--partition-by supports only event_date, got {:?}",
+                        other
+                    ))
+                }
+            },
+            "--min-net-usd-cents" => {
+                let value = option_value(arg, &mut iter)?;
+                options.min_net_usd_cents = Some(parse_bounded_i64(arg, value, 0, i64::MAX)?);
+            }
+            "--dedup-keep" => options.dedup_keep = DedupKeep::parse(option_value(arg, &mut iter)?)?,
+            "--dedup-conflict" => {
+                options.dedup_conflict = DedupConflict::parse(option_value(arg, &mut iter)?)?
+            }
+            "--superseded-out" => {
+                options.superseded_out_path = Some(PathBuf::from(option_value(arg, &mut iter)?))
+            }
+            "--top-products" => {
+                let value = option_value(arg, &mut iter)?;
+                options.top_products = Some(parse_bounded_i64(arg, value, 1, i64::MAX)? as usize);
+            }
+            "--audit-versions" => {
+                options.audit_versions_path = Some(PathBuf::from(option_value(arg, &mut iter)?))
+            }
+            "--customer-day-out" => {
+                options.customer_day_out_path = Some(PathBuf::from(option_value(arg, &mut iter)?))
+            }
+            "--output-number-format" => {
+                options.output_number_format =
+                    OutputNumberFormat::parse(option_value(arg, &mut iter)?)?;
+            }
+            "--output-unit" => {
+                options.output_unit = OutputUnit::parse(option_value(arg, &mut iter)?)?
+            }
+            "--output-unit-rounding" => {
+                options.output_unit_rounding =
+                    Some(RoundingMode::parse(arg, option_value(arg, &mut iter)?)?)
+            }
+            "--verify" => options.verify = true,
+            "--canonical" => options.canonical = true,
+            "--run-id" => options.run_id = Some(parse_run_id(arg, option_value(arg, &mut iter)?)?),
+            "--run-id-column" => options.run_id_column = true,
+            "--atomic-output" => options.atomic_output = true,
+            #[cfg(feature = "checksum")]
+            "--checksum" => options.checksum_output = true,
+            #[cfg(not(feature = "checksum"))]
+            "--checksum" => {
+                return Err(format!(
+                    "{} requires building with --features checksum",
+                    arg
+                ));
+            }
+            "--shuffle-output" => {
+                let value = option_value(arg, &mut iter)?;
+                let seed = value.trim().parse::<u64>().map_err(|_| {
+                    format!(
+                        "{} expects a non-negative integer seed, got {:?}",
+                        arg, value
+                    )
+                })?;
+                options.shuffle_output_seed = Some(seed);
+            }
+            "--customer-tier-dim" => {
+                options.customer_tier_dim_path = Some(PathBuf::from(option_value(arg, &mut iter)?))
+            }
+            "--min-shipping-cents" => {
+                let value = option_value(arg, &mut iter)?;
+                options.min_shipping_cents = parse_bounded_i64(arg, value, -25_000, 0)?;
+            }
+            "--explain" => {
+                options.explain_event_id = Some(option_value(arg, &mut iter)?.trim().to_string())
+            }
+            "--fx-round-places" => {
+                let value = option_value(arg, &mut iter)?;
+                options.fx_round_places = Some(parse_bounded_i64(arg, value, 0, 6)? as u32);
+            }
+            "--heavy-categories" => options.heavy_categories.extend(
+                option_value(arg, &mut iter)?
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|category| !category.is_empty())
+                    .map(str::to_string),
+            ),
+            "--tiers" => {
+                let mut tiers: Vec<String> = Vec::new();
+                for tier in option_value(arg, &mut iter)?.split(',') {
+                    let tier = tier.trim().to_ascii_lowercase();
+                    if !tier.is_empty() && !tiers.contains(&tier) {
+                        tiers.push(tier);
+                    }
+                }
+                if tiers.is_empty() {
+                    return Err("--tiers needs at least one tier".to_string());
+                }
+                options.tiers = Some(tiers);
+            }
+            "--risk-buckets" => {
+                options.risk_buckets = Some(RiskBuckets::parse(option_value(arg, &mut iter)?)?)
+            }
+            "--dense" => {
+                let (dimension, domain) = parse_dense_domain(option_value(arg, &mut iter)?)?;
+                options.dense_domains.insert(dimension, domain);
+            }
+            "--vip-key" => options.vip_key = VipKey::parse(option_value(arg, &mut iter)?)?,
+            "--max-rows-per-file" => {
+                let value = option_value(arg, &mut iter)?;
+                options.max_rows_per_file =
+                    Some(parse_bounded_i64(arg, value, 1, i64::MAX)? as usize);
+            }
+            "--spill-dir" => options.spill_dir = Some(PathBuf::from(option_value(arg, &mut iter)?)),
+            "--spill-chunk-rows" => {
+                let value = option_value(arg, &mut iter)?;
+                options.spill_chunk_rows =
+                    Some(parse_bounded_i64(arg, value, 1, i64::MAX)? as usize);
+            }
+            "--skip-rows" => {
+                let value = option_value(arg, &mut iter)?;
+                options.skip_rows = parse_bounded_i64(arg, value, 0, i64::MAX)? as usize;
+            }
+            "--max-line-bytes" => {
+                let value = option_value(arg, &mut iter)?;
+                options.max_line_bytes = Some(parse_bounded_i64(arg, value, 1, i64::MAX)? as usize);
+            }
+            "--diff" => {
+                options.diff_baseline_path = Some(PathBuf::from(option_value(arg, &mut iter)?))
+            }
+            "--include-products" => {
+                options.include_products_path = Some(PathBuf::from(option_value(arg, &mut iter)?))
+            }
+            "--exclude-products" => {
+                options.exclude_products_path = Some(PathBuf::from(option_value(arg, &mut iter)?))
+            }
+            "--exclude-customers" => {
+                options.exclude_customers_path = Some(PathBuf::from(option_value(arg, &mut iter)?))
+            }
+            "--allow-missing-dims" => options.allow_missing_dims = true,
+            "--numeric-missing" => {
+                options.numeric_missing = NumericMissing::parse(option_value(arg, &mut iter)?)?
+            }
+            "--invalid-version" => {
+                options.invalid_version =
+                    InvalidVersionPolicy::parse(option_value(arg, &mut iter)?)?
+            }
+            "--progress" => options.progress = true,
+            "--events-delimiter" => {
+                options.events_delimiter =
+                    Some(parse_delimiter(arg, option_value(arg, &mut iter)?)?)
+            }
+            "--product-delimiter" => {
+                options.product_delimiter =
+                    Some(parse_delimiter(arg, option_value(arg, &mut iter)?)?)
+            }
+            "--country-delimiter" => {
+                options.country_delimiter =
+                    Some(parse_delimiter(arg, option_value(arg, &mut iter)?)?)
+            }
+            "--lenient-numbers" => {
+                let symbols = option_value(arg, &mut iter)?;
+                if symbols.is_empty() {
+                    return Err("--lenient-numbers needs at least one currency symbol".to_string());
+                }
+                options.input_format.currency_symbols = Some(symbols.to_string());
+            }
+            "--country-subtotals-dir" => {
+                options.country_subtotals_dir = Some(PathBuf::from(option_value(arg, &mut iter)?))
+            }
+            "--mark-missing-dims" => options.mark_missing_dims = true,
+            "--gross-local" => options.gross_local = true,
+            "--quantity-bins" => {
+                options.quantity_bins = Some(QuantityBins::parse(option_value(arg, &mut iter)?)?)
+            }
+            "--effective-tax" => options.effective_tax = true,
+            "--fail-on-empty" => options.fail_on_empty = true,
+            "--rounding" => {
+                options.rounding = RoundingMode::parse(arg, option_value(arg, &mut iter)?)?
+            }
+            "--fx-rounding" => {
+                options.fx_rounding = RoundingMode::parse(arg, option_value(arg, &mut iter)?)?
+            }
+            "--events-format" => {
+                options.events_format = EventsFormat::parse(option_value(arg, &mut iter)?)?
+            }
+            "--require-country-dim" => options.require_country_dim = true,
+            "--discount-applies-to-shipping" => {
+                options.discount_applies_to_shipping =
+                    Some(parse_bool(arg, option_value(arg, &mut iter)?)?)
+            }
+            "--tax-applies-to-shipping" => {
+                options.tax_applies_to_shipping =
+                    Some(parse_bool(arg, option_value(arg, &mut iter)?)?)
+            }
+            "--fx-key-column" => {
+                options.fx_key_column = Some(option_value(arg, &mut iter)?.to_string())
+            }
+            "--country-aliases" => {
+                options.country_aliases_path = Some(PathBuf::from(option_value(arg, &mut iter)?));
+            }
+            flag if flag.starts_with("--") => return Err(format!("unknown option: {}", flag)),
+            value => positional.push(value),
+        }
     }
-}
 
-fn split_csv_line(line: &str) -> Vec<&str> {
-    line.trim_end_matches(&['\r', '\n'][..]).split(',').collect()
-}
+    for delimiter in [
+        None,
+        options.events_delimiter,
+        options.product_delimiter,
+        options.country_delimiter,
+    ] {
+        options.input_format.with_delimiter(delimiter).validate()?;
+    }
 
-fn load_product_dim(dim_path: &Path) -> io::Result<HashMap<i64, ProductDim>> {
-    let file = File::open(dim_path)?;
-    let reader = BufReader::new(file);
+    if options.suppressed_other && options.min_order_count.is_none() {
+        return Err("--suppressed-other requires --min-order-count".to_string());
+    }
 
-    let mut product_map = HashMap::new();
+    if options.pivot.is_some() && options.columns.is_some() {
+        return Err("--pivot cannot be combined with --columns".to_string());
+    }
 
-    for (idx, line_res) in reader.lines().enumerate() {
-        let line = line_res?;
-        if idx == 0 || line.trim().is_empty() {
-            continue;
-        }
+    if let Some(flag) = options.collapsed_dimension_use() {
+        return Err(format!(
+            "{} needs its grouping column listed in --columns",
+            flag
+        ));
+    }
 
-        let cols = split_csv_line(&line);
-        if cols.len() < 4 {
-            continue;
-        }
+    if !matches!(options.output_format, OutputFormat::Parquet)
+        && !matches!(options.parquet_compression, ParquetCompression::Snappy)
+    {
+        return Err("--parquet-compression applies only to parquet output".to_string());
+    }
 
-        let product_id = parse_i64(cols[0]);
-        if product_id <= 0 {
-            continue;
-        }
+    if !matches!(options.output_format, OutputFormat::Csv)
+        && !matches!(options.output_number_format, OutputNumberFormat::Cents)
+    {
+        return Err("--output-number-format applies only to csv output".to_string());
+    }
 
-        let category_raw = cols[1].trim().to_ascii_lowercase();
-        let category = if category_raw.is_empty() {
-            "unknown".to_string()
-        } else {
-            category_raw
-        };
+    if matches!(options.output_unit, OutputUnit::Dollars)
+        && !matches!(options.output_number_format, OutputNumberFormat::Cents)
+    {
+        return Err(
+            "--output-unit dollars already writes whole dollars; drop --output-number-format"
+                .to_string(),
+        );
+    }
 
-        let margin_bps = clamp_i64(parse_i64(cols[2]), 0, 9500);
-        let weight_grams = clamp_i64(parse_i64(cols[3]), 1, 20_000);
+    if options.output_unit_rounding.is_some() && matches!(options.output_unit, OutputUnit::Cents) {
+        return Err("--output-unit-rounding applies only to --output-unit dollars".to_string());
+    }
 
-        product_map.insert(
-            product_id,
-            ProductDim {
-                category,
-                margin_bps,
-                weight_grams,
-            },
+    if options.risk_buckets.is_none() && options.references_risk_bucket() {
+        return Err("risk_bucket is only available with --risk-buckets".to_string());
+    }
+
+    if !matches!(options.dedup_conflict, DedupConflict::KeepFirst)
+        && !matches!(options.dedup_keep, DedupKeep::LatestVersion)
+    {
+        return Err("--dedup-conflict applies only to --dedup-keep latest-version".to_string());
+    }
+
+    if options.measures.is_some() && (options.columns.is_some() || options.pivot.is_some()) {
+        return Err("--measures cannot be combined with --columns or --pivot".to_string());
+    }
+
+    if options.canonical
+        && (options.columns.is_some()
+            || options.measures.is_some()
+            || options.pivot.is_some()
+            || !matches!(options.output_number_format, OutputNumberFormat::Cents)
+            || !matches!(options.output_unit, OutputUnit::Cents))
+    {
+        return Err(
+            "--canonical fixes the columns and formatting: drop --columns, --measures, --pivot, --output-number-format and --output-unit"
+                .to_string(),
         );
     }
 
-    Ok(product_map)
-}
+    if options.shuffle_output_seed.is_some()
+        && (options.pivot.is_some() || options.partition_by_date)
+    {
+        return Err(
+            "--shuffle-output cannot be combined with --pivot or --partition-by".to_string(),
+        );
+    }
 
-fn load_country_dim(dim_path: &Path) -> io::Result<HashMap<String, CountryDim>> {
-    let file = File::open(dim_path)?;
-    let reader = BufReader::new(file);
+    if matches!(options.date_from, DateSource::UtcTs)
+        && !matches!(options.date_ts_mismatch, DateTsMismatch::Keep)
+    {
+        return Err(
+            "--date-ts-mismatch checks the event_date column, which --date-from utc-ts replaces"
+                .to_string(),
+        );
+    }
 
-    let mut country_map = HashMap::new();
+    if options.pivot.is_some() && options.partition_by_date {
+        return Err("--pivot cannot be combined with --partition-by".to_string());
+    }
 
-    for (idx, line_res) in reader.lines().enumerate() {
-        let line = line_res?;
-        if idx == 0 || line.trim().is_empty() {
-            continue;
-        }
+    if options.pivot.is_some() && options.max_rows_per_file.is_some() {
+        return Err("--pivot cannot be combined with --max-rows-per-file".to_string());
+    }
 
-        let cols = split_csv_line(&line);
-        if cols.len() < 4 {
-            continue;
-        }
+    if options.run_id_column && (options.canonical || options.diff_baseline_path.is_some()) {
+        return Err(
+            "--run-id-column changes every row, so it cannot be combined with --canonical or --diff"
+                .to_string(),
+        );
+    }
 
-        let country = cols[0].trim().to_ascii_uppercase();
-        if country.is_empty() {
-            continue;
-        }
+    if options.diff_baseline_path.is_some()
+        && (options.pivot.is_some()
+            || options.partition_by_date
+            || options.max_rows_per_file.is_some()
+            || !matches!(options.output_format, OutputFormat::Csv)
+            || !matches!(options.output_number_format, OutputNumberFormat::Cents)
+            || !matches!(options.output_unit, OutputUnit::Cents))
+    {
+        return Err(
+            "--diff needs a single csv output in cents, without --pivot, --partition-by or --max-rows-per-file"
+                .to_string(),
+        );
+    }
 
-        let fx_to_usd_ppm = clamp_i64(parse_i64(cols[1]), 1, 2_500_000);
-        let risk_bps = clamp_i64(parse_i64(cols[2]), 1, 20_000);
-        let tax_bps = clamp_i64(parse_i64(cols[3]), 0, 5_000);
+    if options.include_products_path.is_some() && options.exclude_products_path.is_some() {
+        return Err("--include-products and --exclude-products are mutually exclusive".to_string());
+    }
 
-        country_map.insert(
-            country,
-            CountryDim {
-                fx_to_usd_ppm,
-                risk_bps,
-                tax_bps,
-            },
+    if options.pivot.is_some() && options.country_subtotals_dir.is_some() {
+        return Err("--pivot cannot be combined with --country-subtotals-dir".to_string());
+    }
+
+    if options.lowercase_output_category && !options.preserve_category_case {
+        return Err(
+            "--lowercase-output-category requires --preserve-category-case; categories are lowercased at load otherwise"
+                .to_string(),
         );
     }
 
-    Ok(country_map)
-}
+    if options.spill_chunk_rows.is_some() && options.spill_dir.is_none() {
+        return Err("--spill-chunk-rows requires --spill-dir".to_string());
+    }
 
-fn transform(
-    events_path: &Path,
-    product_dim_path: &Path,
-    country_dim_path: &Path,
-    output_path: &Path,
-) -> io::Result<(i64, i64, i64)> {
-    let product_map = load_product_dim(product_dim_path)?;
-    let country_map = load_country_dim(country_dim_path)?;
+    if let Some(flag) = options.spill_dir.as_ref().and(options.spill_conflict()) {
+        return Err(format!("--spill-dir cannot be combined with {}", flag));
+    }
 
-    let input_file = File::open(events_path)?;
-    let reader = BufReader::new(input_file);
+    if options.gross_local && !options.groups_by_country() {
+        return Err(
+            "--gross-local needs country in the group key: keep country in --columns and drop --pivot and --suppressed-other"
+                .to_string(),
+        );
+    }
 
-    let mut dedup: HashMap<String, EventRecord> = HashMap::new();
+    if options.effective_tax && !options.groups_by_country() {
+        return Err(
+            "--effective-tax needs country in the group key: keep country in --columns and drop --pivot and --suppressed-other"
+                .to_string(),
+        );
+    }
 
-    let mut raw_rows = 0_i64;
-    let mut filtered_rows = 0_i64;
+    if options.run_id.is_none() {
+        options.run_id = Some(generate_run_id());
+    }
 
-    for (idx, line_res) in reader.lines().enumerate() {
-        let line = line_res?;
-        if idx == 0 || line.trim().is_empty() {
-            continue;
-        }
+    if positional.len() != 4 {
+        return Err(format!(
+            "expected 4 positional arguments, got {}",
+            positional.len()
+        ));
+    }
 
-        raw_rows += 1;
-        let cols = split_csv_line(&line);
-        if cols.len() < 14 {
-            continue;
-        }
+    Ok(CliArgs {
+        events_path: PathBuf::from(positional[0]),
+        product_dim_path: PathBuf::from(positional[1]),
+        country_dim_path: PathBuf::from(positional[2]),
+        output_path: PathBuf::from(positional[3]),
+        options,
+        config_path,
+        metrics_path,
+        quiet,
+        print_config,
+        verify_idempotent,
+        #[cfg(feature = "watch")]
+        watch,
+    })
+}
 
-        let event_id = cols[0].trim();
-        if event_id.is_empty() {
-            continue;
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let program = args.first().map_or("process_rust", String::as_str);
+
+    let cli = match parse_args(args.get(1..).unwrap_or_default()) {
+        Ok(cli) => cli,
+        Err(message) => {
+            eprintln!("{}", message);
+            eprintln!(
+                "Usage: {} [options] <events_csv> <product_dim_csv> <country_dim_csv> <output_csv>",
+                program
+            );
+            std::process::exit(1);
         }
+    };
 
-        let event_version = parse_i64(cols[1]);
-        let event_ts = cols[2].trim();
-        let event_date = cols[3].trim();
-        let customer_id = parse_i64(cols[4]);
-        let product_id = parse_i64(cols[5]);
-        let amount_cents = parse_i64(cols[6]);
-        let quantity = parse_i64(cols[7]);
-        let discount_bps = clamp_i64(parse_i64(cols[8]), 0, 5000);
-        let shipping_cents = clamp_i64(parse_i64(cols[9]), 0, 25_000);
-        let status = cols[10].trim().to_ascii_uppercase();
-        let country = cols[11].trim().to_ascii_uppercase();
-
-        let customer_tier_raw = cols[12].trim().to_ascii_lowercase();
-        let customer_tier = match customer_tier_raw.as_str() {
-            "bronze" | "silver" | "gold" | "platinum" => customer_tier_raw,
-            _ => "unknown".to_string(),
-        };
+    if cli.print_config {
+        print_config(&cli);
+    }
 
-        if status != "COMPLETE" || amount_cents <= 0 || quantity <= 0 {
-            continue;
-        }
-        if customer_id <= 0 || product_id <= 0 || event_date.is_empty() || event_ts.is_empty() {
-            continue;
+    #[cfg(feature = "watch")]
+    if cli.watch {
+        if let Err(err) = watch::watch(&cli) {
+            eprintln!("rust transform watch failed | {}", err);
+            std::process::exit(1);
         }
+        return;
+    }
 
-        filtered_rows += 1;
-
-        let candidate = EventRecord {
-            event_version,
-            event_ts: event_ts.to_string(),
-            event_date: event_date.to_string(),
-            customer_id,
-            product_id,
-            amount_cents,
-            quantity,
-            discount_bps,
-            shipping_cents,
-            country,
-            customer_tier,
-        };
+    #[cfg(feature = "signals")]
+    let cli = with_interrupt_handler(cli);
 
-        let should_replace = match dedup.get(event_id) {
-            Some(current) => {
-                candidate.event_version > current.event_version
-                    || (candidate.event_version == current.event_version
-                        && candidate.event_ts > current.event_ts)
-            }
-            None => true,
+    if let Err(err) = run(&cli) {
+        eprintln!("rust transform failed | {}", err);
+        // 130 is the shell's code for a run ended by SIGINT.
+        let code = if err.kind() == io::ErrorKind::Interrupted {
+            130
+        } else {
+            1
         };
+        std::process::exit(code);
+    }
+}
 
-        if should_replace {
-            dedup.insert(event_id.to_string(), candidate);
+/// The first Ctrl-C stops the run; a second exits at once.
+#[cfg(feature = "signals")]
+fn with_interrupt_handler(mut cli: CliArgs) -> CliArgs {
+    let interrupt = Arc::new(AtomicBool::new(false));
+    let handler_interrupt = Arc::clone(&interrupt);
+    let installed = ctrlc::set_handler(move || {
+        if handler_interrupt.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
         }
+    });
+    match installed {
+        Ok(()) => cli.options.interrupt = Some(interrupt),
+        Err(err) => eprintln!("SIGINT handler not installed: {}", err),
     }
+    cli
+}
 
-    let mut customer_day_spend: HashMap<(String, i64), i64> = HashMap::new();
-    let mut enriched_rows: Vec<DerivedRecord> = Vec::with_capacity(dedup.len());
-
-    for record in dedup.values() {
-        let product = product_map.get(&record.product_id).cloned().unwrap_or(ProductDim {
-            category: "unknown".to_string(),
-            margin_bps: 2500,
-            weight_grams: 500,
-        });
-
-        let country_factor = country_map
-            .get(&record.country)
-            .cloned()
-            .unwrap_or(CountryDim {
-                fx_to_usd_ppm: 1_000_000,
-                risk_bps: 10_000,
-                tax_bps: 0,
-            });
-
-        let gross_local_cents = record.amount_cents * record.quantity + record.shipping_cents;
-        let discount_local_cents = round_div(gross_local_cents * record.discount_bps, 10_000);
-        let taxable_local_cents = std::cmp::max(gross_local_cents - discount_local_cents, 0);
-        let tax_local_cents = round_div(taxable_local_cents * country_factor.tax_bps, 10_000);
-        let net_local_cents = taxable_local_cents + tax_local_cents;
-
-        let net_usd_cents = round_div(net_local_cents * country_factor.fx_to_usd_ppm, 1_000_000);
-        let cost_usd_cents = round_div(net_usd_cents * (10_000 - product.margin_bps), 10_000);
-        let profit_usd_cents = net_usd_cents - cost_usd_cents;
-        let risk_adjusted_usd_cents = round_div(net_usd_cents * country_factor.risk_bps, 10_000);
-
-        let hour = parse_event_hour(&record.event_ts);
-        let time_bucket = time_bucket_from_hour(hour);
-        let size_bucket = order_size_bucket(record.quantity);
-        let heavy_item_order = if product.weight_grams * record.quantity >= 5_000 {
-            1
-        } else {
-            0
-        };
+/// Reruns into a scratch directory and fails unless the output is byte-identical.
+fn verify_idempotent(cli: &CliArgs) -> io::Result<()> {
+    if !std::fs::metadata(&cli.events_path).is_ok_and(|metadata| metadata.is_file()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--verify-idempotent needs the events input to be a regular file it can read twice",
+        ));
+    }
+    let output_name = cli
+        .output_path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "output path has no name"))?;
+    let scratch_dir = cli.output_path.with_file_name(format!(
+        ".{}.idempotency-check",
+        output_name.to_string_lossy()
+    ));
+    let rerun_path = scratch_dir.join(output_name);
+
+    let rerun = transform(
+        &cli.events_path,
+        &cli.product_dim_path,
+        &cli.country_dim_path,
+        &rerun_path,
+        &cli.options,
+    )
+    .and_then(|_| compare_outputs(&cli.output_path, &rerun_path));
+    std::fs::remove_dir_all(&scratch_dir)?;
+    rerun
+}
 
-        let customer_day_key = (record.event_date.clone(), record.customer_id);
-        *customer_day_spend.entry(customer_day_key).or_insert(0) += net_usd_cents;
-
-        enriched_rows.push(DerivedRecord {
-            event_date: record.event_date.clone(),
-            customer_id: record.customer_id,
-            customer_tier: record.customer_tier.clone(),
-            category: product.category,
-            country: record.country.clone(),
-            time_bucket,
-            order_size_bucket: size_bucket,
-            quantity: record.quantity,
-            net_usd_cents,
-            profit_usd_cents,
-            risk_adjusted_usd_cents,
-            heavy_item_order,
-        });
-    }
-
-    let mut aggregated: HashMap<(String, String, String, String, String, String), AggregateRecord> =
-        HashMap::new();
-
-    for row in &enriched_rows {
-        let vip_customer_order = match customer_day_spend.get(&(row.event_date.clone(), row.customer_id)) {
-            Some(total) if *total >= 50_000 => 1,
-            _ => 0,
-        };
+fn compare_outputs(first: &Path, second: &Path) -> io::Result<()> {
+    let differs = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "--verify-idempotent: a second run produced different output at {}",
+                first.display()
+            ),
+        )
+    };
+    if first.is_dir() {
+        let mut names: Vec<_> = std::fs::read_dir(first)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<io::Result<_>>()?;
+        let mut second_names: Vec<_> = std::fs::read_dir(second)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<io::Result<_>>()?;
+        names.sort();
+        second_names.sort();
+        if names != second_names {
+            return Err(differs());
+        }
+        for name in names {
+            compare_outputs(&first.join(&name), &second.join(&name))?;
+        }
+        return Ok(());
+    }
+    if second.is_dir() || std::fs::read(first)? != std::fs::read(second)? {
+        return Err(differs());
+    }
+    Ok(())
+}
 
-        let key = (
-            row.event_date.clone(),
-            row.customer_tier.clone(),
-            row.category.clone(),
-            row.country.clone(),
-            row.time_bucket.clone(),
-            row.order_size_bucket.clone(),
-        );
+fn print_config(cli: &CliArgs) {
+    let path = |path: &PathBuf| json_string(&path.display().to_string());
+    let mut config = vec![
+        ("events_path", path(&cli.events_path)),
+        ("product_dim_path", path(&cli.product_dim_path)),
+        ("country_dim_path", path(&cli.country_dim_path)),
+        ("output_path", path(&cli.output_path)),
+        (
+            "config_path",
+            cli.config_path
+                .as_ref()
+                .map_or_else(|| "null".to_string(), path),
+        ),
+        (
+            "metrics_path",
+            cli.metrics_path
+                .as_ref()
+                .map_or_else(|| "null".to_string(), path),
+        ),
+        ("quiet", cli.quiet.to_string()),
+        ("verify_idempotent", cli.verify_idempotent.to_string()),
+    ];
+    #[cfg(feature = "watch")]
+    config.push(("watch", cli.watch.to_string()));
+    config.extend(effective_config(&cli.options));
+
+    let fields: Vec<String> = config
+        .iter()
+        .map(|(key, value)| format!("  {}: {}", json_string(key), value))
+        .collect();
+    println!("{{\n{}\n}}", fields.join(",\n"));
+}
 
-        let entry = aggregated.entry(key).or_default();
-        entry.order_count += 1;
-        entry.vip_customer_orders += vip_customer_order;
-        entry.total_quantity += row.quantity;
-        entry.total_net_usd_cents += row.net_usd_cents;
-        entry.total_profit_usd_cents += row.profit_usd_cents;
-        entry.total_risk_adjusted_usd_cents += row.risk_adjusted_usd_cents;
-        entry.total_items += row.quantity;
-        entry.heavy_item_orders += row.heavy_item_order;
-    }
-
-    let mut rows: Vec<_> = aggregated.into_iter().collect();
-    rows.sort_by(|a, b| {
-        a.0 .0
-            .cmp(&b.0 .0)
-            .then(a.0 .1.cmp(&b.0 .1))
-            .then(a.0 .2.cmp(&b.0 .2))
-            .then(a.0 .3.cmp(&b.0 .3))
-            .then(a.0 .4.cmp(&b.0 .4))
-            .then(a.0 .5.cmp(&b.0 .5))
-    });
+fn run(cli: &CliArgs) -> io::Result<()> {
+    let output_path = cli.output_path.as_path();
 
-    let output_file = File::create(output_path)?;
-    let mut writer = BufWriter::new(output_file);
+    // transform checks its own outputs; the metrics file is written here.
+    if let Some(metrics_path) = &cli.metrics_path {
+        ensure_writable_file(metrics_path, "metrics")?;
+    }
 
-    writeln!(
-        writer,
-        "event_date,customer_tier,category,country,time_bucket,order_size_bucket,order_count,vip_customer_orders,total_quantity,total_net_usd_cents,total_profit_usd_cents,total_risk_adjusted_usd_cents,avg_item_price_usd_cents,heavy_item_orders"
+    let started = Instant::now();
+    let summary = transform(
+        &cli.events_path,
+        &cli.product_dim_path,
+        &cli.country_dim_path,
+        output_path,
+        &cli.options,
     )?;
+    let elapsed_sec = started.elapsed().as_secs_f64();
+    let rows_per_sec = if elapsed_sec > 0.0 {
+        summary.raw_rows as f64 / elapsed_sec
+    } else {
+        0.0
+    };
+    // Timing covers the first run only.
+    if cli.verify_idempotent {
+        verify_idempotent(cli)?;
+    }
+
+    if let Some(metrics_path) = &cli.metrics_path {
+        write_metrics(metrics_path, &summary, elapsed_sec, rows_per_sec)?;
+    }
+    if cli.quiet {
+        return Ok(());
+    }
+
+    println!("{}", summary_line(cli, &summary, elapsed_sec, rows_per_sec));
+    if cli.options.max_rows_per_file.is_some() {
+        for path in &summary.output_files {
+            println!("wrote {}", path.display());
+        }
+    }
 
-    for ((event_date, customer_tier, category, country, time_bucket, order_size_bucket), agg) in rows {
-        let avg_item_price_usd_cents = round_div(agg.total_net_usd_cents, agg.total_items);
-
-        writeln!(
-            writer,
-            "{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
-            event_date,
-            customer_tier,
-            category,
-            country,
-            time_bucket,
-            order_size_bucket,
-            agg.order_count,
-            agg.vip_customer_orders,
-            agg.total_quantity,
-            agg.total_net_usd_cents,
-            agg.total_profit_usd_cents,
-            agg.total_risk_adjusted_usd_cents,
-            avg_item_price_usd_cents,
-            agg.heavy_item_orders
-        )?;
-    }
-
-    Ok((raw_rows, filtered_rows, dedup.len() as i64))
+    Ok(())
 }
 
-fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 5 {
-        eprintln!(
-            "Usage: {} <events_csv> <product_dim_csv> <country_dim_csv> <output_csv>",
-            args.get(0).map_or("process_rust", String::as_str)
+fn summary_line(
+    cli: &CliArgs,
+    summary: &TransformSummary,
+    elapsed_sec: f64,
+    rows_per_sec: f64,
+) -> String {
+    let mut line = format!(
+        "rust transform completed | raw_rows={} filtered_rows={} dedup_rows={} superseded_rows={} unknown_tier_rows={} full_discount_rows={} rejects={}",
+        summary.raw_rows,
+        summary.filtered_rows,
+        summary.dedup_rows,
+        summary.superseded_rows,
+        summary.unknown_tier_rows,
+        summary.full_discount_rows,
+        format_reject_counts(&summary.reject_counts),
+    );
+    if summary.invalid_version_rows > 0 {
+        line.push_str(&format!(
+            " invalid_version_rows={}",
+            summary.invalid_version_rows
+        ));
+    }
+    if summary.skipped_rows > 0 {
+        line.push_str(&format!(" skipped_rows={}", summary.skipped_rows));
+    }
+    if summary.repeated_header_rows > 0 {
+        line.push_str(&format!(
+            " repeated_header_rows={}",
+            summary.repeated_header_rows
+        ));
+    }
+    if cli.options.min_order_count.is_some() {
+        line.push_str(&format!(
+            " suppressed_groups={} suppressed_orders={}",
+            summary.suppressed_groups, summary.suppressed_orders
+        ));
+    }
+    if cli.options.min_net_usd_cents.is_some() {
+        line.push_str(&format!(" negligible_groups={}", summary.negligible_groups));
+    }
+    if !cli.options.dense_domains.is_empty() {
+        line.push_str(&format!(" dense_groups={}", summary.dense_groups));
+    }
+    if let Some(diff) = &summary.diff {
+        line.push_str(&format!(
+            " diff_added={} diff_removed={} diff_changed={}",
+            diff.added_groups, diff.removed_groups, diff.changed_groups
+        ));
+    }
+    line.push_str(&format!(
+        " elapsed_sec={:.3} rows_per_sec={:.0} output={}",
+        elapsed_sec,
+        rows_per_sec,
+        cli.output_path.display()
+    ));
+    if let Some(run_id) = &summary.run_id {
+        line.push_str(&format!(" run_id={}", run_id));
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    /// Writes one-order inputs under a fresh temp dir and returns its path.
+    fn write_inputs(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("process_rust-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("events.csv"),
+            "event_id,event_version,event_ts,event_date,customer_id,product_id,amount_cents,\
+             quantity,discount_bps,shipping_cents,status,country,customer_tier,payment_method\n\
+             E1,1,2025-01-24T07:21:55,2025-01-24,1,1,1000,1,0,0,COMPLETE,US,gold,card\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("products.csv"),
+            "product_id,category,margin_bps,weight_grams\n1,apparel,2000,500\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("countries.csv"),
+            "country,fx_to_usd_ppm,risk_bps,tax_bps\nUS,1000000,10000,850\n",
+        )
+        .unwrap();
+        dir
+    }
+
+    #[test]
+    fn verify_idempotent_reruns_and_cleans_up() {
+        let dir = write_inputs("verify-idempotent");
+        let path = |name: &str| dir.join(name).display().to_string();
+        let cli = parse_args(&args(&[
+            "--verify-idempotent",
+            "--quiet",
+            &path("events.csv"),
+            &path("products.csv"),
+            &path("countries.csv"),
+            &path("out.csv"),
+        ]))
+        .unwrap();
+
+        let outcome = run(&cli);
+        let mut names: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        outcome.unwrap();
+        assert_eq!(
+            names,
+            ["countries.csv", "events.csv", "out.csv", "products.csv"]
+        );
+    }
+
+    #[test]
+    fn run_id_override_reaches_the_summary_metrics_and_output() {
+        let dir = write_inputs("run-id");
+        let path = |name: &str| dir.join(name).display().to_string();
+        let cli = parse_args(&args(&[
+            "--run-id",
+            "nightly-42",
+            "--run-id-column",
+            "--metrics-file",
+            &path("metrics.json"),
+            "--quiet",
+            &path("events.csv"),
+            &path("products.csv"),
+            &path("countries.csv"),
+            &path("out.csv"),
+        ]))
+        .unwrap();
+        let outcome = run(&cli);
+        let output = std::fs::read_to_string(dir.join("out.csv"));
+        let metrics = std::fs::read_to_string(dir.join("metrics.json"));
+        let summary = transform(
+            &cli.events_path,
+            &cli.product_dim_path,
+            &cli.country_dim_path,
+            &cli.output_path,
+            &cli.options,
         );
-        std::process::exit(1);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        outcome.unwrap();
+        let output = output.unwrap();
+        let (header, row) = output.split_once('\n').unwrap();
+        assert!(header.ends_with(",run_id"));
+        assert!(row.trim_end().ends_with(",nightly-42"));
+        assert!(metrics.unwrap().contains("\"run_id\": \"nightly-42\","));
+        let line = summary_line(&cli, &summary.unwrap(), 0.0, 0.0);
+        assert!(line.ends_with(" run_id=nightly-42"));
     }
 
-    let events_path = Path::new(&args[1]);
-    let product_dim_path = Path::new(&args[2]);
-    let country_dim_path = Path::new(&args[3]);
-    let output_path = Path::new(&args[4]);
+    #[test]
+    fn compare_outputs_reports_a_difference() {
+        let dir = write_inputs("compare-outputs");
+        std::fs::write(dir.join("a.csv"), "x\n1\n").unwrap();
+        std::fs::write(dir.join("b.csv"), "x\n2\n").unwrap();
+        let outcome = compare_outputs(&dir.join("a.csv"), &dir.join("b.csv"));
+        std::fs::remove_dir_all(&dir).unwrap();
 
-    if let Some(parent) = output_path.parent() {
-        std::fs::create_dir_all(parent)?;
+        assert_eq!(outcome.unwrap_err().kind(), io::ErrorKind::InvalidData);
     }
 
-    let (raw_rows, filtered_rows, dedup_rows) =
-        transform(events_path, product_dim_path, country_dim_path, output_path)?;
+    #[test]
+    fn config_file_values_merge_under_command_line_flags() {
+        let path = env::temp_dir().join(format!("process_rust-config-{}.conf", std::process::id()));
+        std::fs::write(
+            &path,
+            "# nightly run\n--min-order-count 5\n--unknown-label not known\n\n--quiet\n",
+        )
+        .unwrap();
+        let config = path.display().to_string();
+        let cli = parse_args(&args(&[
+            "--config",
+            &config,
+            "--min-order-count",
+            "7",
+            "events.csv",
+            "products.csv",
+            "countries.csv",
+            "out.csv",
+        ]));
+        std::fs::remove_file(&path).unwrap();
+        let cli = cli.unwrap();
+
+        assert_eq!(cli.config_path, Some(path));
+        assert!(cli.quiet);
+        assert_eq!(cli.options.min_order_count, Some(7));
+        assert_eq!(cli.options.unknown_label.as_deref(), Some("not known"));
+    }
 
-    println!(
-        "rust transform completed | raw_rows={} filtered_rows={} dedup_rows={} output={}",
-        raw_rows,
-        filtered_rows,
-        dedup_rows,
-        output_path.display()
-    );
+    #[test]
+    fn config_file_cannot_hold_positional_arguments() {
+        let path = env::temp_dir().join(format!(
+            "process_rust-config-positional-{}.conf",
+            std::process::id()
+        ));
+        std::fs::write(&path, "events.csv\n").unwrap();
+        let config = path.display().to_string();
+        let err = read_config_file(Path::new(&config)).err();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(err.is_some_and(|err| err.contains("line 1")));
+    }
 
-    Ok(())
+    #[test]
+    fn category_margin_stays_within_the_dim_range() {
+        let parse = |value: &str| {
+            parse_args(&args(&[
+                "--category-margin",
+                value,
+                "events.csv",
+                "products.csv",
+                "countries.csv",
+                "out.csv",
+            ]))
+        };
+
+        assert!(parse("books=-1").is_err());
+        assert!(parse("books=9501").is_err());
+        assert_eq!(
+            parse("Books=0").unwrap().options.category_margins["books"],
+            0
+        );
+        assert_eq!(
+            parse("books=9500").unwrap().options.category_margins["books"],
+            9500
+        );
+    }
+
+    #[test]
+    fn spill_dir_rejects_flags_that_need_every_group() {
+        let err = parse_args(&args(&[
+            "--spill-dir",
+            "spill",
+            "--min-order-count",
+            "5",
+            "events.csv",
+            "products.csv",
+            "countries.csv",
+            "out.csv",
+        ]))
+        .err();
+
+        assert_eq!(
+            err.as_deref(),
+            Some("--spill-dir cannot be combined with --min-order-count")
+        );
+    }
+
+    #[test]
+    fn gross_local_needs_country_in_the_group_key() {
+        let parse = |extra: &[&str]| {
+            let mut values = vec!["--gross-local"];
+            values.extend_from_slice(extra);
+            values.extend(["events.csv", "products.csv", "countries.csv", "out.csv"]);
+            parse_args(&args(&values))
+        };
+
+        assert!(parse(&[]).is_ok());
+        assert!(parse(&["--columns", "country,order_count"]).is_ok());
+        assert!(parse(&["--columns", "category,order_count"])
+            .err()
+            .is_some_and(|err| err.starts_with("--gross-local needs country")));
+        assert!(parse(&["--suppressed-other", "--min-order-count", "2"])
+            .err()
+            .is_some_and(|err| err.starts_with("--gross-local needs country")));
+    }
+
+    #[test]
+    fn effective_tax_rejects_groups_spanning_countries() {
+        let parse = |extra: &[&str]| {
+            let mut values = vec!["--effective-tax"];
+            values.extend_from_slice(extra);
+            values.extend(["events.csv", "products.csv", "countries.csv", "out.csv"]);
+            parse_args(&args(&values))
+        };
+
+        assert!(parse(&["--columns", "country,order_count"]).is_ok());
+        assert!(parse(&["--columns", "event_date,order_count"])
+            .err()
+            .is_some_and(|err| err.starts_with("--effective-tax needs country")));
+    }
 }
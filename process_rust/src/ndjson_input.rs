@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::normalize_field;
+
+/// Event fields in CSV column order.
+const EVENT_FIELDS: [&str; 14] = [
+    "event_id",
+    "event_version",
+    "event_ts",
+    "event_date",
+    "customer_id",
+    "product_id",
+    "amount_cents",
+    "quantity",
+    "discount_bps",
+    "shipping_cents",
+    "status",
+    "country",
+    "customer_tier",
+    "payment_method",
+];
+
+/// `None` unless the line is a flat JSON object; missing and `null` fields are empty.
+pub(crate) fn parse_event_fields(line: &str, extra_field: Option<&str>) -> Option<Vec<String>> {
+    let mut object = parse_flat_object(line)?;
+    Some(
+        EVENT_FIELDS
+            .iter()
+            .copied()
+            .chain(extra_field)
+            .map(|name| {
+                let value = object.remove(name).unwrap_or_default();
+                normalize_field(&value).to_string()
+            })
+            .collect(),
+    )
+}
+
+fn parse_flat_object(line: &str) -> Option<HashMap<String, String>> {
+    let mut chars = line.chars().peekable();
+    let mut object = HashMap::new();
+
+    skip_whitespace(&mut chars);
+    expect(&mut chars, '{')?;
+    skip_whitespace(&mut chars);
+    if chars.peek() == Some(&'}') {
+        chars.next();
+    } else {
+        loop {
+            skip_whitespace(&mut chars);
+            expect(&mut chars, '"')?;
+            let key = parse_string(&mut chars)?;
+            skip_whitespace(&mut chars);
+            expect(&mut chars, ':')?;
+            skip_whitespace(&mut chars);
+            let value = parse_scalar(&mut chars)?;
+            object.insert(key, value);
+            skip_whitespace(&mut chars);
+            match chars.next()? {
+                ',' => continue,
+                '}' => break,
+                _ => return None,
+            }
+        }
+    }
+
+    skip_whitespace(&mut chars);
+    chars.next().is_none().then_some(object)
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars<'_>>) {
+    while chars.peek().is_some_and(|ch| ch.is_whitespace()) {
+        chars.next();
+    }
+}
+
+fn expect(chars: &mut Peekable<Chars<'_>>, expected: char) -> Option<()> {
+    (chars.next()? == expected).then_some(())
+}
+
+/// Nested objects and arrays reject the line.
+fn parse_scalar(chars: &mut Peekable<Chars<'_>>) -> Option<String> {
+    match *chars.peek()? {
+        '"' => {
+            chars.next();
+            parse_string(chars)
+        }
+        '{' | '[' => None,
+        _ => {
+            let mut literal = String::new();
+            while let Some(&ch) = chars.peek() {
+                if ch == ',' || ch == '}' || ch.is_whitespace() {
+                    break;
+                }
+                literal.push(ch);
+                chars.next();
+            }
+            match literal.as_str() {
+                "" => None,
+                "null" => Some(String::new()),
+                _ => Some(literal),
+            }
+        }
+    }
+}
+
+fn parse_string(chars: &mut Peekable<Chars<'_>>) -> Option<String> {
+    let mut out = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(out),
+            '\\' => match chars.next()? {
+                '"' => out.push('"'),
+                '\\' => out.push('\\'),
+                '/' => out.push('/'),
+                'b' => out.push('\u{8}'),
+                'f' => out.push('\u{c}'),
+                'n' => out.push('\n'),
+                'r' => out.push('\r'),
+                't' => out.push('\t'),
+                'u' => {
+                    let high = parse_hex4(chars)?;
+                    let code = if (0xD800..0xDC00).contains(&high) {
+                        expect(chars, '\\')?;
+                        expect(chars, 'u')?;
+                        let low = parse_hex4(chars)?;
+                        if !(0xDC00..0xE000).contains(&low) {
+                            return None;
+                        }
+                        0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+                    } else {
+                        high
+                    };
+                    out.push(char::from_u32(code)?);
+                }
+                _ => return None,
+            },
+            ch => out.push(ch),
+        }
+    }
+}
+
+fn parse_hex4(chars: &mut Peekable<Chars<'_>>) -> Option<u32> {
+    let mut code = 0;
+    for _ in 0..4 {
+        code = code * 16 + chars.next()?.to_digit(16)?;
+    }
+    Some(code)
+}
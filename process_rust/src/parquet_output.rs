@@ -0,0 +1,137 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use parquet::basic::{
+    Compression, GzipLevel, LogicalType, Repetition, Type as PhysicalType, ZstdLevel,
+};
+use parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::types::Type;
+
+use crate::{CellValue, ColumnKind, OutputColumn, ParquetCompression, RowSink};
+
+fn parquet_error(err: ParquetError) -> io::Error {
+    io::Error::other(format!("parquet: {}", err))
+}
+
+enum ColumnBuffer {
+    Text(Vec<ByteArray>),
+    Int(Vec<i64>),
+}
+
+/// Buffers the rows and writes one Parquet row group on finish.
+pub(crate) struct ParquetSink {
+    file: File,
+    compression: Compression,
+    schema: Option<Arc<Type>>,
+    buffers: Vec<ColumnBuffer>,
+}
+
+impl ParquetSink {
+    pub(crate) fn create(output_path: &Path, compression: ParquetCompression) -> io::Result<Self> {
+        let compression = match compression {
+            ParquetCompression::None => Compression::UNCOMPRESSED,
+            ParquetCompression::Snappy => Compression::SNAPPY,
+            ParquetCompression::Zstd => Compression::ZSTD(ZstdLevel::default()),
+            ParquetCompression::Gzip => Compression::GZIP(GzipLevel::default()),
+        };
+        Ok(ParquetSink {
+            file: File::create(output_path)?,
+            compression,
+            schema: None,
+            buffers: Vec::new(),
+        })
+    }
+}
+
+impl RowSink for ParquetSink {
+    fn write_header(&mut self, columns: &[(String, &'static OutputColumn)]) -> io::Result<()> {
+        let mut fields = Vec::with_capacity(columns.len());
+        for (name, column) in columns {
+            let (physical_type, logical_type, buffer) = match column.kind {
+                ColumnKind::String => (
+                    PhysicalType::BYTE_ARRAY,
+                    Some(LogicalType::String),
+                    ColumnBuffer::Text(Vec::new()),
+                ),
+                ColumnKind::Int64 => (PhysicalType::INT64, None, ColumnBuffer::Int(Vec::new())),
+            };
+            let field = Type::primitive_type_builder(name, physical_type)
+                .with_repetition(Repetition::REQUIRED)
+                .with_logical_type(logical_type)
+                .build()
+                .map_err(parquet_error)?;
+            fields.push(Arc::new(field));
+            self.buffers.push(buffer);
+        }
+
+        let schema = Type::group_type_builder("schema")
+            .with_fields(fields)
+            .build()
+            .map_err(parquet_error)?;
+        self.schema = Some(Arc::new(schema));
+        Ok(())
+    }
+
+    fn write_row(&mut self, values: &[CellValue<'_>]) -> io::Result<()> {
+        for (buffer, value) in self.buffers.iter_mut().zip(values) {
+            match (buffer, value) {
+                (ColumnBuffer::Text(cells), CellValue::Text(text)) => {
+                    cells.push(ByteArray::from(*text))
+                }
+                (ColumnBuffer::Int(cells), CellValue::Int(number)) => cells.push(*number),
+                (ColumnBuffer::Text(cells), CellValue::Int(number)) => {
+                    cells.push(ByteArray::from(number.to_string().as_str()))
+                }
+                (ColumnBuffer::Int(_), CellValue::Text(_)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "parquet: text value in an int64 column",
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        let sink = *self;
+        let schema = sink
+            .schema
+            .ok_or_else(|| io::Error::other("parquet: rows finished before the header"))?;
+        let properties = Arc::new(
+            WriterProperties::builder()
+                .set_compression(sink.compression)
+                .build(),
+        );
+
+        let mut writer =
+            SerializedFileWriter::new(sink.file, schema, properties).map_err(parquet_error)?;
+        let mut row_group = writer.next_row_group().map_err(parquet_error)?;
+        for buffer in &sink.buffers {
+            let mut column = row_group
+                .next_column()
+                .map_err(parquet_error)?
+                .ok_or_else(|| {
+                    io::Error::other("parquet: schema has fewer columns than buffers")
+                })?;
+            match buffer {
+                ColumnBuffer::Text(cells) => column
+                    .typed::<ByteArrayType>()
+                    .write_batch(cells, None, None),
+                ColumnBuffer::Int(cells) => {
+                    column.typed::<Int64Type>().write_batch(cells, None, None)
+                }
+            }
+            .map_err(parquet_error)?;
+            column.close().map_err(parquet_error)?;
+        }
+        row_group.close().map_err(parquet_error)?;
+        writer.close().map_err(parquet_error)?;
+        Ok(())
+    }
+}
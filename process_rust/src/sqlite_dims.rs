@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+
+use rusqlite::types::Value;
+use rusqlite::{Connection, OpenFlags};
+
+use crate::{
+    normalize_field, parse_country_row, parse_product_row, CountryDim, InputFormat, ProductDim,
+    TransformOptions,
+};
+
+/// Each column list maps database columns onto the CSV dim layout.
+pub struct SqliteDimSource {
+    pub db_path: PathBuf,
+    pub product_columns: [String; 4],
+    pub country_columns: [String; 4],
+}
+
+impl SqliteDimSource {
+    pub fn new(db_path: PathBuf) -> Self {
+        SqliteDimSource {
+            db_path,
+            product_columns: ["product_id", "category", "margin_bps", "weight_grams"]
+                .map(String::from),
+            country_columns: ["country", "fx_to_usd_ppm", "risk_bps", "tax_bps"].map(String::from),
+        }
+    }
+}
+
+pub fn parse_dim_columns(flag: &str, value: &str) -> Result<[String; 4], String> {
+    let names: Vec<String> = value
+        .split(',')
+        .map(|name| name.trim().to_string())
+        .collect();
+    match <[String; 4]>::try_from(names) {
+        Ok(columns) if columns.iter().all(|name| !name.is_empty()) => Ok(columns),
+        _ => Err(format!(
+            "{} expects four comma-separated column names, got {:?}",
+            flag, value
+        )),
+    }
+}
+
+fn sqlite_error(err: rusqlite::Error) -> io::Error {
+    io::Error::other(format!("sqlite: {}", err))
+}
+
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+// Rows are rendered as text so they go through the same parsing and
+// clamping as the CSV loaders.
+fn value_text(value: Value) -> String {
+    match value {
+        Value::Null | Value::Blob(_) => String::new(),
+        Value::Integer(number) => number.to_string(),
+        Value::Real(number) => number.to_string(),
+        Value::Text(text) => text,
+    }
+}
+
+fn query_rows(
+    source: &SqliteDimSource,
+    table: &str,
+    columns: &[String; 4],
+) -> io::Result<Vec<[String; 4]>> {
+    let conn = Connection::open_with_flags(&source.db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(sqlite_error)?;
+    let select_list = columns
+        .iter()
+        .map(|name| quote_identifier(name))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let sql = format!("SELECT {} FROM {}", select_list, quote_identifier(table));
+
+    let mut stmt = conn.prepare(&sql).map_err(sqlite_error)?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok([
+                value_text(row.get(0)?),
+                value_text(row.get(1)?),
+                value_text(row.get(2)?),
+                value_text(row.get(3)?),
+            ])
+        })
+        .map_err(sqlite_error)?;
+
+    rows.collect::<Result<Vec<_>, _>>().map_err(sqlite_error)
+}
+
+pub(crate) fn load_product_dim(
+    source: &SqliteDimSource,
+    table: &str,
+    format: &InputFormat,
+    options: &TransformOptions,
+) -> io::Result<HashMap<i64, ProductDim>> {
+    let mut product_map = HashMap::new();
+    for row in query_rows(source, table, &source.product_columns)? {
+        let cols: Vec<&str> = row.iter().map(|value| normalize_field(value)).collect();
+        if let Some((product_id, product)) = parse_product_row(&cols, format, options) {
+            product_map.insert(product_id, product);
+        }
+    }
+    Ok(product_map)
+}
+
+pub(crate) fn load_country_dim(
+    source: &SqliteDimSource,
+    table: &str,
+    format: &InputFormat,
+) -> io::Result<HashMap<String, CountryDim>> {
+    let mut country_map = HashMap::new();
+    for row in query_rows(source, table, &source.country_columns)? {
+        let cols: Vec<&str> = row.iter().map(|value| normalize_field(value)).collect();
+        if let Some((country, factor)) = parse_country_row(&cols, format) {
+            country_map.insert(country, factor);
+        }
+    }
+    Ok(country_map)
+}
@@ -0,0 +1,2465 @@
+//! Behaviour tests for `transform` on small per-test fixtures.
+
+use std::fs;
+use std::time::Instant;
+
+use super::*;
+
+const PRODUCTS: &str = "product_id,category,margin_bps,weight_grams\n\
+                        1,apparel,2000,500\n\
+                        2,grocery,3000,1200\n";
+const COUNTRIES: &str = "country,fx_to_usd_ppm,risk_bps,tax_bps\n\
+                         US,1000000,10000,850\n\
+                         CA,740000,10150,500\n";
+const EVENTS_HEADER: &str = "event_id,event_version,event_ts,event_date,customer_id,product_id,\
+                             amount_cents,quantity,discount_bps,shipping_cents,status,country,\
+                             customer_tier,payment_method\n";
+
+/// A per-test scratch directory, removed on drop.
+struct Fixture {
+    dir: PathBuf,
+}
+
+impl Fixture {
+    fn new(name: &str) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("process_rust-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let fixture = Fixture { dir };
+        fixture.write("dim_products.csv", PRODUCTS);
+        fixture.write("dim_countries.csv", COUNTRIES);
+        fixture
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    fn write(&self, name: &str, contents: &str) -> PathBuf {
+        let path = self.path(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// Writes `events.csv` with the standard header and the given rows.
+    fn events(&self, rows: &[String]) -> PathBuf {
+        let mut contents = EVENTS_HEADER.to_string();
+        for row in rows {
+            contents.push_str(row);
+            contents.push('\n');
+        }
+        self.write("events.csv", &contents)
+    }
+
+    /// Runs `transform` on `events` and the fixture dims into `output.csv`.
+    fn run(
+        &self,
+        events: &Path,
+        options: &TransformOptions,
+    ) -> io::Result<(TransformSummary, String)> {
+        let output = self.path("output.csv");
+        let summary = transform(
+            events,
+            &self.path("dim_products.csv"),
+            &self.path("dim_countries.csv"),
+            &output,
+            options,
+        )?;
+        Ok((summary, fs::read_to_string(&output).unwrap_or_default()))
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A COMPLETE event row on 2025-01-24 with no discount or shipping.
+fn event(
+    id: &str,
+    product_id: i64,
+    amount_cents: i64,
+    quantity: i64,
+    country: &str,
+    tier: &str,
+) -> String {
+    format!(
+        "{},1,2025-01-24T07:21:55,2025-01-24,1,{},{},{},0,0,COMPLETE,{},{},card",
+        id, product_id, amount_cents, quantity, country, tier
+    )
+}
+
+/// The values of column `name` in a CSV output, one per data row.
+fn column(output: &str, name: &str) -> Vec<String> {
+    let mut lines = output.lines();
+    let header: Vec<&str> = lines.next().unwrap_or_default().split(',').collect();
+    let idx = header
+        .iter()
+        .position(|col| *col == name)
+        .unwrap_or_else(|| panic!("no column {:?} in {:?}", name, header));
+    lines
+        .map(|line| line.split(',').nth(idx).unwrap_or_default().to_string())
+        .collect()
+}
+
+/// 4x the rows takes about 4x as long, a quadratic step 16x; 8x fails only the latter.
+#[test]
+fn transform_scales_linearly() {
+    const ROWS: usize = 5_000;
+    const MAX_RATIO: f64 = 8.0;
+
+    let fixture = Fixture::new("scaling");
+    let countries = ["US", "CA"];
+    let tiers = ["bronze", "silver", "gold", "platinum"];
+    let events_of = |rows: usize, name: &str| {
+        let mut contents = EVENTS_HEADER.to_string();
+        for i in 0..rows {
+            contents.push_str(&format!(
+                "E{:012},1,2025-01-{:02}T07:21:55,2025-01-{:02},{},{},{},{},{},{},COMPLETE,{},{},card\n",
+                i,
+                i % 28 + 1,
+                i % 28 + 1,
+                i % 997,
+                i % 2 + 1,
+                1_000 + (i * 37) % 50_000,
+                i % 5 + 1,
+                (i * 13) % 3_000,
+                (i * 7) % 2_000,
+                countries[i % countries.len()],
+                tiers[i % tiers.len()],
+            ));
+        }
+        fixture.write(name, &contents)
+    };
+    let small = events_of(ROWS, "small.csv");
+    let large = events_of(ROWS * 4, "large.csv");
+    let options = TransformOptions::default();
+    let fastest = |events: &Path| {
+        (0..3)
+            .map(|_| {
+                let started = Instant::now();
+                fixture.run(events, &options).unwrap();
+                started.elapsed().as_secs_f64()
+            })
+            .fold(f64::INFINITY, f64::min)
+    };
+
+    let ratio = fastest(&large) / fastest(&small);
+    assert!(
+        ratio < MAX_RATIO,
+        "4x the rows took {:.1}x as long (tolerance {}x)",
+        ratio,
+        MAX_RATIO
+    );
+}
+
+#[test]
+fn orders_of_one_group_are_summed() {
+    let fixture = Fixture::new("group-sum");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 1, 3_000, 1, "US", "gold"),
+        event("E3", 2, 500, 1, "US", "gold"),
+    ]);
+    let (summary, output) = fixture.run(&events, &TransformOptions::default()).unwrap();
+
+    assert_eq!(summary.dedup_rows, 3);
+    assert_eq!(column(&output, "category"), ["apparel", "grocery"]);
+    assert_eq!(column(&output, "order_count"), ["2", "1"]);
+    assert_eq!(column(&output, "total_net_usd_cents"), ["4340", "543"]);
+}
+
+#[test]
+fn country_aliases_map_to_the_canonical_code() {
+    let fixture = Fixture::new("country-alias");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "USA", "gold"),
+        event("E2", 1, 1_000, 1, "us", "gold"),
+    ]);
+    let options = TransformOptions {
+        country_aliases_path: Some(fixture.write("aliases.csv", "alias,country\nUSA,US\n")),
+        ..TransformOptions::default()
+    };
+    let (_, output) = fixture.run(&events, &options).unwrap();
+
+    assert_eq!(column(&output, "country"), ["US"]);
+    assert_eq!(column(&output, "order_count"), ["2"]);
+}
+
+#[test]
+fn country_aliases_without_a_header_keep_the_first_row() {
+    let fixture = Fixture::new("country-alias-no-header");
+    let events = fixture.events(&[event("E1", 1, 1_000, 1, "USA", "gold")]);
+    let mut options = TransformOptions {
+        country_aliases_path: Some(fixture.write("aliases.csv", "USA,US\n")),
+        ..TransformOptions::default()
+    };
+    options.input_format.has_header = false;
+    let (_, output) = fixture.run(&events, &options).unwrap();
+
+    assert_eq!(column(&output, "country"), ["US"]);
+}
+
+#[test]
+fn dated_fx_replaces_the_static_rate_for_its_date() {
+    let fixture = Fixture::new("dated-fx");
+    let events = fixture.events(&[event("E1", 1, 1_000, 1, "CA", "gold")]);
+    let options = TransformOptions {
+        dated_fx_path: Some(fixture.write(
+            "fx.csv",
+            "country,date,fx_to_usd_ppm\nCA,2025-01-24,500000\nCA,2025-01-25,900000\n",
+        )),
+        ..TransformOptions::default()
+    };
+    let (_, output) = fixture.run(&events, &options).unwrap();
+
+    // 1000 cents plus 5% CA tax, at 0.50 instead of the static 0.74.
+    assert_eq!(column(&output, "total_net_usd_cents"), ["525"]);
+}
+
+#[test]
+fn dated_fx_without_a_header_keeps_the_first_row() {
+    let fixture = Fixture::new("dated-fx-no-header");
+    let events = fixture.events(&[event("E1", 1, 1_000, 1, "CA", "gold")]);
+    let mut options = TransformOptions {
+        dated_fx_path: Some(fixture.write("fx.csv", "CA,2025-01-24,500000\n")),
+        ..TransformOptions::default()
+    };
+    options.input_format.has_header = false;
+    let (_, output) = fixture.run(&events, &options).unwrap();
+
+    assert_eq!(column(&output, "total_net_usd_cents"), ["525"]);
+}
+
+#[test]
+fn columns_roll_up_the_dimensions_left_out() {
+    let fixture = Fixture::new("columns");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 2, 2_000, 3, "US", "bronze"),
+        event("E3", 1, 4_000, 1, "CA", "gold"),
+    ]);
+    let options = TransformOptions {
+        columns: Some(resolve_output_columns("country,order_count,total_net_usd_cents").unwrap()),
+        ..TransformOptions::default()
+    };
+    let (_, output) = fixture.run(&events, &options).unwrap();
+
+    assert_eq!(
+        output,
+        "country,order_count,total_net_usd_cents\nCA,1,3108\nUS,2,7595\n"
+    );
+}
+
+#[test]
+fn round_div_rounds_negative_values_like_positive_ones() {
+    assert_eq!(round_div(15, 10), 2);
+    assert_eq!(round_div(-15, 10), -2);
+    assert_eq!(round_div(-14, 10), -1);
+    assert_eq!(round_div(-4, 10), 0);
+    assert_eq!(round_div(7, 0), 0);
+}
+
+#[test]
+fn profit_floor_clamps_only_negative_profit() {
+    assert_eq!(ProfitFloor::Signed.apply(-217), -217);
+    assert_eq!(ProfitFloor::Zero.apply(-217), 0);
+    assert_eq!(ProfitFloor::Zero.apply(325), 325);
+
+    let fixture = Fixture::new("profit-floor");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 2, 1_000, 1, "US", "gold"),
+    ]);
+    let with_floor = |profit_floor| TransformOptions {
+        profit_floor,
+        ..TransformOptions::default()
+    };
+    let (_, signed) = fixture
+        .run(&events, &with_floor(ProfitFloor::Signed))
+        .unwrap();
+    let (_, zero) = fixture
+        .run(&events, &with_floor(ProfitFloor::Zero))
+        .unwrap();
+    assert_eq!(column(&signed, "total_profit_usd_cents"), ["217", "325"]);
+    assert_eq!(zero, signed);
+}
+
+#[test]
+fn category_margin_overrides_only_its_category() {
+    let fixture = Fixture::new("category-margin");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 2, 1_000, 1, "US", "gold"),
+    ]);
+    let options = TransformOptions {
+        category_margins: HashMap::from([("apparel".to_string(), 5_000)]),
+        ..TransformOptions::default()
+    };
+    let (_, output) = fixture.run(&events, &options).unwrap();
+
+    // Apparel: net 1085 less a cost of round(542.5) = 543, at 50% instead of
+    // the dim's 20%. Grocery keeps its 30%.
+    assert_eq!(column(&output, "category"), ["apparel", "grocery"]);
+    assert_eq!(column(&output, "total_profit_usd_cents"), ["542", "325"]);
+}
+
+#[test]
+fn unknown_label_replaces_every_fallback() {
+    let fixture = Fixture::new("unknown-label");
+    let events = fixture.events(&[
+        "E1,1,not-a-timestamp,2025-01-24,1,99,1000,1,0,0,COMPLETE,US,diamond,card".to_string(),
+    ]);
+    let options = TransformOptions {
+        unknown_label: Some("UNKNOWN".to_string()),
+        ..TransformOptions::default()
+    };
+    let (_, output) = fixture.run(&events, &options).unwrap();
+
+    assert_eq!(column(&output, "customer_tier"), ["UNKNOWN"]);
+    assert_eq!(column(&output, "category"), ["UNKNOWN"]);
+    assert_eq!(column(&output, "time_bucket"), ["UNKNOWN"]);
+    assert!(!output.contains("unknown"));
+}
+
+#[test]
+fn rotation_splits_at_an_exact_boundary() {
+    let fixture = Fixture::new("rotation");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "bronze"),
+        event("E2", 1, 1_000, 1, "US", "gold"),
+        event("E3", 1, 1_000, 1, "US", "silver"),
+        event("E4", 2, 1_000, 1, "US", "gold"),
+    ]);
+    let options = TransformOptions {
+        max_rows_per_file: Some(2),
+        ..TransformOptions::default()
+    };
+    let output_dir = fixture.path("parts");
+    let summary = transform(
+        &events,
+        &fixture.path("dim_products.csv"),
+        &fixture.path("dim_countries.csv"),
+        &output_dir,
+        &options,
+    )
+    .unwrap();
+
+    assert_eq!(
+        summary.output_files,
+        [
+            output_dir.join("part-00000.csv"),
+            output_dir.join("part-00001.csv")
+        ]
+    );
+    for part in &summary.output_files {
+        assert_eq!(fs::read_to_string(part).unwrap().lines().count(), 3);
+    }
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn rotated_parts_are_gzipped_under_a_gz_output_directory() {
+    let fixture = Fixture::new("rotation-gzip");
+    let events = fixture.events(&[event("E1", 1, 1_000, 1, "US", "gold")]);
+    let (_, plain) = fixture.run(&events, &TransformOptions::default()).unwrap();
+    let options = TransformOptions {
+        max_rows_per_file: Some(10),
+        ..TransformOptions::default()
+    };
+    let output_dir = fixture.path("parts.gz");
+    let summary = transform(
+        &events,
+        &fixture.path("dim_products.csv"),
+        &fixture.path("dim_countries.csv"),
+        &output_dir,
+        &options,
+    )
+    .unwrap();
+
+    assert_eq!(summary.output_files, [output_dir.join("part-00000.csv.gz")]);
+    let mut decompressed = String::new();
+    flate2::read::GzDecoder::new(File::open(&summary.output_files[0]).unwrap())
+        .read_to_string(&mut decompressed)
+        .unwrap();
+    assert_eq!(decompressed, plain);
+}
+
+#[test]
+fn orders_whose_money_arithmetic_overflows_are_rejected() {
+    let fixture = Fixture::new("numeric-overflow");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        // amount * quantity fits; the tax product does not.
+        event("E2", 1, i64::MAX / 4_000, 2, "US", "gold"),
+        // amount * quantity itself overflows.
+        event("E3", 1, i64::MAX / 2, 3, "US", "gold"),
+    ]);
+    let (summary, output) = fixture.run(&events, &TransformOptions::default()).unwrap();
+
+    assert_eq!(summary.reject_counts.get("numeric_overflow"), Some(&2));
+    assert_eq!(column(&output, "total_net_usd_cents"), ["1085"]);
+}
+
+#[test]
+fn wide_rates_do_not_overflow() {
+    assert_eq!(
+        round_div_wide(i128::from(i64::MAX) * 10_000, i64::MAX),
+        10_000
+    );
+    assert_eq!(round_div_wide(-15, 10), -2);
+    assert_eq!(round_div_wide(1, 0), 0);
+}
+
+#[test]
+fn diff_delta_overflow_fails_the_run() {
+    let fixture = Fixture::new("diff-overflow");
+    let events = fixture.events(&[event("E1", 1, 1_000, 1, "US", "gold")]);
+    let (_, output) = fixture.run(&events, &TransformOptions::default()).unwrap();
+    let baseline = output.replacen(",1085,", &format!(",{},", i64::MIN), 1);
+    assert_ne!(baseline, output);
+    let options = TransformOptions {
+        diff_baseline_path: Some(fixture.write("baseline.csv", &baseline)),
+        ..TransformOptions::default()
+    };
+
+    let err = fixture.run(&events, &options).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn country_subtotals_write_one_file_per_country() {
+    let fixture = Fixture::new("country-subtotals");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 2, 1_000, 1, "US", "gold"),
+        event("E3", 1, 1_000, 1, "CA", "gold"),
+    ]);
+    let subtotals_dir = fixture.path("subtotals");
+    let options = TransformOptions {
+        country_subtotals_dir: Some(subtotals_dir.clone()),
+        ..TransformOptions::default()
+    };
+    let (summary, _) = fixture.run(&events, &options).unwrap();
+
+    assert_eq!(
+        summary.output_files[1..],
+        [subtotals_dir.join("CA.csv"), subtotals_dir.join("US.csv")]
+    );
+    let us = fs::read_to_string(subtotals_dir.join("US.csv")).unwrap();
+    assert_eq!(column(&us, "country"), ["US", "US"]);
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn country_subtotals_are_gzipped_with_a_gz_output() {
+    let fixture = Fixture::new("country-subtotals-gzip");
+    let events = fixture.events(&[event("E1", 1, 1_000, 1, "US", "gold")]);
+    let subtotals_dir = fixture.path("subtotals");
+    let options = TransformOptions {
+        country_subtotals_dir: Some(subtotals_dir.clone()),
+        ..TransformOptions::default()
+    };
+    let summary = transform(
+        &events,
+        &fixture.path("dim_products.csv"),
+        &fixture.path("dim_countries.csv"),
+        &fixture.path("output.csv.gz"),
+        &options,
+    )
+    .unwrap();
+
+    let subtotal = subtotals_dir.join("US.csv.gz");
+    assert_eq!(
+        summary.output_files,
+        [fixture.path("output.csv.gz"), subtotal.clone()]
+    );
+    let mut decompressed = String::new();
+    flate2::read::GzDecoder::new(File::open(&subtotal).unwrap())
+        .read_to_string(&mut decompressed)
+        .unwrap();
+    assert_eq!(column(&decompressed, "country"), ["US"]);
+}
+
+#[test]
+fn haircut_stays_at_zero_above_full_risk() {
+    assert_eq!(
+        RiskMode::Haircut.apply(1_000, 10_150, RoundingMode::HalfUp),
+        0
+    );
+    assert_eq!(
+        RiskMode::Haircut.apply(1_000, 9_950, RoundingMode::HalfUp),
+        5
+    );
+}
+
+#[test]
+fn general_rounding_applies_to_tax_independently_of_fx_rounding() {
+    let fixture = Fixture::new("rounding");
+    // US tax on 100 cents is 8.5 cents; CA's 0.74 FX turns 105 local cents
+    // into 77.7 USD cents.
+    let events = fixture.events(&[
+        event("E1", 1, 100, 1, "US", "gold"),
+        event("E2", 1, 100, 1, "CA", "gold"),
+    ]);
+    let net_with = |rounding, fx_rounding| {
+        let options = TransformOptions {
+            rounding,
+            fx_rounding,
+            ..TransformOptions::default()
+        };
+        let (_, output) = fixture.run(&events, &options).unwrap();
+        column(&output, "total_net_usd_cents")
+    };
+
+    assert_eq!(
+        net_with(RoundingMode::HalfUp, RoundingMode::HalfUp),
+        ["78", "109"]
+    );
+    assert_eq!(
+        net_with(RoundingMode::HalfEven, RoundingMode::HalfUp),
+        ["78", "108"]
+    );
+    assert_eq!(
+        net_with(RoundingMode::HalfEven, RoundingMode::Truncate),
+        ["77", "108"]
+    );
+}
+
+#[test]
+fn rerunning_gives_byte_identical_output() {
+    let fixture = Fixture::new("idempotent");
+    // E1 ties on version and timestamp, the dedup case most likely to
+    // depend on hash order.
+    let mut rows: Vec<String> = (0..200)
+        .map(|i| {
+            event(
+                &format!("E{}", i % 150 + 2),
+                i % 2 + 1,
+                1_000 + i * 7,
+                i % 4 + 1,
+                ["US", "CA"][i as usize % 2],
+                ["bronze", "gold"][i as usize % 3 % 2],
+            )
+        })
+        .collect();
+    rows.push(event("E1", 1, 1_000, 1, "US", "gold"));
+    rows.push(event("E1", 1, 2_000, 1, "US", "gold"));
+    let events = fixture.events(&rows);
+
+    let (_, first) = fixture.run(&events, &TransformOptions::default()).unwrap();
+    for _ in 0..3 {
+        let (_, again) = fixture.run(&events, &TransformOptions::default()).unwrap();
+        assert_eq!(again, first);
+    }
+}
+
+#[cfg(feature = "parquet")]
+#[test]
+fn parquet_output_round_trips_under_each_codec() {
+    use parquet::basic::Compression;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::Field;
+
+    let fixture = Fixture::new("parquet-codecs");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 2, 2_000, 3, "CA", "bronze"),
+    ]);
+    let (_, csv) = fixture.run(&events, &TransformOptions::default()).unwrap();
+    let expected: Vec<Vec<String>> = csv
+        .lines()
+        .skip(1)
+        .map(|line| line.split(',').map(str::to_string).collect())
+        .collect();
+
+    for codec in ["none", "snappy", "zstd", "gzip"] {
+        let options = TransformOptions {
+            output_format: OutputFormat::Parquet,
+            parquet_compression: ParquetCompression::parse(codec).unwrap(),
+            ..TransformOptions::default()
+        };
+        let path = fixture.path(&format!("output-{}.parquet", codec));
+        transform(
+            &events,
+            &fixture.path("dim_products.csv"),
+            &fixture.path("dim_countries.csv"),
+            &path,
+            &options,
+        )
+        .unwrap();
+
+        let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+        let compression = reader.metadata().row_group(0).column(0).compression();
+        let expected_compression = match codec {
+            "none" => Compression::UNCOMPRESSED,
+            "snappy" => Compression::SNAPPY,
+            "zstd" => Compression::ZSTD(Default::default()),
+            _ => Compression::GZIP(Default::default()),
+        };
+        assert_eq!(
+            std::mem::discriminant(&compression),
+            std::mem::discriminant(&expected_compression),
+            "{}",
+            codec
+        );
+        let rows: Vec<Vec<String>> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| {
+                row.unwrap()
+                    .get_column_iter()
+                    .map(|(_, field)| match field {
+                        Field::Str(value) => value.clone(),
+                        Field::Long(value) => value.to_string(),
+                        other => panic!("unexpected parquet field {:?}", other),
+                    })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(rows, expected, "{}", codec);
+    }
+}
+
+#[test]
+fn risk_modes_match_their_formulas() {
+    // Scale: 1085 * 9000 / 10000 = 976.5; haircut: 1085 * 1000 / 10000 = 108.5.
+    assert_eq!(
+        RiskMode::Scale.apply(1_085, 9_000, RoundingMode::HalfUp),
+        977
+    );
+    assert_eq!(
+        RiskMode::Haircut.apply(1_085, 9_000, RoundingMode::HalfUp),
+        109
+    );
+
+    let fixture = Fixture::new("risk-mode");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 1, 1_000, 1, "CA", "gold"),
+    ]);
+    let risk = |risk_mode| {
+        let options = TransformOptions {
+            risk_mode,
+            ..TransformOptions::default()
+        };
+        let (_, output) = fixture.run(&events, &options).unwrap();
+        column(&output, "total_risk_adjusted_usd_cents")
+    };
+
+    // CA nets 1050 local, 777 USD: scaled by 1.015 it is 788.655. US risk is
+    // exactly 10000 bps, so scaling keeps its 1085 and the haircut leaves
+    // nothing; CA's 10150 bps haircut is clamped to 0 too.
+    assert_eq!(risk(RiskMode::Scale), ["789", "1085"]);
+    assert_eq!(risk(RiskMode::Haircut), ["0", "0"]);
+}
+
+#[test]
+fn verify_aggregation_catches_a_corrupted_group() {
+    let order = |net_usd_cents| DerivedRecord {
+        event_date: "2025-01-24".to_string(),
+        customer_id: 1,
+        product_id: 1,
+        customer_tier: "gold".to_string(),
+        category: "apparel".to_string(),
+        country: "US".to_string(),
+        time_bucket: "morning".to_string(),
+        order_size_bucket: "small".to_string(),
+        risk_bucket: String::new(),
+        quantity: 1,
+        net_usd_cents,
+        profit_usd_cents: 0,
+        risk_adjusted_usd_cents: 0,
+        heavy_item_order: 0,
+        taxable_local_cents: 0,
+        tax_local_cents: 0,
+        extra: Vec::new(),
+    };
+    let rows = [order(1_085), order(2_170)];
+    let group = |order_count, total_net_usd_cents| {
+        HashMap::from([(
+            GroupKey::default(),
+            AggregateRecord {
+                order_count,
+                total_net_usd_cents,
+                ..AggregateRecord::default()
+            },
+        )])
+    };
+
+    assert!(verify_aggregation(&rows, &group(2, 3_255)).is_ok());
+    // A loop that drops the last order's net, or the order itself.
+    let dropped_net = verify_aggregation(&rows, &group(2, 1_085)).unwrap_err();
+    assert_eq!(dropped_net.kind(), io::ErrorKind::InvalidData);
+    assert!(verify_aggregation(&rows, &group(1, 3_255)).is_err());
+}
+
+#[test]
+fn number_locale_strips_grouping_separators() {
+    let fixture = Fixture::new("number-locale");
+    fixture.write(
+        "dim_products.csv",
+        "product_id;category;margin_bps;weight_grams\n1;apparel;2.000;500\n",
+    );
+    fixture.write(
+        "dim_countries.csv",
+        "country;fx_to_usd_ppm;risk_bps;tax_bps\nUS;1.000.000;10.000;850\n",
+    );
+    let events = fixture.write(
+        "events.csv",
+        &format!(
+            "{}E1;1;2025-01-24T07:21:55;2025-01-24;1;1;1.234;1;0;0;COMPLETE;US;gold;card\n",
+            EVENTS_HEADER.replace(',', ";")
+        ),
+    );
+    let mut options = TransformOptions::default();
+    options.input_format.delimiter = ';';
+    options.input_format.number_locale = NumberLocale::De;
+    let (_, output) = fixture.run(&events, &options).unwrap();
+
+    // 1234 cents plus 8.5% tax is 1338.89; the dims' grouped values still
+    // give a 20% margin at a 1.0 FX rate.
+    assert_eq!(column(&output, "total_net_usd_cents"), ["1339"]);
+    assert_eq!(column(&output, "total_profit_usd_cents"), ["268"]);
+}
+
+#[test]
+fn other_rollup_sums_the_suppressed_groups() {
+    let fixture = Fixture::new("other-rollup");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 1, 2_000, 1, "US", "gold"),
+        event("E3", 2, 1_000, 2, "US", "gold"),
+        event("E4", 1, 1_000, 1, "CA", "gold"),
+    ]);
+    let options = TransformOptions {
+        min_order_count: Some(2),
+        suppressed_other: true,
+        ..TransformOptions::default()
+    };
+    let (summary, output) = fixture.run(&events, &options).unwrap();
+
+    assert_eq!(summary.suppressed_groups, 2);
+    assert_eq!(summary.suppressed_orders, 2);
+    assert_eq!(column(&output, "category"), ["apparel", "OTHER"]);
+    assert_eq!(column(&output, "order_count"), ["2", "2"]);
+    // Grocery US nets 2170 and apparel CA 777.
+    assert_eq!(column(&output, "total_net_usd_cents"), ["3255", "2947"]);
+    assert_eq!(column(&output, "total_quantity"), ["2", "3"]);
+}
+
+#[test]
+fn full_discount_rows_are_counted_and_can_be_rejected() {
+    let fixture = Fixture::new("full-discount");
+    let events = fixture.events(&[
+        "E1,1,2025-01-24T07:21:55,2025-01-24,1,1,1000,1,10000,0,COMPLETE,US,gold,card".to_string(),
+        "E2,1,2025-01-24T07:21:55,2025-01-24,1,2,1000,1,9900,0,COMPLETE,US,gold,card".to_string(),
+    ]);
+    let (summary, output) = fixture.run(&events, &TransformOptions::default()).unwrap();
+
+    // Only the 100% row is flagged; both still get the 50% discount clamp.
+    assert_eq!(summary.full_discount_rows, 1);
+    assert_eq!(column(&output, "total_net_usd_cents"), ["543", "543"]);
+
+    let options = TransformOptions {
+        reject_full_discount: true,
+        ..TransformOptions::default()
+    };
+    let (summary, output) = fixture.run(&events, &options).unwrap();
+    assert_eq!(summary.full_discount_rows, 1);
+    assert_eq!(
+        summary.reject_counts.get("discount_exceeds_gross"),
+        Some(&1)
+    );
+    assert_eq!(column(&output, "category"), ["grocery"]);
+}
+
+#[test]
+fn no_header_keeps_the_first_row_of_every_input() {
+    let fixture = Fixture::new("no-header");
+    fixture.write("dim_products.csv", "1,apparel,2000,500\n");
+    fixture.write("dim_countries.csv", "US,1000000,10000,850\n");
+    let events = fixture.write(
+        "events.csv",
+        &format!("{}\n", event("E1", 1, 1_000, 1, "US", "gold")),
+    );
+    let mut options = TransformOptions::default();
+    options.input_format.has_header = false;
+    let (summary, output) = fixture.run(&events, &options).unwrap();
+
+    assert_eq!(summary.raw_rows, 1);
+    // A headed read would skip the event and both dim rows.
+    assert_eq!(column(&output, "category"), ["apparel"]);
+    assert_eq!(column(&output, "total_net_usd_cents"), ["1085"]);
+}
+
+#[test]
+fn per_date_counts_sum_to_dedup_rows() {
+    let fixture = Fixture::new("per-date");
+    let dated = |id: &str, version: i64, date: &str| {
+        format!(
+            "{},{},{}T07:21:55,{},1,1,1000,1,0,0,COMPLETE,US,gold,card",
+            id, version, date, date
+        )
+    };
+    let events = fixture.events(&[
+        dated("E1", 1, "2025-01-24"),
+        dated("E1", 2, "2025-01-24"),
+        dated("E2", 1, "2025-01-24"),
+        dated("E3", 1, "2025-01-25"),
+        dated("E4", 1, "2025-01-26"),
+        dated("E4", 1, "2025-01-26"),
+    ]);
+    let (summary, _) = fixture.run(&events, &TransformOptions::default()).unwrap();
+
+    let expected = BTreeMap::from([
+        ("2025-01-24".to_string(), 2),
+        ("2025-01-25".to_string(), 1),
+        ("2025-01-26".to_string(), 1),
+    ]);
+    assert_eq!(summary.dedup_rows_by_date, expected);
+    assert_eq!(
+        summary.dedup_rows_by_date.values().sum::<i64>(),
+        summary.dedup_rows
+    );
+
+    let metrics = fixture.path("metrics.json");
+    write_metrics(&metrics, &summary, 0.0, 0.0).unwrap();
+    assert!(fs::read_to_string(metrics).unwrap().contains(
+        "\"dedup_rows_by_date\": {\"2025-01-24\": 2, \"2025-01-25\": 1, \"2025-01-26\": 1},"
+    ));
+}
+
+#[test]
+fn min_net_drops_only_groups_below_the_threshold() {
+    let fixture = Fixture::new("min-net");
+    let events = fixture.events(&[
+        event("E1", 2, 999, 1, "US", "gold"),
+        event("E2", 1, 1_000, 1, "US", "gold"),
+        event("E3", 1, 1_001, 1, "US", "silver"),
+    ]);
+    let options = TransformOptions {
+        min_net_usd_cents: Some(1_085),
+        ..TransformOptions::default()
+    };
+    let (summary, output) = fixture.run(&events, &options).unwrap();
+
+    // Nets of 1084, 1085 and 1086: only the one under 1085 goes.
+    assert_eq!(summary.negligible_groups, 1);
+    assert_eq!(column(&output, "total_net_usd_cents"), ["1085", "1086"]);
+}
+
+#[test]
+fn unknown_tier_rate_fails_only_above_the_limit() {
+    let fixture = Fixture::new("unknown-tier-rate");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 1, 1_000, 1, "US", "gold"),
+        event("E3", 1, 1_000, 1, "US", "gold"),
+        event("E4", 1, 1_000, 1, "US", "diamond"),
+    ]);
+    let with_limit = |rate| TransformOptions {
+        max_unknown_tier_rate: Some(rate),
+        ..TransformOptions::default()
+    };
+
+    // One unknown tier in four filtered rows is a rate of 0.25.
+    let (summary, _) = fixture.run(&events, &with_limit(0.26)).unwrap();
+    assert_eq!(summary.unknown_tier_rows, 1);
+    assert!(fixture.run(&events, &with_limit(0.25)).is_ok());
+    let err = fixture.run(&events, &with_limit(0.24)).err();
+    assert!(err.is_some_and(|err| err.kind() == io::ErrorKind::InvalidData));
+}
+
+#[test]
+fn each_dedup_keep_strategy_picks_its_row() {
+    let fixture = Fixture::new("dedup-keep");
+    let version = |version: i64, time: &str, amount_cents: i64| {
+        format!(
+            "E1,{},2025-01-24T{},2025-01-24,1,1,{},1,0,0,COMPLETE,US,gold,card",
+            version, time, amount_cents
+        )
+    };
+    let events = fixture.events(&[
+        version(2, "07:00:00", 1_000),
+        version(3, "06:00:00", 2_000),
+        version(1, "08:00:00", 3_000),
+    ]);
+    let kept_net = |dedup_keep| {
+        let options = TransformOptions {
+            dedup_keep,
+            ..TransformOptions::default()
+        };
+        let (summary, output) = fixture.run(&events, &options).unwrap();
+        assert_eq!((summary.dedup_rows, summary.superseded_rows), (1, 2));
+        column(&output, "total_net_usd_cents")
+    };
+
+    // The highest version wins over the later timestamp; first and last go
+    // by file order whatever the version.
+    assert_eq!(kept_net(DedupKeep::LatestVersion), ["2170"]);
+    assert_eq!(kept_net(DedupKeep::First), ["1085"]);
+    assert_eq!(kept_net(DedupKeep::Last), ["3255"]);
+}
+
+#[test]
+fn superseded_rows_are_filtered_rows_less_dedup_rows() {
+    let fixture = Fixture::new("superseded");
+    let versioned = |id: &str, version: i64, status: &str| {
+        format!(
+            "{},{},2025-01-24T07:21:55,2025-01-24,1,1,1000,1,0,0,{},US,gold,card",
+            id, version, status
+        )
+    };
+    let events = fixture.events(&[
+        versioned("E1", 1, "COMPLETE"),
+        versioned("E1", 2, "COMPLETE"),
+        versioned("E1", 3, "COMPLETE"),
+        versioned("E2", 1, "COMPLETE"),
+        versioned("E2", 2, "CANCELLED"),
+    ]);
+    let options = TransformOptions {
+        superseded_out_path: Some(fixture.path("superseded.csv")),
+        ..TransformOptions::default()
+    };
+    let (summary, _) = fixture.run(&events, &options).unwrap();
+
+    // The cancelled row is filtered out, so it supersedes nothing.
+    assert_eq!(summary.filtered_rows, 4);
+    assert_eq!(summary.dedup_rows, 2);
+    assert_eq!(
+        summary.superseded_rows,
+        summary.filtered_rows - summary.dedup_rows
+    );
+    let superseded = fs::read_to_string(fixture.path("superseded.csv")).unwrap();
+    assert_eq!(
+        superseded.lines().count() as i64,
+        1 + summary.superseded_rows
+    );
+}
+
+#[test]
+fn cr_only_line_endings_split_into_rows() {
+    let fixture = Fixture::new("cr-only");
+    let rows = [
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 1, 1_000, 1, "US", "gold"),
+        event("E3", 2, 1_000, 1, "US", "gold"),
+    ];
+    let events = fixture.write(
+        "events.csv",
+        &format!("{}\r{}\r", EVENTS_HEADER.trim_end(), rows.join("\r")),
+    );
+    let (summary, output) = fixture.run(&events, &TransformOptions::default()).unwrap();
+
+    assert_eq!(summary.raw_rows, 3);
+    assert_eq!(summary.dedup_rows, 3);
+    assert_eq!(column(&output, "order_count"), ["2", "1"]);
+}
+
+#[test]
+fn customer_tier_dim_overrides_and_falls_back() {
+    let fixture = Fixture::new("customer-tier-dim");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        "E2,1,2025-01-24T07:21:55,2025-01-24,2,1,1000,1,0,0,COMPLETE,US,silver,card".to_string(),
+    ]);
+    let options = TransformOptions {
+        customer_tier_dim_path: Some(
+            fixture.write("tiers.csv", "customer_id,customer_tier\n1,Platinum\n"),
+        ),
+        ..TransformOptions::default()
+    };
+    let (_, output) = fixture.run(&events, &options).unwrap();
+
+    // Customer 1 takes the dim's tier; customer 2 is not in the dim.
+    assert_eq!(column(&output, "customer_tier"), ["platinum", "silver"]);
+}
+
+#[test]
+fn shipping_credit_reduces_gross() {
+    let fixture = Fixture::new("shipping-credit");
+    let events = fixture.events(&[
+        "E1,1,2025-01-24T07:21:55,2025-01-24,1,1,1000,1,0,-200,COMPLETE,US,gold,card".to_string(),
+    ]);
+    let (_, clamped) = fixture.run(&events, &TransformOptions::default()).unwrap();
+    let options = TransformOptions {
+        min_shipping_cents: -25_000,
+        ..TransformOptions::default()
+    };
+    let (_, credited) = fixture.run(&events, &options).unwrap();
+
+    // By default the credit is clamped to 0; kept, it leaves a gross of 800,
+    // taxed at 8.5%.
+    assert_eq!(column(&clamped, "total_net_usd_cents"), ["1085"]);
+    assert_eq!(column(&credited, "total_net_usd_cents"), ["868"]);
+}
+
+#[test]
+fn explain_trace_matches_the_output_row() {
+    let fixture = Fixture::new("explain");
+    let events = fixture.events(&[
+        "E1,1,2025-01-24T07:21:55,2025-01-24,1,2,1250,3,1000,400,COMPLETE,CA,gold,card".to_string(),
+    ]);
+    let options = TransformOptions::default();
+    let (_, output) = fixture.run(&events, &options).unwrap();
+
+    let product_map = HashMap::from([(
+        2,
+        ProductDim {
+            category: "grocery".to_string(),
+            subcategory: "unknown".to_string(),
+            margin_bps: 3_000,
+            weight_grams: 1_200,
+        },
+    )]);
+    let country_map = HashMap::from([(
+        "CA".to_string(),
+        CountryDim {
+            fx_to_usd_ppm: 740_000,
+            risk_bps: 10_150,
+            tax_bps: 500,
+        },
+    )]);
+    let (dated_fx, customer_tiers) = (HashMap::new(), HashMap::new());
+    let context = EnrichContext {
+        product_map: &product_map,
+        country_map: &country_map,
+        dated_fx: &dated_fx,
+        customer_tiers: &customer_tiers,
+        options: &options,
+    };
+    let record = EventRecord {
+        event_version: 1,
+        event_ts: "2025-01-24T07:21:55".to_string(),
+        event_date: "2025-01-24".to_string(),
+        customer_id: 1,
+        product_id: 2,
+        amount_cents: 1_250,
+        quantity: 3,
+        discount_bps: 1_000,
+        shipping_cents: 400,
+        country: "CA".to_string(),
+        customer_tier: "gold".to_string(),
+        fx_key: String::new(),
+    };
+    let mut traced = HashMap::new();
+    context
+        .enrich_traced(&record, |name, value| {
+            traced.insert(name, value.to_string());
+        })
+        .unwrap();
+
+    // Gross 4150, less a 415 discount, plus 5% tax on 3735 is 3922 local.
+    assert_eq!(traced["gross_local_cents"], "4150");
+    assert_eq!(traced["net_local_cents"], "3922");
+    for (name, measure) in [
+        ("net_usd_cents", "total_net_usd_cents"),
+        ("profit_usd_cents", "total_profit_usd_cents"),
+        ("risk_adjusted_usd_cents", "total_risk_adjusted_usd_cents"),
+    ] {
+        assert_eq!(
+            column(&output, measure),
+            [traced[name].as_str()],
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn concatenated_shard_headers_are_skipped_and_counted() {
+    let fixture = Fixture::new("repeated-header");
+    let shard = |ids: &[&str]| {
+        let mut shard = EVENTS_HEADER.to_string();
+        for id in ids {
+            shard.push_str(&event(id, 1, 1_000, 1, "US", "gold"));
+            shard.push('\n');
+        }
+        shard
+    };
+    let events = fixture.write(
+        "events.csv",
+        &[shard(&["E1", "E2"]), shard(&["E3"]), shard(&["E4"])].concat(),
+    );
+    let (summary, output) = fixture.run(&events, &TransformOptions::default()).unwrap();
+
+    assert_eq!(summary.repeated_header_rows, 2);
+    assert_eq!(summary.raw_rows, 4);
+    assert!(summary.reject_counts.is_empty());
+    assert_eq!(column(&output, "order_count"), ["4"]);
+}
+
+#[test]
+fn json_output_is_a_valid_array_with_or_without_rows() {
+    let fixture = Fixture::new("json-output");
+    let options = TransformOptions {
+        output_format: OutputFormat::Json,
+        columns: Some(resolve_output_columns("country,order_count").unwrap()),
+        ..TransformOptions::default()
+    };
+
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 1, 1_000, 1, "CA", "gold"),
+    ]);
+    let (_, output) = fixture.run(&events, &options).unwrap();
+    assert_eq!(
+        output,
+        "[\n{\"country\": \"CA\", \"order_count\": 1},\n{\"country\": \"US\", \"order_count\": 1}\n]\n"
+    );
+
+    let cancelled = event("E1", 1, 1_000, 1, "US", "gold").replace("COMPLETE", "CANCELLED");
+    let events = fixture.events(&[cancelled]);
+    let (_, output) = fixture.run(&events, &options).unwrap();
+    assert_eq!(output, "[]\n");
+}
+
+#[test]
+fn fx_round_places_rounds_the_rate_before_converting() {
+    let fixture = Fixture::new("fx-round-places");
+    fixture.write(
+        "dim_countries.csv",
+        "country,fx_to_usd_ppm,risk_bps,tax_bps\nCA,745678,10150,500\n",
+    );
+    let events = fixture.events(&[event("E1", 1, 1_000, 1, "CA", "gold")]);
+    let net = |fx_round_places| {
+        let options = TransformOptions {
+            fx_round_places,
+            ..TransformOptions::default()
+        };
+        let (_, output) = fixture.run(&events, &options).unwrap();
+        column(&output, "total_net_usd_cents")
+    };
+
+    // 1050 local cents at 0.745678 is 782.96; at 0.75 it is 787.5, at
+    // 0.7457 it is 782.985, and no places rounds the rate to 1.
+    assert_eq!(net(None), ["783"]);
+    assert_eq!(net(Some(2)), ["788"]);
+    assert_eq!(net(Some(4)), ["783"]);
+    assert_eq!(net(Some(0)), ["1050"]);
+}
+
+#[test]
+fn heavy_categories_flag_light_orders() {
+    let fixture = Fixture::new("heavy-categories");
+    fixture.write(
+        "dim_products.csv",
+        "product_id,category,margin_bps,weight_grams\n1,apparel,2000,500\n3,furniture,2000,100\n",
+    );
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 3, 1_000, 1, "US", "gold"),
+    ]);
+    let options = TransformOptions {
+        heavy_categories: BTreeSet::from(["furniture".to_string()]),
+        ..TransformOptions::default()
+    };
+    let (_, output) = fixture.run(&events, &options).unwrap();
+    let (_, by_weight) = fixture.run(&events, &TransformOptions::default()).unwrap();
+
+    // Both orders are far below 5000 g; only the furniture category makes one heavy.
+    assert_eq!(column(&output, "category"), ["apparel", "furniture"]);
+    assert_eq!(column(&output, "heavy_item_orders"), ["0", "1"]);
+    assert_eq!(column(&by_weight, "heavy_item_orders"), ["0", "0"]);
+}
+
+#[test]
+fn dense_emits_a_zero_row_for_a_missing_combination() {
+    let fixture = Fixture::new("dense");
+    let events = fixture.events(&[event("E1", 1, 1_000, 1, "US", "gold")]);
+    let (dimension, values) = parse_dense_domain("country=US,CA").unwrap();
+    let options = TransformOptions {
+        dense_domains: BTreeMap::from([(dimension, values)]),
+        ..TransformOptions::default()
+    };
+    let (summary, output) = fixture.run(&events, &options).unwrap();
+
+    assert_eq!(summary.dense_groups, 1);
+    assert_eq!(column(&output, "country"), ["CA", "US"]);
+    assert_eq!(column(&output, "category"), ["apparel", "apparel"]);
+    for measure in [
+        "order_count",
+        "total_quantity",
+        "total_net_usd_cents",
+        "total_profit_usd_cents",
+        "heavy_item_orders",
+    ] {
+        assert_eq!(column(&output, measure)[0], "0", "{}", measure);
+    }
+    assert_eq!(column(&output, "total_net_usd_cents")[1], "1085");
+}
+
+#[test]
+fn per_country_vip_key_splits_a_cross_border_customer() {
+    let fixture = Fixture::new("vip-key");
+    let events = fixture.events(&[
+        event("E1", 1, 30_000, 1, "US", "gold"),
+        event("E2", 1, 40_000, 1, "CA", "gold"),
+    ]);
+    let vip = |vip_key| {
+        let options = TransformOptions {
+            vip_key,
+            ..TransformOptions::default()
+        };
+        let (_, output) = fixture.run(&events, &options).unwrap();
+        column(&output, "vip_customer_orders")
+    };
+
+    // 32550 in the US and 31080 in CA: pooled they pass the 50000 threshold,
+    // apart neither does.
+    assert_eq!(vip(VipKey::DateCustomer), ["1", "1"]);
+    assert_eq!(vip(VipKey::DateCustomerCountry), ["0", "0"]);
+}
+
+#[test]
+fn diff_reports_only_the_changed_group() {
+    let fixture = Fixture::new("diff-changed");
+    let baseline_events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 2, 1_000, 1, "CA", "gold"),
+    ]);
+    let (_, baseline) = fixture
+        .run(&baseline_events, &TransformOptions::default())
+        .unwrap();
+    let options = TransformOptions {
+        diff_baseline_path: Some(fixture.write("baseline.csv", &baseline)),
+        ..TransformOptions::default()
+    };
+    let events = fixture.events(&[
+        event("E1", 1, 2_000, 1, "US", "gold"),
+        event("E2", 2, 1_000, 1, "CA", "gold"),
+    ]);
+
+    let (summary, _) = fixture.run(&events, &options).unwrap();
+    let diff = summary.diff.unwrap();
+    assert_eq!(
+        (diff.added_groups, diff.removed_groups, diff.changed_groups),
+        (0, 0, 1)
+    );
+    let diff_csv = fs::read_to_string(fixture.path("output.diff.csv")).unwrap();
+    assert_eq!(column(&diff_csv, "change"), ["changed"]);
+    assert_eq!(column(&diff_csv, "country"), ["US"]);
+    assert_eq!(column(&diff_csv, "total_net_usd_cents_delta"), ["1085"]);
+    assert_eq!(column(&diff_csv, "order_count_delta"), ["0"]);
+}
+
+#[test]
+fn product_lists_include_or_exclude_their_products() {
+    let fixture = Fixture::new("product-lists");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 2, 1_000, 1, "US", "gold"),
+    ]);
+    let list = fixture.write("products.txt", "product_id\n2\n");
+
+    let included = TransformOptions {
+        include_products_path: Some(list.clone()),
+        ..TransformOptions::default()
+    };
+    let (summary, output) = fixture.run(&events, &included).unwrap();
+    assert_eq!(column(&output, "category"), ["grocery"]);
+    assert_eq!(summary.reject_counts.get("product_not_included"), Some(&1));
+
+    let excluded = TransformOptions {
+        exclude_products_path: Some(list),
+        ..TransformOptions::default()
+    };
+    let (summary, output) = fixture.run(&events, &excluded).unwrap();
+    assert_eq!(column(&output, "category"), ["apparel"]);
+    assert_eq!(summary.reject_counts.get("product_excluded"), Some(&1));
+}
+
+#[test]
+fn missing_dims_fall_back_to_defaults_only_when_allowed() {
+    let fixture = Fixture::new("missing-dims");
+    fs::remove_file(fixture.path("dim_products.csv")).unwrap();
+    fs::remove_file(fixture.path("dim_countries.csv")).unwrap();
+    let events = fixture.events(&[event("E1", 1, 1_000, 1, "CA", "gold")]);
+
+    let err = fixture.run(&events, &TransformOptions::default()).err();
+    assert!(err.is_some_and(|err| err.kind() == io::ErrorKind::NotFound));
+
+    let options = TransformOptions {
+        allow_missing_dims: true,
+        ..TransformOptions::default()
+    };
+    let (_, output) = fixture.run(&events, &options).unwrap();
+    assert_eq!(column(&output, "category"), ["unknown"]);
+    assert_eq!(column(&output, "total_net_usd_cents"), ["1000"]);
+    assert_eq!(column(&output, "total_profit_usd_cents"), ["250"]);
+}
+
+#[test]
+fn invalid_versions_follow_their_policy() {
+    let fixture = Fixture::new("invalid-version");
+    let valid = event("E1", 1, 1_000, 1, "US", "gold").replacen(",1,", ",0,", 1);
+    let garbage = event("E1", 1, 2_000, 1, "US", "gold")
+        .replacen(",1,", ",v2,", 1)
+        .replacen("07:21:55", "08:00:00", 1);
+    let events = fixture.events(&[valid, garbage]);
+
+    for (policy, net, rejected) in [
+        (InvalidVersionPolicy::Zero, "2170", None),
+        (InvalidVersionPolicy::Lowest, "1085", None),
+        (InvalidVersionPolicy::Reject, "1085", Some(&1)),
+    ] {
+        let options = TransformOptions {
+            invalid_version: policy,
+            ..TransformOptions::default()
+        };
+        let (summary, output) = fixture.run(&events, &options).unwrap();
+        assert_eq!(summary.invalid_version_rows, 1, "{}", policy.as_str());
+        assert_eq!(
+            column(&output, "total_net_usd_cents"),
+            [net],
+            "{}",
+            policy.as_str()
+        );
+        assert_eq!(
+            summary.reject_counts.get("invalid_event_version"),
+            rejected,
+            "{}",
+            policy.as_str()
+        );
+    }
+}
+
+#[test]
+fn product_delimiter_reads_a_tsv_dim_beside_csv_events() {
+    let fixture = Fixture::new("tsv-products");
+    fixture.write("dim_products.csv", &PRODUCTS.replace(',', "\t"));
+    let events = fixture.events(&[event("E1", 1, 1_000, 1, "US", "gold")]);
+
+    let (_, output) = fixture.run(&events, &TransformOptions::default()).unwrap();
+    assert_eq!(column(&output, "category"), ["unknown"]);
+
+    let options = TransformOptions {
+        product_delimiter: Some('\t'),
+        ..TransformOptions::default()
+    };
+    let (_, output) = fixture.run(&events, &options).unwrap();
+    assert_eq!(column(&output, "category"), ["apparel"]);
+    assert_eq!(column(&output, "total_profit_usd_cents"), ["217"]);
+}
+
+#[test]
+fn configured_tiers_make_diamond_a_first_class_tier() {
+    let fixture = Fixture::new("diamond-tier");
+    let events = fixture.events(&[event("E1", 1, 1_000, 1, "US", "diamond")]);
+
+    let (summary, output) = fixture.run(&events, &TransformOptions::default()).unwrap();
+    assert_eq!(column(&output, "customer_tier"), ["unknown"]);
+    assert_eq!(summary.unknown_tier_rows, 1);
+
+    let mut tiers: Vec<String> = DEFAULT_TIERS.iter().map(|tier| tier.to_string()).collect();
+    tiers.push("diamond".to_string());
+    let options = TransformOptions {
+        tiers: Some(tiers),
+        ..TransformOptions::default()
+    };
+    let (summary, output) = fixture.run(&events, &options).unwrap();
+    assert_eq!(column(&output, "customer_tier"), ["diamond"]);
+    assert_eq!(summary.unknown_tier_rows, 0);
+}
+
+#[test]
+fn profit_margin_blends_the_categories_of_a_group() {
+    let fixture = Fixture::new("profit-margin");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 2, 2_000, 1, "US", "gold"),
+    ]);
+    let options = TransformOptions {
+        columns: Some(
+            resolve_output_columns(
+                "country,total_net_usd_cents,total_profit_usd_cents,profit_margin_bps",
+            )
+            .unwrap(),
+        ),
+        ..TransformOptions::default()
+    };
+    let (_, output) = fixture.run(&events, &options).unwrap();
+
+    // 217 + 651 profit on 1085 + 2170 net.
+    assert_eq!(
+        output,
+        "country,total_net_usd_cents,total_profit_usd_cents,profit_margin_bps\nUS,3255,868,2667\n"
+    );
+}
+
+#[test]
+fn lenient_numbers_strip_currency_symbols_and_grouping() {
+    let strict = InputFormat::default();
+    assert_eq!(strict.parse_i64("$1,234"), 0);
+    let lenient = InputFormat {
+        currency_symbols: Some("$".to_string()),
+        ..InputFormat::default()
+    };
+    assert_eq!(lenient.parse_i64("$1,234"), 1234);
+
+    let fixture = Fixture::new("lenient-numbers");
+    // Tab-separated, since an unquoted CSV field cannot hold a comma.
+    let row = event("E1", 1, 1_000, 1, "US", "gold")
+        .replace(',', "\t")
+        .replacen("\t1000\t", "\t$1,000\t", 1);
+    let events = fixture.write(
+        "events.tsv",
+        &format!("{}{}\n", EVENTS_HEADER.replace(',', "\t"), row),
+    );
+    let options = TransformOptions {
+        input_format: InputFormat {
+            delimiter: '\t',
+            ..lenient
+        },
+        product_delimiter: Some(','),
+        country_delimiter: Some(','),
+        ..TransformOptions::default()
+    };
+    let (_, output) = fixture.run(&events, &options).unwrap();
+    assert_eq!(column(&output, "total_net_usd_cents"), ["1085"]);
+}
+
+#[test]
+fn mark_missing_dims_labels_the_join_failures_only() {
+    let fixture = Fixture::new("mark-missing");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 9, 1_000, 1, "MX", "gold"),
+    ]);
+
+    let (_, plain) = fixture.run(&events, &TransformOptions::default()).unwrap();
+    assert_eq!(column(&plain, "category"), ["apparel", "unknown"]);
+
+    let options = TransformOptions {
+        mark_missing_dims: true,
+        ..TransformOptions::default()
+    };
+    let (_, marked) = fixture.run(&events, &options).unwrap();
+    assert_eq!(column(&marked, "category"), ["apparel", "missing_product"]);
+    assert_eq!(column(&marked, "country"), ["US", "MX"]);
+    assert_eq!(
+        column(&marked, "total_profit_usd_cents"),
+        column(&plain, "total_profit_usd_cents")
+    );
+    assert_eq!(column(&marked, "total_net_usd_cents"), ["1085", "1000"]);
+}
+
+#[test]
+fn ndjson_events_give_the_same_output_as_csv() {
+    let fixture = Fixture::new("ndjson");
+    let rows = [
+        event("E1", 1, 1_000, 2, "US", "gold"),
+        event("E2", 2, 3_000, 1, "CA", "bronze"),
+    ];
+    let csv = fixture.events(&rows);
+    let (_, csv_output) = fixture.run(&csv, &TransformOptions::default()).unwrap();
+
+    let names: Vec<&str> = EVENTS_HEADER.trim_end().split(',').collect();
+    let mut ndjson = String::new();
+    for row in &rows {
+        let fields: Vec<String> = names
+            .iter()
+            .zip(row.split(','))
+            .map(|(name, value)| match value.parse::<i64>() {
+                Ok(number) => format!("\"{}\": {}", name, number),
+                Err(_) => format!("\"{}\": \"{}\"", name, value),
+            })
+            .collect();
+        ndjson.push_str(&format!("{{{}}}\n", fields.join(", ")));
+    }
+    let events = fixture.write("events.ndjson", &ndjson);
+    let options = TransformOptions {
+        events_format: EventsFormat::Ndjson,
+        ..TransformOptions::default()
+    };
+    let (_, ndjson_output) = fixture.run(&events, &options).unwrap();
+
+    assert_eq!(ndjson_output, csv_output);
+    assert_eq!(column(&csv_output, "order_count"), ["1", "1"]);
+}
+
+#[test]
+fn whitespace_only_fields_behave_like_empty_ones() {
+    let run = |blank: &str| {
+        let fixture = Fixture::new("whitespace-fields");
+        fixture.write(
+            "dim_products.csv",
+            &format!("{}3,{},2000,500\n", PRODUCTS, blank),
+        );
+        let events = fixture.events(&[
+            format!(
+                "E1,1,2025-01-24T07:21:55,2025-01-24,1,3,1000,1,{b},{b},COMPLETE,US,{b},card",
+                b = blank
+            ),
+            format!(
+                "{b},1,2025-01-24T07:21:55,2025-01-24,1,1,1000,1,0,0,COMPLETE,US,gold,card",
+                b = blank
+            ),
+        ]);
+        let (summary, output) = fixture.run(&events, &TransformOptions::default()).unwrap();
+        (format_reject_counts(&summary.reject_counts), output)
+    };
+
+    let (empty_rejects, empty_output) = run("");
+    let (spaces_rejects, spaces_output) = run("  ");
+    assert_eq!(spaces_output, empty_output);
+    assert_eq!(spaces_rejects, empty_rejects);
+    assert_eq!(column(&empty_output, "category"), ["unknown"]);
+    assert_eq!(column(&empty_output, "customer_tier"), ["unknown"]);
+}
+
+#[test]
+fn gross_local_sums_local_goods_per_country() {
+    let fixture = Fixture::new("gross-local");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 2, "CA", "gold").replacen(",0,0,COMPLETE", ",0,300,COMPLETE", 1),
+        event("E3", 1, 700, 1, "US", "gold"),
+    ]);
+    let options = TransformOptions {
+        gross_local: true,
+        ..TransformOptions::default()
+    };
+    let (_, output) = fixture.run(&events, &options).unwrap();
+
+    // Shipping stays out; CAD and USD are never summed together.
+    assert_eq!(column(&output, "country"), ["CA", "US"]);
+    assert_eq!(column(&output, "total_gross_local_cents"), ["2000", "700"]);
+}
+
+#[test]
+fn stddev_is_the_population_deviation_of_order_net() {
+    let fixture = Fixture::new("stddev");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 1, 2_000, 1, "US", "gold"),
+        event("E3", 1, 3_000, 1, "US", "gold"),
+        event("E4", 1, 1_000, 1, "CA", "gold"),
+    ]);
+    let options = TransformOptions {
+        columns: Some(resolve_output_columns("country,order_count,stddev_net_usd_cents").unwrap()),
+        ..TransformOptions::default()
+    };
+    let (_, output) = fixture.run(&events, &options).unwrap();
+
+    // US nets 1085, 2170, 3255: sqrt(2 * 1085^2 / 3) = 885.9, rounded to 886.
+    assert_eq!(
+        output,
+        "country,order_count,stddev_net_usd_cents\nCA,1,0\nUS,3,886\n"
+    );
+}
+
+#[test]
+fn utc_dates_shift_orders_across_the_day_boundary() {
+    let fixture = Fixture::new("utc-date");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold").replacen("07:21:55", "03:30:00+05:30", 1),
+        event("E2", 1, 1_000, 1, "US", "gold").replacen("07:21:55", "22:00:00-05:00", 1),
+        event("E3", 1, 1_000, 1, "US", "gold").replacen("07:21:55", "12:00:00Z", 1),
+    ]);
+
+    let columns = Some(resolve_output_columns("event_date,order_count").unwrap());
+    let source = TransformOptions {
+        columns: columns.clone(),
+        ..TransformOptions::default()
+    };
+    let (_, output) = fixture.run(&events, &source).unwrap();
+    assert_eq!(output, "event_date,order_count\n2025-01-24,3\n");
+
+    let utc = TransformOptions {
+        columns,
+        date_from: DateSource::UtcTs,
+        ..TransformOptions::default()
+    };
+    let (_, output) = fixture.run(&events, &utc).unwrap();
+    assert_eq!(
+        output,
+        "event_date,order_count\n2025-01-23,1\n2025-01-24,1\n2025-01-25,1\n"
+    );
+}
+
+#[test]
+fn excluded_customers_leave_every_group() {
+    let fixture = Fixture::new("exclude-customers");
+    let flagged = |id: &str, product_id: i64, country: &str| {
+        event(id, product_id, 5_000, 1, country, "gold").replacen(
+            ",2025-01-24,1,",
+            ",2025-01-24,2,",
+            1,
+        )
+    };
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        flagged("E2", 1, "US"),
+        flagged("E3", 2, "CA"),
+    ]);
+    let options = TransformOptions {
+        exclude_customers_path: Some(fixture.write("flagged.txt", "customer_id\n2\n")),
+        ..TransformOptions::default()
+    };
+    let (summary, output) = fixture.run(&events, &options).unwrap();
+
+    assert_eq!(summary.reject_counts.get("excluded_customer"), Some(&2));
+    assert_eq!(column(&output, "country"), ["US"]);
+    assert_eq!(column(&output, "order_count"), ["1"]);
+    assert_eq!(column(&output, "total_net_usd_cents"), ["1085"]);
+}
+
+#[test]
+fn customer_day_out_flags_vip_from_the_threshold_up() {
+    let fixture = Fixture::new("customer-day-out");
+    let events = fixture.events(&[
+        event("E1", 1, 2_000, 1, "US", "gold"),
+        event("E2", 1, 1_999, 1, "US", "gold").replacen(",2025-01-24,1,", ",2025-01-24,2,", 1),
+    ]);
+    let options = TransformOptions {
+        vip_thresholds: HashMap::from([("gold".to_string(), 2_170)]),
+        customer_day_out_path: Some(fixture.path("customer_day.csv")),
+        ..TransformOptions::default()
+    };
+    let (_, output) = fixture.run(&events, &options).unwrap();
+
+    assert_eq!(
+        fs::read_to_string(fixture.path("customer_day.csv")).unwrap(),
+        "event_date,customer_id,total_net_usd_cents,is_vip\n\
+         2025-01-24,1,2170,1\n\
+         2025-01-24,2,2169,0\n"
+    );
+    assert_eq!(
+        column(&output, "vip_customer_orders")
+            .iter()
+            .map(|orders| orders.parse::<i64>().unwrap())
+            .sum::<i64>(),
+        1
+    );
+}
+
+#[test]
+fn subcategory_level_reads_an_optional_fifth_column() {
+    let fixture = Fixture::new("subcategory");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 2, 1_000, 1, "US", "gold"),
+    ]);
+    let options = TransformOptions {
+        category_level: CategoryLevel::Subcategory,
+        ..TransformOptions::default()
+    };
+
+    let (_, output) = fixture.run(&events, &options).unwrap();
+    assert_eq!(
+        column(&output, "category"),
+        ["apparel/unknown", "grocery/unknown"]
+    );
+
+    fixture.write(
+        "dim_products.csv",
+        "product_id,category,margin_bps,weight_grams,subcategory\n\
+         1,apparel,2000,500,Shirts\n\
+         2,grocery,3000,1200,\n",
+    );
+    let (_, output) = fixture.run(&events, &options).unwrap();
+    assert_eq!(
+        column(&output, "category"),
+        ["apparel/shirts", "grocery/unknown"]
+    );
+    assert_eq!(column(&output, "total_profit_usd_cents"), ["217", "325"]);
+}
+
+#[test]
+fn effective_tax_is_the_rate_of_each_country() {
+    let fixture = Fixture::new("effective-tax");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 1, 1_000, 1, "CA", "gold"),
+    ]);
+    let options = TransformOptions {
+        columns: Some(resolve_output_columns("country,order_count").unwrap()),
+        effective_tax: true,
+        ..TransformOptions::default()
+    };
+    let (_, output) = fixture.run(&events, &options).unwrap();
+
+    assert_eq!(
+        output,
+        "country,order_count,effective_tax_bps\nCA,1,500\nUS,1,850\n"
+    );
+}
+
+#[test]
+fn fail_on_empty_rejects_an_all_filtered_input() {
+    let fixture = Fixture::new("fail-on-empty");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold").replacen("COMPLETE", "CANCELLED", 1),
+        event("E2", 1, 0, 1, "US", "gold"),
+    ]);
+
+    let (_, output) = fixture.run(&events, &TransformOptions::default()).unwrap();
+    assert_eq!(output.lines().count(), 1);
+
+    let options = TransformOptions {
+        fail_on_empty: true,
+        ..TransformOptions::default()
+    };
+    let err = fixture.run(&events, &options).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("raw_rows=2"), "{}", err);
+}
+
+#[test]
+fn usd_rounding_unit_rounds_only_its_country_to_the_dollar() {
+    let fixture = Fixture::new("usd-rounding-unit");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "CA", "gold"),
+        event("E2", 1, 1_000, 1, "US", "gold"),
+    ]);
+    let options = TransformOptions {
+        usd_rounding_units: HashMap::from([("CA".to_string(), 100)]),
+        ..TransformOptions::default()
+    };
+    let (_, output) = fixture.run(&events, &options).unwrap();
+
+    // CA nets 777 cents and profits 155 before rounding.
+    assert_eq!(column(&output, "country"), ["CA", "US"]);
+    assert_eq!(column(&output, "total_net_usd_cents"), ["800", "1085"]);
+    assert_eq!(column(&output, "total_profit_usd_cents"), ["200", "217"]);
+}
+
+#[test]
+fn oversized_lines_are_rejected_as_line_too_long() {
+    let giant = io::repeat(b'x').take(16 << 20);
+    let reader = io::BufReader::new(io::Read::chain(giant, &b"\nnext\n"[..]));
+    let mut lines = input_lines(reader).max_line_bytes(Some(1024));
+    assert!(is_line_too_long(&lines.next().unwrap().unwrap_err()));
+    assert_eq!(lines.next().unwrap().unwrap(), "next");
+    assert!(lines.next().is_none());
+
+    let fixture = Fixture::new("max-line-bytes");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        "x".repeat(4096),
+        event("E2", 1, 1_000, 1, "US", "gold"),
+    ]);
+    let options = TransformOptions {
+        max_line_bytes: Some(1024),
+        ..TransformOptions::default()
+    };
+    let (summary, output) = fixture.run(&events, &options).unwrap();
+    assert_eq!(summary.reject_counts.get("line_too_long"), Some(&1));
+    assert_eq!(column(&output, "order_count"), ["2"]);
+}
+
+#[test]
+fn row_count_reconciliation_catches_a_double_count() {
+    // 10 filtered = 7 kept + 2 superseded + 1 conflict; 7 kept = 6 grouped + 1 rejected.
+    assert!(reconcile_row_counts(10, 2, 1, 7, 1, 6).is_ok());
+    for (filtered, grouped) in [(11, 6), (10, 7)] {
+        let err = reconcile_row_counts(filtered, 2, 1, 7, 1, grouped).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("row counts do not reconcile"));
+    }
+}
+
+#[test]
+fn overrides_patch_only_their_field_of_their_key() {
+    let fixture = Fixture::new("overrides");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 2, 1_000, 1, "US", "gold"),
+        event("E3", 1, 1_000, 1, "CA", "gold"),
+    ]);
+    let (_, plain) = fixture.run(&events, &TransformOptions::default()).unwrap();
+    assert_eq!(
+        column(&plain, "total_net_usd_cents"),
+        ["777", "1085", "1085"]
+    );
+    assert_eq!(
+        column(&plain, "total_profit_usd_cents"),
+        ["155", "217", "325"]
+    );
+
+    let options = TransformOptions {
+        product_overrides: vec![
+            ProductOverride::parse("--product-override", "1:margin_bps=3000").unwrap(),
+        ],
+        country_overrides: vec![
+            CountryOverride::parse("--country-override", "us:tax_bps=800").unwrap(),
+        ],
+        ..TransformOptions::default()
+    };
+    let (_, patched) = fixture.run(&events, &options).unwrap();
+
+    for name in ["category", "country", "total_quantity", "heavy_item_orders"] {
+        assert_eq!(column(&patched, name), column(&plain, name), "{}", name);
+    }
+    assert_eq!(
+        column(&patched, "total_net_usd_cents"),
+        ["777", "1080", "1080"]
+    );
+    assert_eq!(
+        column(&patched, "total_profit_usd_cents"),
+        ["233", "324", "324"]
+    );
+}
+
+#[test]
+fn heavy_thresholds_apply_per_category() {
+    let fixture = Fixture::new("heavy-thresholds");
+    fixture.write(
+        "dim_products.csv",
+        "product_id,category,margin_bps,weight_grams\n\
+         4,electronics,2000,1000\n\
+         5,furniture,2000,1000\n\
+         6,toys,2000,1000\n",
+    );
+    let events = fixture.events(&[
+        event("E1", 4, 1_000, 1, "US", "gold"),
+        event("E2", 5, 1_000, 1, "US", "gold"),
+        event("E3", 6, 1_000, 1, "US", "gold"),
+    ]);
+    let options = TransformOptions {
+        heavy_grams_by_category: HashMap::from([
+            ("electronics".to_string(), 800),
+            ("furniture".to_string(), 2_000),
+        ]),
+        ..TransformOptions::default()
+    };
+    let (_, output) = fixture.run(&events, &options).unwrap();
+
+    // Each order weighs 1000 g; toys falls back to the global 5000 g.
+    assert_eq!(
+        column(&output, "category"),
+        ["electronics", "furniture", "toys"]
+    );
+    assert_eq!(column(&output, "heavy_item_orders"), ["1", "0", "0"]);
+}
+
+#[test]
+fn leading_comment_lines_are_skipped_before_the_dim_header() {
+    let fixture = Fixture::new("dim-comments");
+    let dim = fixture.write(
+        "dim_countries.csv",
+        &format!(
+            "# exported 2025-01-24\n# source: finance\n# rows: 2\n{}",
+            COUNTRIES
+        ),
+    );
+    let format = InputFormat::default();
+
+    let countries = load_country_dim(&dim, &format, "#").unwrap();
+    let mut codes: Vec<&String> = countries.keys().collect();
+    codes.sort();
+    assert_eq!(codes, ["CA", "US"]);
+
+    // Under another prefix the first comment is taken as the header, so the real one is data.
+    let countries = load_country_dim(&dim, &format, "%").unwrap();
+    assert!(countries.contains_key("COUNTRY"));
+
+    let events = fixture.events(&[event("E1", 1, 1_000, 1, "CA", "gold")]);
+    let (_, output) = fixture.run(&events, &TransformOptions::default()).unwrap();
+    assert_eq!(column(&output, "total_net_usd_cents"), ["777"]);
+}
+
+#[test]
+fn canonical_output_is_stable_under_shuffling() {
+    let fixture = Fixture::new("canonical-shuffle");
+    let mut rows = vec![
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 2, 2_000, 3, "US", "bronze"),
+        event("E3", 1, 4_000, 1, "CA", "gold"),
+        event("E4", 2, 500, 2, "CA", "silver"),
+    ];
+    let canonical = TransformOptions {
+        canonical: true,
+        ..TransformOptions::default()
+    };
+    let (_, expected) = fixture.run(&fixture.events(&rows), &canonical).unwrap();
+    assert_eq!(expected.lines().count(), 5);
+
+    rows.reverse();
+    let events = fixture.events(&rows);
+    for seed in [None, Some(1), Some(7)] {
+        let options = TransformOptions {
+            canonical: true,
+            shuffle_output_seed: seed,
+            ..TransformOptions::default()
+        };
+        let (_, output) = fixture.run(&events, &options).unwrap();
+        assert_eq!(output, expected, "seed {:?}", seed);
+    }
+}
+
+#[test]
+fn top_products_rank_by_revenue_with_ties_to_the_lower_id() {
+    let fixture = Fixture::new("top-products");
+    let events = fixture.events(&[
+        event("E1", 2, 1_000, 1, "US", "gold"),
+        event("E2", 3, 3_000, 1, "US", "gold"),
+        event("E3", 2, 1_000, 1, "US", "gold"),
+        event("E4", 1, 2_000, 1, "US", "gold"),
+        event("E5", 4, 500, 1, "US", "gold"),
+    ]);
+    let options = TransformOptions {
+        top_products: Some(3),
+        ..TransformOptions::default()
+    };
+    fixture.run(&events, &options).unwrap();
+
+    // Products 1 and 2 both net 2170; product 4 nets least and is past the limit.
+    assert_eq!(
+        fs::read_to_string(fixture.path("output.top_products.csv")).unwrap(),
+        "product_id,total_net_usd_cents,order_count\n\
+         3,3255,1\n\
+         1,2170,1\n\
+         2,2170,2\n"
+    );
+}
+
+#[test]
+fn numeric_missing_zeroes_or_rejects_a_blank_discount() {
+    let fixture = Fixture::new("numeric-missing");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold").replacen(",0,0,COMPLETE", ",,0,COMPLETE", 1),
+        event("E2", 1, 2_000, 1, "US", "gold"),
+    ]);
+
+    let (summary, output) = fixture.run(&events, &TransformOptions::default()).unwrap();
+    assert!(summary.reject_counts.is_empty());
+    assert_eq!(column(&output, "total_net_usd_cents"), ["3255"]);
+
+    let options = TransformOptions {
+        numeric_missing: NumericMissing::Reject,
+        ..TransformOptions::default()
+    };
+    let (summary, output) = fixture.run(&events, &options).unwrap();
+    assert_eq!(summary.reject_counts.get("missing_discount"), Some(&1));
+    assert_eq!(column(&output, "total_net_usd_cents"), ["2170"]);
+}
+
+#[test]
+fn avg_quantity_centi_is_the_rounded_mean_in_hundredths() {
+    let fixture = Fixture::new("avg-quantity");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 1, 1_000, 2, "US", "gold"),
+        event("E3", 1, 1_000, 2, "US", "gold"),
+        event("E4", 1, 1_000, 3, "CA", "gold"),
+    ]);
+    let options = TransformOptions {
+        columns: Some(
+            resolve_output_columns("country,order_count,total_quantity,avg_quantity_centi")
+                .unwrap(),
+        ),
+        ..TransformOptions::default()
+    };
+    let (_, output) = fixture.run(&events, &options).unwrap();
+
+    // US: 500 / 3 = 166.67 rounds to 167.
+    assert_eq!(
+        output,
+        "country,order_count,total_quantity,avg_quantity_centi\nCA,1,3,300\nUS,3,5,167\n"
+    );
+}
+
+#[test]
+fn date_ts_mismatch_keeps_rejects_or_corrects_the_date() {
+    let fixture = Fixture::new("date-ts-mismatch");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold").replacen("2025-01-24T", "2025-01-25T", 1),
+        event("E2", 1, 1_000, 1, "US", "gold"),
+    ]);
+
+    for (mode, expected, rejected) in [
+        (DateTsMismatch::Keep, "2025-01-24,2\n", None),
+        (DateTsMismatch::Reject, "2025-01-24,1\n", Some(&1)),
+        (DateTsMismatch::UseTs, "2025-01-24,1\n2025-01-25,1\n", None),
+    ] {
+        let options = TransformOptions {
+            columns: Some(resolve_output_columns("event_date,order_count").unwrap()),
+            date_ts_mismatch: mode,
+            ..TransformOptions::default()
+        };
+        let (summary, output) = fixture.run(&events, &options).unwrap();
+        let mode = mode.as_str();
+        assert_eq!(
+            output,
+            format!("event_date,order_count\n{}", expected),
+            "{}",
+            mode
+        );
+        assert_eq!(
+            summary.reject_counts.get("date_ts_mismatch"),
+            rejected,
+            "{}",
+            mode
+        );
+    }
+}
+
+#[test]
+fn dollar_output_is_cents_over_100_under_each_rounding() {
+    let fixture = Fixture::new("output-dollars");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "bronze"),
+        event("E2", 1, 230, 1, "US", "gold"),
+        event("E3", 1, 138, 1, "US", "silver"),
+    ]);
+    let (_, cents) = fixture.run(&events, &TransformOptions::default()).unwrap();
+    assert_eq!(
+        column(&cents, "total_net_usd_cents"),
+        ["1085", "250", "150"]
+    );
+
+    for (rounding, expected) in [
+        (None, ["10", "2", "1"]),
+        (Some(RoundingMode::Truncate), ["10", "2", "1"]),
+        (Some(RoundingMode::HalfUp), ["11", "3", "2"]),
+        (Some(RoundingMode::HalfEven), ["11", "2", "2"]),
+    ] {
+        let options = TransformOptions {
+            output_unit: OutputUnit::Dollars,
+            output_unit_rounding: rounding,
+            ..TransformOptions::default()
+        };
+        let (_, dollars) = fixture.run(&events, &options).unwrap();
+        assert_eq!(column(&dollars, "total_net_usd"), expected);
+        assert_eq!(
+            dollars.lines().next().unwrap(),
+            cents.lines().next().unwrap().replace("_usd_cents", "_usd")
+        );
+        assert_eq!(
+            column(&dollars, "order_count"),
+            column(&cents, "order_count")
+        );
+    }
+}
+
+#[test]
+fn dedup_conflict_policies_resolve_a_conflicting_pair() {
+    let fixture = Fixture::new("dedup-conflict");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E1", 1, 3_000, 1, "US", "gold"),
+        event("E2", 1, 2_000, 1, "US", "gold"),
+    ]);
+
+    for (policy, net, rejected) in [
+        (DedupConflict::KeepFirst, "3255", None),
+        (DedupConflict::RejectConflict, "2170", Some(&1)),
+        (DedupConflict::PreferHigherAmount, "5425", None),
+    ] {
+        let options = TransformOptions {
+            dedup_conflict: policy,
+            ..TransformOptions::default()
+        };
+        let (summary, output) = fixture.run(&events, &options).unwrap();
+        let policy = policy.as_str();
+        assert_eq!(summary.dedup_conflicts, 1, "{}", policy);
+        assert_eq!(column(&output, "total_net_usd_cents"), [net], "{}", policy);
+        assert_eq!(
+            summary.reject_counts.get("dedup_conflict"),
+            rejected,
+            "{}",
+            policy
+        );
+    }
+}
+
+#[test]
+fn fx_key_column_values_an_order_at_its_currency_rate() {
+    let fixture = Fixture::new("fx-key-column");
+    fixture.write(
+        "dim_countries.csv",
+        &format!("{}EUR,1200000,10000,0\n", COUNTRIES),
+    );
+    let events = fixture.write(
+        "events.csv",
+        &format!(
+            "{},currency\n{},EUR\n{},\n",
+            EVENTS_HEADER.trim_end(),
+            event("E1", 1, 1_000, 1, "US", "gold"),
+            event("E2", 1, 1_000, 1, "US", "silver"),
+        ),
+    );
+
+    let (_, by_country) = fixture.run(&events, &TransformOptions::default()).unwrap();
+    assert_eq!(column(&by_country, "total_net_usd_cents"), ["1085", "1085"]);
+
+    let options = TransformOptions {
+        fx_key_column: Some("currency".to_string()),
+        ..TransformOptions::default()
+    };
+    let (_, by_currency) = fixture.run(&events, &options).unwrap();
+    // US tax still applies; only the FX rate follows the currency.
+    assert_eq!(column(&by_currency, "country"), ["US", "US"]);
+    assert_eq!(
+        column(&by_currency, "total_net_usd_cents"),
+        ["1302", "1085"]
+    );
+}
+
+#[test]
+fn risk_buckets_start_at_their_thresholds() {
+    let fixture = Fixture::new("risk-buckets");
+    fixture.write(
+        "dim_countries.csv",
+        &format!("{}MX,50000,9999,0\n", COUNTRIES),
+    );
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 1, 1_000, 1, "CA", "gold"),
+        event("E3", 1, 1_000, 1, "MX", "gold"),
+    ]);
+    let options = TransformOptions {
+        columns: Some(resolve_output_columns("country,risk_bucket,order_count").unwrap()),
+        risk_buckets: Some(RiskBuckets::parse("10000,10150").unwrap()),
+        ..TransformOptions::default()
+    };
+    let (_, output) = fixture.run(&events, &options).unwrap();
+
+    // risk_bps 9999, 10000 and 10150 against medium from 10000 and high from 10150.
+    assert_eq!(
+        output,
+        "country,risk_bucket,order_count\nCA,high,1\nMX,low,1\nUS,medium,1\n"
+    );
+}
+
+#[test]
+fn version_audit_reports_gaps_and_decreases() {
+    let fixture = Fixture::new("audit-versions");
+    let versioned = |id: &str, version: &str| {
+        event(id, 1, 1_000, 1, "US", "gold").replacen(",1,", &format!(",{},", version), 1)
+    };
+    let events = fixture.events(&[
+        versioned("E1", "1"),
+        versioned("E1", "3"),
+        versioned("E2", "2"),
+        versioned("E2", "1"),
+        versioned("E2", "1"),
+    ]);
+    let options = TransformOptions {
+        audit_versions_path: Some(fixture.path("audit.csv")),
+        ..TransformOptions::default()
+    };
+    let (summary, _) = fixture.run(&events, &options).unwrap();
+
+    // The repeated E2 version 1 on line 6 is not an issue.
+    assert_eq!(
+        fs::read_to_string(fixture.path("audit.csv")).unwrap(),
+        "line,event_id,previous_version,event_version,issue\n\
+         3,E1,1,3,gap\n\
+         5,E2,2,1,decrease\n"
+    );
+    assert_eq!(summary.dedup_rows, 2);
+}
+
+#[test]
+fn lowercase_output_category_normalizes_preserved_dim_case_only() {
+    let fixture = Fixture::new("category_case");
+    fixture.write(
+        "dim_products.csv",
+        "product_id,category,margin_bps,weight_grams\n1,Apparel,2000,500\n",
+    );
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 99, 1_000, 1, "US", "gold"),
+    ]);
+    let run_with = |preserve_category_case, lowercase_output_category| {
+        let options = TransformOptions {
+            preserve_category_case,
+            lowercase_output_category,
+            unknown_label: Some("UNKNOWN".to_string()),
+            category_margins: HashMap::from([("apparel".to_string(), 5_000)]),
+            ..TransformOptions::default()
+        };
+        let (_, output) = fixture.run(&events, &options).unwrap();
+        (
+            column(&output, "category"),
+            column(&output, "total_profit_usd_cents"),
+        )
+    };
+
+    let (stored, stored_profit) = run_with(false, false);
+    let (preserved, preserved_profit) = run_with(true, false);
+    let (lowered, lowered_profit) = run_with(true, true);
+    assert_eq!(stored, ["UNKNOWN", "apparel"]);
+    assert_eq!(preserved, ["Apparel", "UNKNOWN"]);
+    assert_eq!(lowered, ["UNKNOWN", "apparel"]);
+    // The lowercased --category-margin key matches the preserved category.
+    assert_eq!(stored_profit, ["271", "542"]);
+    assert_eq!(preserved_profit, ["542", "271"]);
+    assert_eq!(lowered_profit, stored_profit);
+}
+
+#[cfg(feature = "checksum")]
+fn sha256_hex(bytes: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+#[test]
+fn atomic_output_renames_into_place() {
+    let fixture = Fixture::new("atomic");
+    let events = fixture.events(&[event("E1", 1, 1_000, 1, "US", "gold")]);
+    let options = TransformOptions {
+        atomic_output: true,
+        ..TransformOptions::default()
+    };
+    let (summary, output) = fixture.run(&events, &options).unwrap();
+
+    assert_eq!(summary.output_files, [fixture.path("output.csv")]);
+    assert!(!fixture.path(".tmp.output.csv").exists());
+    assert_eq!(column(&output, "total_net_usd_cents"), ["1085"]);
+}
+
+#[cfg(feature = "checksum")]
+#[test]
+fn checksum_sidecar_hashes_the_stored_bytes() {
+    let fixture = Fixture::new("checksum");
+    let events = fixture.events(&[event("E1", 1, 1_000, 1, "US", "gold")]);
+    let options = TransformOptions {
+        atomic_output: true,
+        checksum_output: true,
+        ..TransformOptions::default()
+    };
+    let (_, output) = fixture.run(&events, &options).unwrap();
+
+    // The well-known digest of the empty string pins the hash itself.
+    assert_eq!(
+        sha256_hex(b""),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+    assert_eq!(
+        fs::read_to_string(fixture.path("output.csv.sha256")).unwrap(),
+        format!("{}  output.csv\n", sha256_hex(output.as_bytes()))
+    );
+}
+
+#[cfg(feature = "compression")]
+fn run_gzipped(fixture: &Fixture, events: &Path, options: &TransformOptions) -> Vec<u8> {
+    let gz_path = fixture.path("output.csv.gz");
+    transform(
+        events,
+        &fixture.path("dim_products.csv"),
+        &fixture.path("dim_countries.csv"),
+        &gz_path,
+        options,
+    )
+    .unwrap();
+    fs::read(gz_path).unwrap()
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn gzip_output_decompresses_to_the_csv() {
+    let fixture = Fixture::new("gzip-output");
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "gold"),
+        event("E2", 2, 2_000, 2, "CA", "bronze"),
+    ]);
+    let (_, plain) = fixture.run(&events, &TransformOptions::default()).unwrap();
+    let compressed = run_gzipped(&fixture, &events, &TransformOptions::default());
+
+    let mut decompressed = String::new();
+    flate2::read::GzDecoder::new(compressed.as_slice())
+        .read_to_string(&mut decompressed)
+        .unwrap();
+    assert_eq!(decompressed, plain);
+}
+
+#[cfg(all(feature = "compression", feature = "checksum"))]
+#[test]
+fn gzip_output_is_renamed_and_checksummed_compressed() {
+    let fixture = Fixture::new("gzip-checksum");
+    let events = fixture.events(&[event("E1", 1, 1_000, 1, "US", "gold")]);
+    let options = TransformOptions {
+        atomic_output: true,
+        checksum_output: true,
+        ..TransformOptions::default()
+    };
+    let compressed = run_gzipped(&fixture, &events, &options);
+
+    assert!(!fixture.path(".tmp.output.csv.gz").exists());
+    assert_eq!(
+        fs::read_to_string(fixture.path("output.csv.gz.sha256")).unwrap(),
+        format!("{}  output.csv.gz\n", sha256_hex(&compressed))
+    );
+}
+
+#[test]
+fn spilled_aggregation_matches_the_in_memory_one() {
+    let fixture = Fixture::new("spill");
+    let spill_dir = fixture.path("spill");
+    fs::create_dir(&spill_dir).unwrap();
+    // Repeated customers spread each VIP customer-day over several runs.
+    let rows: Vec<String> = (0..300)
+        .map(|i| {
+            format!(
+                "E{},1,2025-01-2{}T0{}:00:00,2025-01-2{},{},{},{},{},0,0,COMPLETE,{},{},card",
+                i,
+                i % 3,
+                i % 7,
+                i % 3,
+                i % 11 + 1,
+                i % 2 + 1,
+                5_000 + i * 37,
+                i % 5 + 1,
+                ["US", "CA"][i as usize % 2],
+                ["bronze", "gold", "platinum"][i as usize % 3],
+            )
+        })
+        .collect();
+    let events = fixture.events(&rows);
+    let options = TransformOptions {
+        quantity_bins: Some(QuantityBins::parse("1,2,3-4,5+").unwrap()),
+        min_net_usd_cents: Some(20_000),
+        ..TransformOptions::default()
+    };
+    let (expected_summary, expected) = fixture.run(&events, &options).unwrap();
+    assert!(column(&expected, "vip_customer_orders")
+        .iter()
+        .any(|vip| vip != "0"));
+    assert!(expected_summary.negligible_groups > 0);
+
+    // One order per run also takes the batched merge past `MERGE_FAN_IN`.
+    for chunk_rows in [1, 7, 1_000] {
+        let spilled = TransformOptions {
+            quantity_bins: Some(QuantityBins::parse("1,2,3-4,5+").unwrap()),
+            min_net_usd_cents: Some(20_000),
+            spill_dir: Some(spill_dir.clone()),
+            spill_chunk_rows: Some(chunk_rows),
+            ..TransformOptions::default()
+        };
+        let (summary, output) = fixture.run(&events, &spilled).unwrap();
+        assert_eq!(output, expected, "chunk_rows {}", chunk_rows);
+        assert_eq!(summary.grouped_orders, expected_summary.grouped_orders);
+        assert_eq!(
+            summary.negligible_groups,
+            expected_summary.negligible_groups
+        );
+        assert_eq!(fs::read_dir(&spill_dir).unwrap().count(), 0);
+    }
+}
+
+#[test]
+fn skip_rows_moves_the_start_of_processing() {
+    let fixture = Fixture::new("skip_rows");
+    let rows: Vec<String> = (1..=4)
+        .map(|i| event(&format!("E{}", i), 1, i * 1_000, 1, "US", "gold"))
+        .collect();
+    let events = fixture.events(&rows);
+    let options = TransformOptions {
+        skip_rows: 2,
+        ..TransformOptions::default()
+    };
+    let (summary, skipped) = fixture.run(&events, &options).unwrap();
+    let tail = fixture.events(&rows[2..]);
+    let (_, expected) = fixture.run(&tail, &TransformOptions::default()).unwrap();
+
+    assert_eq!(summary.skipped_rows, 2);
+    assert_eq!(summary.raw_rows, 2);
+    assert_eq!(skipped, expected);
+    assert_eq!(column(&skipped, "total_net_usd_cents"), ["7595"]);
+}
+
+#[test]
+fn quantity_bins_sum_to_order_count() {
+    let fixture = Fixture::new("quantity_bins");
+    let rows: Vec<String> = (1..=30)
+        .map(|i| {
+            event(
+                &format!("E{}", i),
+                i % 2 + 1,
+                1_000,
+                i % 15 + 1,
+                "US",
+                "gold",
+            )
+        })
+        .collect();
+    let events = fixture.events(&rows);
+    let options = TransformOptions {
+        quantity_bins: Some(QuantityBins::parse("1,2,3,4-5,6-10,11+").unwrap()),
+        ..TransformOptions::default()
+    };
+    let (_, output) = fixture.run(&events, &options).unwrap();
+
+    let counts = |name: &str| -> Vec<i64> {
+        column(&output, name)
+            .iter()
+            .map(|value| value.parse().unwrap())
+            .collect()
+    };
+    let bins = [
+        "qty_bin_1",
+        "qty_bin_2",
+        "qty_bin_3",
+        "qty_bin_4_5",
+        "qty_bin_6_10",
+        "qty_bin_11_plus",
+    ];
+    let order_counts = counts("order_count");
+    assert!(order_counts.len() > 1);
+    for (row, order_count) in order_counts.iter().enumerate() {
+        let binned: i64 = bins.iter().map(|bin| counts(bin)[row]).sum();
+        assert_eq!(binned, *order_count, "row {}", row);
+    }
+    let totals: Vec<i64> = bins.iter().map(|bin| counts(bin).iter().sum()).collect();
+    assert_eq!(totals, [2, 2, 2, 4, 10, 10]);
+}
+
+#[test]
+fn mixed_tier_customer_day_takes_its_highest_tiers_threshold() {
+    let fixture = Fixture::new("vip_tiers");
+    // One customer-day spending 2170 USD cents as both bronze and platinum.
+    let events = fixture.events(&[
+        event("E1", 1, 1_000, 1, "US", "bronze"),
+        event("E2", 1, 1_000, 1, "US", "platinum"),
+    ]);
+    let vip_with = |bronze: i64, platinum: i64| {
+        let options = TransformOptions {
+            vip_thresholds: HashMap::from([
+                ("bronze".to_string(), bronze),
+                ("platinum".to_string(), platinum),
+            ]),
+            ..TransformOptions::default()
+        };
+        let (_, output) = fixture.run(&events, &options).unwrap();
+        column(&output, "vip_customer_orders")
+    };
+
+    assert_eq!(vip_with(1_000, 1_000_000), ["0", "0"]);
+    assert_eq!(vip_with(1_000_000, 1_000), ["1", "1"]);
+}
+
+#[test]
+fn shipping_can_leave_the_discount_and_tax_bases() {
+    let fixture = Fixture::new("shipping_bases");
+    // 10000 cents of goods plus 1000 shipping, 10% off, US tax 8.5%.
+    let events = fixture.events(&[
+        "E1,1,2025-01-24T07:21:55,2025-01-24,1,1,10000,1,1000,1000,COMPLETE,US,gold,card"
+            .to_string(),
+    ]);
+    let net_with = |discount_applies, tax_applies| {
+        let options = TransformOptions {
+            discount_applies_to_shipping: Some(discount_applies),
+            tax_applies_to_shipping: Some(tax_applies),
+            ..TransformOptions::default()
+        };
+        let (_, output) = fixture.run(&events, &options).unwrap();
+        column(&output, "total_net_usd_cents")
+    };
+
+    // Both: 11000 - 1100 = 9900, plus tax 842 (8.5% of 9900).
+    assert_eq!(net_with(true, true), ["10742"]);
+    // Goods-only discount: 11000 - 1000 = 10000, plus tax 850.
+    assert_eq!(net_with(false, true), ["10850"]);
+    // Goods-only tax: 9900, less shipping's 900 after its share of the
+    // discount, leaves 9000 goods and tax 765.
+    assert_eq!(net_with(true, false), ["10665"]);
+    // Neither: 10000, of which 9000 is goods, tax 765.
+    assert_eq!(net_with(false, false), ["10765"]);
+}
@@ -0,0 +1,74 @@
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::Duration;
+
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+
+use crate::{run, CliArgs};
+
+// Editors and copy tools often emit several events per save; wait for the
+// file to go quiet before re-running.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+fn notify_error(err: notify::Error) -> io::Error {
+    io::Error::other(format!("watch: {}", err))
+}
+
+// Access events are ignored: our own reads of the events file would
+// otherwise trigger another run.
+fn touches(event: &Event, events_path: &Path) -> bool {
+    matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+        && event.paths.iter().any(|path| path == events_path)
+}
+
+fn run_once(cli: &CliArgs) {
+    if let Err(err) = run(cli) {
+        eprintln!("rust transform failed | {}", err);
+    }
+}
+
+/// Reruns on every events file change until Ctrl-C; a failed run does not stop it.
+pub(crate) fn watch(cli: &CliArgs) -> io::Result<()> {
+    let events_path = cli.events_path.canonicalize()?;
+    let watch_dir = events_path
+        .parent()
+        .ok_or_else(|| io::Error::other("watch: events file has no parent directory"))?
+        .to_path_buf();
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_handler = Arc::clone(&stop);
+    ctrlc::set_handler(move || stop_handler.store(true, Ordering::SeqCst))
+        .map_err(|err| io::Error::other(format!("watch: {}", err)))?;
+
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(notify_error)?;
+    // Watch the directory so files replaced by rename are still picked up.
+    watcher
+        .watch(&watch_dir, RecursiveMode::NonRecursive)
+        .map_err(notify_error)?;
+
+    run_once(cli);
+    eprintln!("watching {} (Ctrl-C to stop)", events_path.display());
+
+    let mut pending = false;
+    while !stop.load(Ordering::SeqCst) {
+        let timeout = if pending { DEBOUNCE } else { POLL_INTERVAL };
+        match receiver.recv_timeout(timeout) {
+            Ok(Ok(event)) => pending |= touches(&event, &events_path),
+            Ok(Err(err)) => eprintln!("watch: {}", err),
+            Err(RecvTimeoutError::Timeout) if pending => {
+                pending = false;
+                run_once(cli);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    eprintln!("watch stopped");
+    Ok(())
+}